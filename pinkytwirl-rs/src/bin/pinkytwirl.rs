@@ -0,0 +1,328 @@
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use pinkytwirl::{
+    transition_graph, ConfigFileKind, ConfigLayer, PinkyTwirlEngine, Platform, Simulator,
+    UNIFIED_CONFIG_FILE,
+};
+
+const USAGE: &str = "Usage:
+  pinkytwirl convert <config dir> <pinkytwirl.yaml>   Convert .txt files into a unified config
+  pinkytwirl convert <pinkytwirl.yaml> <config dir>   Convert a unified config into .txt files
+  pinkytwirl fmt [--check] <file>...                   Format mappings.txt and semantics*.txt files
+  pinkytwirl lint <config dir>... [--platform <name>]  Report problems, for every platform by default
+  pinkytwirl query --app <app> [options] <chord>       Show how a chord such as \"Meta + J\" resolves
+  pinkytwirl which <action> --app <app> [options]      List the chords that reach an action
+  pinkytwirl simulate [--app <app>] [options]          Type key events into the engine, see `help`
+                      [--record <file>]                and record them
+  pinkytwirl test <case dir> [options]                 Run the .case files in a directory
+  pinkytwirl replay <recording> [options]              Replay a recording and compare the results
+  pinkytwirl states                                    Print the chord state transitions as a Graphviz graph
+
+Options for query, which, simulate, test and replay:
+  --window <title>      Window title to match contexts against
+  --config <dir>        Config directory, can be repeated for layers. The discovered config by default
+  --platform <name>     Platform to resolve semantics for";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("convert") if args.len() == 3 => convert(Path::new(&args[1]), Path::new(&args[2])),
+        Some("lint") if args.len() > 1 => match lint(&args[1..]) {
+            Ok(true) => Ok(()),
+            Ok(false) => return ExitCode::FAILURE,
+            Err(e) => Err(e),
+        },
+        Some("query") if args.len() > 1 => query(&args[1..]),
+        Some("which") if args.len() > 1 => which(&args[1..]),
+        Some("simulate") => simulate(&args[1..]),
+        Some("states") if args.len() == 1 => {
+            print!("{}", transition_graph());
+            Ok(())
+        }
+        Some("replay") if args.len() > 1 => match replay(&args[1..]) {
+            Ok(true) => Ok(()),
+            Ok(false) => return ExitCode::FAILURE,
+            Err(e) => Err(e),
+        },
+        Some("test") if args.len() > 1 => match test_cases(&args[1..]) {
+            Ok(true) => Ok(()),
+            Ok(false) => return ExitCode::FAILURE,
+            Err(e) => Err(e),
+        },
+        Some("fmt") if args.len() > 1 => {
+            let check = args[1] == "--check";
+            let files: Vec<&Path> = args[1..]
+                .iter()
+                .filter(|arg| *arg != "--check")
+                .map(Path::new)
+                .collect();
+            match format_files(&files, check) {
+                Ok(true) => Ok(()),
+                Ok(false) => return ExitCode::FAILURE,
+                Err(e) => Err(e),
+            }
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// Converts in whichever direction the input calls for. Existing files are never overwritten.
+fn convert(input: &Path, output: &Path) -> Result<(), Box<dyn Error>> {
+    if input.is_dir() {
+        let output = if output.is_dir() {
+            output.join(UNIFIED_CONFIG_FILE)
+        } else {
+            output.to_path_buf()
+        };
+        let yaml = pinkytwirl::txt_to_unified(&ConfigLayer::Directory(input.to_path_buf()))?;
+        write_new_file(&output, &yaml)?;
+    } else {
+        let yaml = fs::read_to_string(input)
+            .map_err(|e| format!("Cannot read {:?}: {}", input, e))?;
+        let files = pinkytwirl::unified_to_txt(&yaml)?;
+        fs::create_dir_all(output)
+            .map_err(|e| format!("Cannot create config directory {:?}: {}", output, e))?;
+        let paths: Vec<PathBuf> = files.iter().map(|(name, _)| output.join(name)).collect();
+        if let Some(path) = paths.iter().find(|path| path.exists()) {
+            return Err(format!("{:?} already exists", path).into());
+        }
+        for ((_, content), path) in files.iter().zip(&paths) {
+            write_new_file(path, content)?;
+        }
+    }
+    Ok(())
+}
+
+// Formats the files in place, or with `check` only lists the ones that aren't formatted.
+// Returns whether every file was formatted already.
+fn format_files(files: &[&Path], check: bool) -> Result<bool, Box<dyn Error>> {
+    let mut formatted = true;
+    for file in files {
+        let file_name = file.file_name().map(|name| name.to_string_lossy().to_string());
+        let kind = file_name
+            .as_deref()
+            .and_then(ConfigFileKind::for_file_name)
+            .ok_or_else(|| format!("{:?} is not a mappings or semantics file", file))?;
+        let text = fs::read_to_string(file).map_err(|e| format!("Cannot read {:?}: {}", file, e))?;
+        let new_text = pinkytwirl::format_config(&text, kind);
+        if new_text == text {
+            continue;
+        }
+        formatted = false;
+        if check {
+            println!("{} is not formatted", file.display());
+        } else {
+            fs::write(file, new_text).map_err(|e| format!("Cannot write {:?}: {}", file, e))?;
+            println!("Formatted {}", file.display());
+        }
+    }
+    Ok(formatted || !check)
+}
+
+// Lints the layers given as directories, in order. Returns whether no problems were found.
+fn lint(args: &[String]) -> Result<bool, Box<dyn Error>> {
+    let mut platforms = Platform::ALL.to_vec();
+    let mut layers = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--platform" {
+            let name = args.next().ok_or("--platform needs a platform name")?;
+            let platform = Platform::from_name(name).ok_or_else(|| format!("Unknown platform '{}'", name))?;
+            platforms = vec![platform];
+        } else {
+            layers.push(ConfigLayer::Directory(PathBuf::from(arg)));
+        }
+    }
+
+    let issues = pinkytwirl::lint_config(&layers, &platforms)?;
+    for issue in &issues {
+        println!("{}", issue);
+    }
+    println!("{} problem(s) found.", issues.len());
+    Ok(issues.is_empty())
+}
+
+// How to build the engine for the commands that run one.
+struct EngineOptions {
+    config_dirs: Vec<PathBuf>,
+    platform: Platform,
+}
+
+impl EngineOptions {
+    fn build(&self) -> Result<PinkyTwirlEngine, Box<dyn Error>> {
+        let mut builder = PinkyTwirlEngine::builder().platform(self.platform);
+        if self.config_dirs.is_empty() {
+            builder = builder.discovered_config();
+        }
+        for config_dir in &self.config_dirs {
+            builder = builder.config_dir(config_dir);
+        }
+        let engine = builder.build();
+        if let Err(e) = &engine.startup {
+            return Err(format!("Cannot load the config: {}", e).into());
+        }
+        Ok(engine)
+    }
+}
+
+// The engine and application a query runs against, and the arguments that are left.
+struct QueryTarget {
+    options: EngineOptions,
+    app_name: Option<String>,
+    window_name: String,
+    args: Vec<String>,
+}
+
+fn query_target(args: &[String]) -> Result<QueryTarget, Box<dyn Error>> {
+    let mut target = QueryTarget {
+        options: EngineOptions {
+            config_dirs: Vec::new(),
+            platform: Platform::current(),
+        },
+        app_name: None,
+        window_name: String::new(),
+        args: Vec::new(),
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--app" => target.app_name = Some(value()?.clone()),
+            "--window" => target.window_name = value()?.clone(),
+            "--config" => target.options.config_dirs.push(std::env::current_dir()?.join(value()?)),
+            "--platform" => {
+                let name = value()?;
+                target.options.platform =
+                    Platform::from_name(name).ok_or_else(|| format!("Unknown platform '{}'", name))?;
+            }
+            _ => target.args.push(arg.clone()),
+        }
+    }
+    Ok(target)
+}
+
+// Prints the context, mapping, semantics and key events of a chord.
+fn query(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let target = query_target(args)?;
+    let [chord] = target.args.as_slice() else {
+        return Err("query needs exactly one chord".into());
+    };
+    let app_name = target.app_name.ok_or("--app is required")?;
+    let query = target
+        .options
+        .build()?
+        .query_chord(&app_name, &target.window_name, chord)
+        .ok_or_else(|| format!("No context matches '{}'", app_name))?;
+    print!("{}", query);
+    Ok(())
+}
+
+// Prints every chord that reaches an action.
+fn which(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let target = query_target(args)?;
+    let [action_name] = target.args.as_slice() else {
+        return Err("which needs exactly one action".into());
+    };
+    let app_name = target.app_name.ok_or("--app is required")?;
+    let engine = target.options.build()?;
+    let context = engine
+        .get_context(&app_name, &target.window_name)
+        .ok_or_else(|| format!("No context matches '{}'", app_name))?;
+    println!("Context: {}", context.name);
+    let chords = engine
+        .chords_for_action(action_name, &app_name, &target.window_name);
+    for chord in &chords {
+        println!("{}", chord);
+    }
+    if chords.is_empty() {
+        println!("No chords reach {}.", action_name);
+    }
+    Ok(())
+}
+
+// Reads simulator commands from standard input until it ends or `quit` is typed.
+fn simulate(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let target = query_target(args)?;
+    let mut engine = target.options.build()?;
+    match target.args.as_slice() {
+        [] => (),
+        [flag, path] if flag == "--record" => engine.start_recording(path)?,
+        [arg, ..] => return Err(format!("Unexpected argument '{}'", arg).into()),
+    }
+    let mut simulator = Simulator::new(engine);
+    if let Some(app_name) = &target.app_name {
+        let focus = format!("focus \"{}\" \"{}\"", app_name, target.window_name);
+        print!("{}", simulator.run_command(&focus)?);
+    }
+    println!("Type `help` for the commands, `quit` to exit.");
+
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            break;
+        }
+        if matches!(line.trim(), "quit" | "exit") {
+            break;
+        }
+        match simulator.run_command(&line) {
+            Ok(output) => print!("{}", output),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    simulator.engine.stop_recording();
+    Ok(())
+}
+
+// Replays a recording against the config. Returns whether every event had the same result.
+fn replay(args: &[String]) -> Result<bool, Box<dyn Error>> {
+    let target = query_target(args)?;
+    let [file] = target.args.as_slice() else {
+        return Err("replay needs exactly one recording".into());
+    };
+    let text = fs::read_to_string(file).map_err(|e| format!("Cannot read {:?}: {}", file, e))?;
+    let report = pinkytwirl::replay_recording(&mut target.options.build()?, Path::new(file), &text)?;
+    print!("{}", report);
+    Ok(report.passed())
+}
+
+// Runs the case files in a directory. Returns whether every case passed.
+fn test_cases(args: &[String]) -> Result<bool, Box<dyn Error>> {
+    let target = query_target(args)?;
+    let [dir] = target.args.as_slice() else {
+        return Err("test needs exactly one case directory".into());
+    };
+    // Make sure the config loads before running every case against it.
+    target.options.build()?;
+    let report = pinkytwirl::run_case_dir(Path::new(dir), || {
+        target.options.build().expect("the config loaded before")
+    })?;
+    print!("{}", report);
+    Ok(report.passed())
+}
+
+fn write_new_file(path: &Path, content: &str) -> Result<(), Box<dyn Error>> {
+    if path.exists() {
+        return Err(format!("{:?} already exists", path).into());
+    }
+    fs::write(path, content).map_err(|e| format!("Cannot write {:?}: {}", path, e))?;
+    println!("Wrote {}", path.display());
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use std::fmt;
+
+// Actions that control the engine itself instead of sending keys. They are written like any
+// other action, and can be bound to chords or given names in the semantics files:
+//
+//     Meta:
+//         Escape = Engine.Reload
+//         F1     = Engine.ShowHelp
+//         D1     = Engine.SwitchProfile(Work)
+//         N      = Engine.ToggleLayer(Meta)
+//
+// Only the profile and layer names keep their case.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EngineCommand {
+    // Reloads the config from disk.
+    Reload,
+    // Disables the engine, or enables it again. A disabled engine still watches for this chord.
+    Toggle,
+    // Switches to the named profile, or back to the base config for `Engine.SwitchProfile()`.
+    SwitchProfile(String),
+    // Locks the layer of a stem such as `D4` on, as if the stem was held, until the same command
+    // or Escape turns it off again.
+    ToggleLayer(String),
+    // Applies the layer of a stem to the next chord only.
+    OneShotLayer(String),
+    // Enters the vim-like modal mode, see `modal.rs`.
+    Modal,
+    // Asks the host to show the chords of the focused context, see `take_help`.
+    ShowHelp,
+    // Releases every key, like `reset_all`.
+    Reset,
+}
+
+pub(crate) const ENGINE_COMMAND_PREFIX: &str = "engine.";
+
+impl EngineCommand {
+    // Parses a command such as `Engine.SwitchProfile(Work)`, or returns `None` if it isn't one.
+    pub fn parse(text: &str) -> Option<EngineCommand> {
+        let text = text.trim();
+        if !text.to_lowercase().starts_with(ENGINE_COMMAND_PREFIX) {
+            return None;
+        }
+        let command = &text[ENGINE_COMMAND_PREFIX.len()..];
+        let (name, argument) = match command.split_once('(') {
+            Some((name, argument)) => (name.trim(), Some(argument.strip_suffix(')')?.trim())),
+            None => (command, None),
+        };
+        match (name.to_lowercase().as_str(), argument) {
+            ("reload", None) => Some(EngineCommand::Reload),
+            ("toggle", None) => Some(EngineCommand::Toggle),
+            ("switchprofile", Some(profile)) => {
+                Some(EngineCommand::SwitchProfile(profile.to_string()))
+            }
+            ("togglelayer", Some(layer)) if !layer.is_empty() => {
+                Some(EngineCommand::ToggleLayer(layer.to_string()))
+            }
+            ("oneshotlayer", Some(layer)) if !layer.is_empty() => {
+                Some(EngineCommand::OneShotLayer(layer.to_string()))
+            }
+            ("modal", None) => Some(EngineCommand::Modal),
+            ("showhelp", None) => Some(EngineCommand::ShowHelp),
+            ("reset", None) => Some(EngineCommand::Reset),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for EngineCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineCommand::Reload => write!(f, "Engine.Reload"),
+            EngineCommand::Toggle => write!(f, "Engine.Toggle"),
+            EngineCommand::SwitchProfile(profile) => write!(f, "Engine.SwitchProfile({})", profile),
+            EngineCommand::ToggleLayer(layer) => write!(f, "Engine.ToggleLayer({})", layer),
+            EngineCommand::OneShotLayer(layer) => write!(f, "Engine.OneShotLayer({})", layer),
+            EngineCommand::Modal => write!(f, "Engine.Modal"),
+            EngineCommand::ShowHelp => write!(f, "Engine.ShowHelp"),
+            EngineCommand::Reset => write!(f, "Engine.Reset"),
+        }
+    }
+}
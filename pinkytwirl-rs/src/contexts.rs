@@ -1,15 +1,25 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt;
-use std::fs;
-use std::path::Path;
 
+use crate::commands::EngineCommand;
 use crate::keycode_macos::KeyCodeLookup;
+use crate::logging::log;
+use crate::platform::{normalize_modifier_name, Platform};
+use crate::sources::{yaml_error_at, Origin, SourceLine};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct YamlContext {
     pub aliases: Vec<String>,
     pub parent: Option<String>,
+    #[serde(default)]
+    pub passthrough_all: bool,
+    #[serde(default)]
+    pub chord_counts: Option<bool>,
+    #[serde(default)]
+    pub semantics: Option<String>,
+    #[serde(default)]
+    pub translate_modifiers: HashMap<String, String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -90,7 +100,7 @@ impl fmt::Display for KeyEvent {
     }
 }
 
-pub fn key_press(s: &str) -> KeyEvent {
+pub fn key_press(s: &str, platform: Platform) -> KeyEvent {
     let parts: Vec<String> = s
         .split('+')
         .map(|s| s.trim().to_lowercase().to_string())
@@ -105,7 +115,13 @@ pub fn key_press(s: &str) -> KeyEvent {
 
     let mut modifier_down_only = false;
     for part in parts.iter() {
-        match part.as_str() {
+        // The abstract modifiers resolve to a concrete one for the platform.
+        let part = match part.as_str() {
+            "primary" => platform.primary_modifier(),
+            "word" => platform.word_modifier(),
+            part => part,
+        };
+        match part {
             "shift" => shift = true,
             "ctrl" => ctrl = true,
             "alt" => alt = true,
@@ -124,12 +140,12 @@ pub fn key_press(s: &str) -> KeyEvent {
         key: key.to_string(),
         code: 0,
         state: KeyState::DownUp,
-        shift: shift,
-        ctrl: ctrl,
-        alt: alt,
-        meta: meta,
-        func: func,
-        modifier_down_only: modifier_down_only,
+        shift,
+        ctrl,
+        alt,
+        meta,
+        func,
+        modifier_down_only,
     }
 }
 
@@ -139,6 +155,22 @@ pub enum SemanticAction {
     Action(String),
     KeyEvent(KeyEvent),
     LiteralString(String),
+    // Explicitly unbinds a chord: the lookup stops here instead of walking to the
+    // parent context, and the keys are passed through untouched.
+    Passthrough,
+    // Controls the engine instead of sending keys, e.g. `Engine.Reload`.
+    Engine(EngineCommand),
+}
+
+impl SemanticAction {
+    // Whether `Passthrough` appears anywhere in the action, e.g. in `Copy | Passthrough`.
+    pub fn contains_passthrough(&self) -> bool {
+        match self {
+            SemanticAction::Passthrough => true,
+            SemanticAction::Sequence(actions) => actions.iter().any(Self::contains_passthrough),
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for SemanticAction {
@@ -158,6 +190,8 @@ impl fmt::Display for SemanticAction {
             SemanticAction::Action(action) => write!(f, "Action({})", action),
             SemanticAction::KeyEvent(event) => write!(f, "{}", event),
             SemanticAction::LiteralString(s) => write!(f, "LiteralString(\"{}\")", s),
+            SemanticAction::Passthrough => write!(f, "Passthrough"),
+            SemanticAction::Engine(command) => write!(f, "{}", command),
         }
     }
 }
@@ -167,28 +201,86 @@ pub struct Context {
     pub name: String,
     pub aliases: Vec<String>,
     pub parent: Option<String>,
+    // When set, no chords are resolved in this context or anything it inherits.
+    pub passthrough_all: bool,
+    // Whether digits under a stem start a count for the next chord, inherited from the parents
+    // unless the context sets it.
+    pub chord_counts: Option<bool>,
+    // Semantics platform to resolve actions with, when it differs from the engine's own.
+    pub semantics_platform: Option<Platform>,
+    // Modifier translation applied to the synthetic keys, e.g. `meta -> ctrl`.
+    pub modifier_translation: HashMap<String, String>,
     pub semantic_actions: HashMap<String, SemanticAction>,
+    // Semantic actions loaded from the semantics files of other platforms.
+    pub foreign_semantic_actions: HashMap<Platform, HashMap<String, SemanticAction>>,
     pub key_mappings: HashMap<String, SemanticAction>,
+    // Where the context and each of its definitions came from, for layered configs.
+    pub origin: Option<Origin>,
+    pub semantic_origins: HashMap<Platform, HashMap<String, Origin>>,
+    pub mapping_origins: HashMap<String, Origin>,
 }
 
-pub fn parse_yaml_file(
-    file_path: &Path,
+pub fn parse_yaml_lines(
+    lines: &[SourceLine],
+    layer: usize,
+    diagnostics: &mut Vec<String>,
 ) -> Result<HashMap<String, Context>, Box<dyn std::error::Error>> {
-    let yaml_str = fs::read_to_string(file_path)?;
-    Ok(parse_yaml(&yaml_str)?)
-}
+    let yaml_str = lines
+        .iter()
+        .map(|line| line.text.as_str())
+        .collect::<Vec<&str>>()
+        .join("\n");
+
+    let mut contexts =
+        parse_yaml(&yaml_str, diagnostics).map_err(|e| yaml_error_at(lines, e))?;
+
+    for line in lines {
+        if line.text.starts_with(' ') || line.text.starts_with('\t') {
+            continue;
+        }
+        if let Some(context) = contexts.get_mut(line.text.trim().trim_end_matches(':')) {
+            context.origin = Some(Origin::new(layer, line));
+        }
+    }
 
-pub fn parse_yaml(yaml_str: &str) -> Result<HashMap<String, Context>, serde_yaml::Error> {
-    let debug = false;
-    let warn = true;
+    Ok(contexts)
+}
 
+pub fn parse_yaml(
+    yaml_str: &str,
+    diagnostics: &mut Vec<String>,
+) -> Result<HashMap<String, Context>, serde_yaml::Error> {
     let yaml_contexts: HashMap<String, YamlContext> = serde_yaml::from_str(yaml_str)?;
     let mut contexts = HashMap::new();
 
     // First pass: Create Context objects.
     for (name, yaml_context) in &yaml_contexts {
-        if debug {
-            println!("Creating context: {}", name);
+        log!(Trace, Config, "Creating context: {}", name);
+
+        let semantics_platform = yaml_context.semantics.as_ref().and_then(|platform_name| {
+            let platform = Platform::from_name(platform_name);
+            if platform.is_none() {
+                diagnostics.push(format!(
+                    "Unknown semantics platform '{}' for context '{}'. Skipping.",
+                    platform_name, name
+                ));
+            }
+            platform
+        });
+
+        let mut modifier_translation = HashMap::new();
+        for (from, to) in &yaml_context.translate_modifiers {
+            match (normalize_modifier_name(from), normalize_modifier_name(to)) {
+                (Some(from), Some(to)) => {
+                    modifier_translation.insert(from.to_string(), to.to_string());
+                }
+                _ => {
+                    diagnostics.push(format!(
+                        "Unknown modifier translation '{}: {}' for context '{}'. Skipping.",
+                        from, to, name
+                    ));
+                }
+            }
         }
 
         contexts.insert(
@@ -197,53 +289,72 @@ pub fn parse_yaml(yaml_str: &str) -> Result<HashMap<String, Context>, serde_yaml
                 name: name.clone(),
                 aliases: yaml_context.aliases.clone(),
                 parent: yaml_context.parent.clone(),
+                passthrough_all: yaml_context.passthrough_all,
+                chord_counts: yaml_context.chord_counts,
+                semantics_platform,
+                modifier_translation,
                 semantic_actions: HashMap::new(),
+                foreign_semantic_actions: HashMap::new(),
                 key_mappings: HashMap::new(),
+                origin: None,
+                semantic_origins: HashMap::new(),
+                mapping_origins: HashMap::new(),
             },
         );
     }
 
-    // Second pass: Make sure that parent contexts exists if set.
+    Ok(contexts)
+}
+
+// Make sure that parent contexts exists if set. This runs once all config layers are merged,
+// since a context may inherit from a context defined in another layer.
+pub fn validate_parents(contexts: &mut HashMap<String, Context>, diagnostics: &mut Vec<String>) {
     let context_names: Vec<String> = contexts.keys().cloned().collect();
     for context in contexts.values_mut() {
         if let Some(parent_name) = &context.parent {
             if !context_names.contains(parent_name) {
-                if warn {
-                    println!(
-                        "Warning: Parent context '{}' not found for context '{}'. Skipping.",
-                        parent_name, context.name
-                    );
-                }
+                diagnostics.push(format!(
+                    "Parent context '{}' not found for context '{}'. Skipping.",
+                    parent_name, context.name
+                ));
 
                 context.parent = None;
             }
         }
     }
-
-    Ok(contexts)
 }
 
-pub fn parse_semantic_action(input: &str, keycodes: &KeyCodeLookup) -> SemanticAction {
+pub fn parse_semantic_action(
+    input: &str,
+    keycodes: &KeyCodeLookup,
+    platform: Platform,
+) -> SemanticAction {
     let parts: Vec<&str> = input.split('|').map(str::trim).collect();
     let mut sequence = Vec::new();
 
     for part in parts {
+        if let Some(command) = EngineCommand::parse(part) {
+            sequence.push(SemanticAction::Engine(command));
+            continue;
+        }
         let part = part.trim().to_lowercase().to_string();
-        if part.starts_with('"') && part.ends_with('"') {
+        if part == "passthrough" || part == "unbound" {
+            sequence.push(SemanticAction::Passthrough);
+        } else if part.starts_with('"') && part.ends_with('"') {
             sequence.push(SemanticAction::LiteralString(
                 part[1..part.len() - 1].to_string(),
             ));
         } else if part.contains('+') {
-            sequence.push(SemanticAction::KeyEvent(key_press(&part)));
+            sequence.push(SemanticAction::KeyEvent(key_press(&part, platform)));
         } else if part.contains('*') {
             let (count, key) = part.split_once('*').unwrap();
             let count: usize = count.trim().parse().unwrap_or(1);
             let key = key.trim().to_string();
             for _ in 0..count {
-                sequence.push(SemanticAction::KeyEvent(key_press(&key)));
+                sequence.push(SemanticAction::KeyEvent(key_press(&key, platform)));
             }
         } else if keycodes.name_to_keycode.contains_key(&part) {
-            sequence.push(SemanticAction::KeyEvent(key_press(&part)));
+            sequence.push(SemanticAction::KeyEvent(key_press(&part, platform)));
         } else {
             sequence.push(SemanticAction::Action(part.to_string()));
         }
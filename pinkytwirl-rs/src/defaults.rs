@@ -0,0 +1,22 @@
+use std::collections::HashMap;
+
+use crate::sources::ConfigLayer;
+
+// The bundled config, compiled into the library so the engine works without any files on disk.
+pub const DEFAULT_CONFIG_FILES: [(&str, &str); 5] = [
+    ("contexts.txt", include_str!("user_config/contexts.txt")),
+    ("semantics.txt", include_str!("user_config/semantics.txt")),
+    ("semantics_macos.txt", include_str!("user_config/semantics_macos.txt")),
+    ("semantics_windows.txt", include_str!("user_config/semantics_windows.txt")),
+    ("mappings.txt", include_str!("user_config/mappings.txt")),
+];
+
+pub fn embedded_layer() -> ConfigLayer {
+    ConfigLayer::Memory {
+        name: "embedded".to_string(),
+        files: DEFAULT_CONFIG_FILES
+            .iter()
+            .map(|(name, content)| (name.to_string(), content.to_string()))
+            .collect::<HashMap<String, String>>(),
+    }
+}
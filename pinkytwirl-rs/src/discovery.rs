@@ -0,0 +1,112 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::defaults::DEFAULT_CONFIG_FILES;
+use crate::logging::log;
+
+// Overrides the config directory, e.g. `PINKYTWIRL_CONFIG_DIR=~/dotfiles/pinkytwirl`.
+pub const CONFIG_DIR_ENV: &str = "PINKYTWIRL_CONFIG_DIR";
+
+// Where the engine found its config.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigLocation {
+    Environment(PathBuf),
+    PlatformDir(PathBuf),
+    Embedded,
+}
+
+impl ConfigLocation {
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            ConfigLocation::Environment(path) | ConfigLocation::PlatformDir(path) => Some(path),
+            ConfigLocation::Embedded => None,
+        }
+    }
+}
+
+impl fmt::Display for ConfigLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigLocation::Environment(path) => {
+                write!(f, "{} (from {})", path.display(), CONFIG_DIR_ENV)
+            }
+            ConfigLocation::PlatformDir(path) => write!(f, "{}", path.display()),
+            ConfigLocation::Embedded => write!(f, "embedded defaults"),
+        }
+    }
+}
+
+// Looks up a variable in the environment of the process.
+pub fn process_env(name: &str) -> Option<OsString> {
+    std::env::var_os(name)
+}
+
+// The conventional config directory of the platform:
+// `~/Library/Application Support/PinkyTwirl` on macOS, `%APPDATA%\PinkyTwirl` on Windows and
+// `$XDG_CONFIG_HOME/pinkytwirl` (or `~/.config/pinkytwirl`) elsewhere.
+pub fn platform_config_dir() -> Option<PathBuf> {
+    platform_config_dir_in(&process_env)
+}
+
+fn platform_config_dir_in(env: &dyn Fn(&str) -> Option<OsString>) -> Option<PathBuf> {
+    let env_dir = |name: &str| {
+        env(name)
+            .filter(|value| !value.is_empty())
+            .map(PathBuf::from)
+    };
+
+    if cfg!(target_os = "macos") {
+        env_dir("HOME").map(|home| home.join("Library/Application Support/PinkyTwirl"))
+    } else if cfg!(target_os = "windows") {
+        env_dir("APPDATA").map(|app_data| app_data.join("PinkyTwirl"))
+    } else {
+        env_dir("XDG_CONFIG_HOME")
+            .or_else(|| env_dir("HOME").map(|home| home.join(".config")))
+            .map(|config_home| config_home.join("pinkytwirl"))
+    }
+}
+
+// Looks for the config directory in the environment variable, then in the platform config
+// directory, and otherwise settles for the embedded defaults. The environment is passed in, e.g.
+// `&process_env`, so tests don't have to change the one of the process.
+pub fn discover_config_location(env: &dyn Fn(&str) -> Option<OsString>) -> ConfigLocation {
+    if let Some(path) = env(CONFIG_DIR_ENV).filter(|value| !value.is_empty()) {
+        let path = PathBuf::from(path);
+        if path.is_dir() {
+            return ConfigLocation::Environment(path);
+        }
+        log!(
+            Warn,
+            Config,
+            "{} is set to {:?}, which is not a directory. Ignoring it.",
+            CONFIG_DIR_ENV,
+            path
+        );
+    }
+
+    match platform_config_dir_in(env) {
+        Some(path) if path.is_dir() => ConfigLocation::PlatformDir(path),
+        _ => ConfigLocation::Embedded,
+    }
+}
+
+// Writes a copy of the embedded defaults into the given directory. Files that already exist
+// are left untouched. Returns the files that were written.
+pub fn write_default_config(config_dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    fs::create_dir_all(config_dir)
+        .map_err(|e| format!("Cannot create config directory {:?}: {}", config_dir, e))?;
+
+    let mut written = Vec::new();
+    for (file_name, content) in DEFAULT_CONFIG_FILES {
+        let path = config_dir.join(file_name);
+        if path.exists() {
+            continue;
+        }
+        fs::write(&path, content).map_err(|e| format!("Cannot write {:?}: {}", path, e))?;
+        written.push(path);
+    }
+    Ok(written)
+}
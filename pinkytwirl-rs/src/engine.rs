@@ -22,7 +22,7 @@
 //     [ ] X + 2 * Y, need to resolve this better if X or Y aren't keys
 //     [x] Get meta+tab working (queue up actual key events?), and 3/4
 //     [ ] Improve meta+tab
-//     [ ] Caps lock keys
+//     [x] Caps lock keys
 //     [x] Get meta+space+j working
 //     [ ] Page up/page down
 // [x] Add README and MIT license
@@ -36,111 +36,630 @@
 
 use std::collections::{HashMap, VecDeque};
 use std::error::Error;
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+use crate::commands::EngineCommand;
 use crate::contexts::{Context, KeyEvent, KeyState, SemanticAction};
+use crate::discovery::ConfigLocation;
+use crate::platform::{normalize_modifier_name, translate_modifiers, Platform};
+use crate::profiles::{ACTIVE_PROFILE_FILE, PROFILES_FILE};
+use crate::loader::{LoadedConfig, ReloadReport};
+use crate::logging::{log, redact_key, redact_keys};
+use crate::modal::{ModalInput, ModalStep, MAX_COUNT};
+use crate::recording::Recorder;
+use crate::sources::{ConfigLayer, Origin};
+use crate::state::{is_transition, ActiveLayer, ChordState, LayerMode};
+use crate::unified::UNIFIED_CONFIG_FILE;
+use crate::watcher::ConfigWatcher;
 
 pub struct PinkyTwirlEngine {
     contexts: HashMap<String, Context>,
-    config_dir: String,
+    layers: Vec<ConfigLayer>,
+    // The layer that was discovered on disk or embedded, when the config was looked up.
+    config_location: Option<(usize, ConfigLocation)>,
+    platform: Platform,
+    // The profile whose layers are loaded on top of the base layers, see `profiles.rs`.
+    active_profile: Option<String>,
+    profiles: Vec<String>,
+    // Where the active profile is remembered between runs.
+    profile_file: Option<PathBuf>,
+    diagnostics: Vec<String>,
+    // The files the last successful load read, includes too.
+    config_files: Vec<PathBuf>,
+    config_watcher: Option<ConfigWatcher>,
+    recorder: Option<Recorder>,
+    // Set by the host while a password field or similar has focus.
+    secure_input: bool,
+    // Set when a panic was caught at the host boundary. The engine passes everything through from
+    // then on, since its state can't be trusted.
+    failure: Option<String>,
+    enabled: bool,
+    // Whether a broken invariant panics, for tests, instead of being logged and reset.
+    strict_invariants: bool,
+    // Pressing every key of the kill chord disables the engine, whatever the config says.
+    kill_chord: Vec<String>,
+    // Every key held, in the order they were pressed. Unlike the pressed keys this is kept up to
+    // date while the engine is disabled, for the kill chord and `Engine.Toggle`.
+    held_keys: Vec<String>,
+    // Keys whose down event re-enabled the engine and was suppressed. Their up events are
+    // suppressed too, so the host never sees an up without its down.
+    suppressed_up_keys: Vec<String>,
+    // Help the engine was asked to show, for the host to pick up with `take_help`.
+    help: Option<String>,
     pub pressed_keys: VecDeque<KeyEvent>,
+    // When each pressed key was last seen held, for the stuck key watchdog.
+    pressed_since: HashMap<String, Instant>,
+    stuck_key_timeout: Option<Duration>,
     current_context: Option<String>,
     keycodes: crate::keycode_macos::KeyCodeLookup,
 
-    no_mapping_until_reset: bool,
-    only_mappings_until_reset: bool,
-    has_generated_synthetic_keys: bool,
+    state: ChordState,
+    // A layer that is active without its stem being held, see `toggle_layer`.
+    active_layer: Option<ActiveLayer>,
+    // The command typed so far while in modal mode, or `None` outside of it.
+    modal: Option<ModalInput>,
+    // A count typed with a stem held, as in `Meta + D5, J`, for the next chord to repeat.
+    pending_count: Option<usize>,
+    // Whether Caps Lock is on. Pressing the key toggles it, the host can correct it through
+    // `set_caps_lock`.
+    caps_lock: bool,
+    // The lock state from before Caps Lock went down, if the engine suppressed the key.
+    caps_lock_before_press: Option<bool>,
+    // The lock state for the host to restore, see `take_caps_lock_restore`.
+    caps_lock_restore: Option<bool>,
     synthetic_keys: Vec<KeyEvent>,
     synthetic_keys_on_next_up_event: Vec<KeyEvent>,
+    // Keys the host has posted down events for but no up events yet.
+    synthetic_down_keys: Vec<KeyEvent>,
 
     pub startup: Result<(), Box<dyn Error>>,
-    debug_key_events: bool,
+}
+
+// Keys not seen held for this long are assumed to have missed their up event.
+pub const DEFAULT_STUCK_KEY_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const CAPS_LOCK_KEY: &str = "capslock";
+
+// Mappings under this stem only apply while Caps Lock is on, e.g. `CapsLocked + Meta:`.
+pub const CAPS_LOCKED_STEM: &str = "capslocked";
+
+// Both Shift keys and Escape.
+pub const DEFAULT_KILL_CHORD: [&str; 3] = ["shift", "rightshift", "escape"];
+
+pub struct EngineBuilder {
+    platform: Platform,
+    layers: Vec<ConfigLayer>,
+    embedded_fallback: bool,
+    config_location: Option<(usize, ConfigLocation)>,
+    profile: Option<String>,
+    profile_file: Option<PathBuf>,
+}
+
+impl EngineBuilder {
+    pub fn platform(mut self, platform: Platform) -> Self {
+        self.platform = platform;
+        self
+    }
+
+    // Adds a config directory as the next layer. Relative paths are resolved against the
+    // directory of the executable, the working directory of the process is left alone.
+    pub fn config_dir<P: AsRef<Path>>(mut self, config_dir: P) -> Self {
+        let config_dir = config_dir.as_ref();
+        let path = if config_dir.is_absolute() {
+            config_dir.to_path_buf()
+        } else {
+            std::env::current_exe()
+                .ok()
+                .and_then(|exe_path| exe_path.parent().map(|exe_dir| exe_dir.join(config_dir)))
+                .unwrap_or_else(|| config_dir.to_path_buf())
+        };
+        self.layers.push(ConfigLayer::Directory(path));
+        self
+    }
+
+    // Adds a layer from config text. The semantics are loaded as the shared `semantics.txt`.
+    pub fn config_strs(mut self, contexts: &str, semantics: &str, mappings: &str) -> Self {
+        let files = [
+            ("contexts.txt", contexts),
+            ("semantics.txt", semantics),
+            ("mappings.txt", mappings),
+        ];
+        self.layers.push(ConfigLayer::Memory {
+            name: format!("strings{}", self.layers.len()),
+            files: files
+                .iter()
+                .map(|(name, content)| (name.to_string(), content.to_string()))
+                .collect(),
+        });
+        self
+    }
+
+    // Adds a layer from the text of a unified config, see `UNIFIED_CONFIG_FILE`.
+    pub fn config_unified_str(mut self, yaml: &str) -> Self {
+        self.layers.push(ConfigLayer::Memory {
+            name: format!("strings{}", self.layers.len()),
+            files: [(UNIFIED_CONFIG_FILE.to_string(), yaml.to_string())].into(),
+        });
+        self
+    }
+
+    // Adds the bundled config that is compiled into the library as the next layer.
+    pub fn embedded_defaults(mut self) -> Self {
+        self.layers.push(crate::defaults::embedded_layer());
+        self
+    }
+
+    // Adds the config directory found through `PINKYTWIRL_CONFIG_DIR` or the platform config
+    // directory as the next layer, or the embedded defaults if there is neither.
+    pub fn discovered_config(self) -> Self {
+        self.discovered_config_in(&crate::discovery::process_env)
+    }
+
+    // Like `discovered_config`, with the environment variables looked up in `env`.
+    pub fn discovered_config_in(mut self, env: &dyn Fn(&str) -> Option<OsString>) -> Self {
+        let location = crate::discovery::discover_config_location(env);
+        log!(Info, Config, "Using config from: {}", location);
+        let layer = match location.path() {
+            Some(path) => ConfigLayer::Directory(path.to_path_buf()),
+            None => crate::defaults::embedded_layer(),
+        };
+        if let Some(path) = location.path() {
+            self.profile_file = Some(path.join(ACTIVE_PROFILE_FILE));
+        }
+        self.config_location = Some((self.layers.len(), location));
+        self.layers.push(layer);
+        self
+    }
+
+    // Starts in the given profile instead of the one that was active on the last run.
+    pub fn profile(mut self, profile: &str) -> Self {
+        self.profile = Some(profile.to_string());
+        self
+    }
+
+    // Remembers the active profile in this file, and starts in the profile it names.
+    pub fn profile_file<P: AsRef<Path>>(mut self, profile_file: P) -> Self {
+        self.profile_file = Some(profile_file.as_ref().to_path_buf());
+        self
+    }
+
+    // Falls back to the embedded defaults when none of the config directories exist.
+    pub fn embedded_fallback(mut self, embedded_fallback: bool) -> Self {
+        self.embedded_fallback = embedded_fallback;
+        self
+    }
+
+    pub fn build(self) -> PinkyTwirlEngine {
+        let mut layers = self.layers;
+        let directories_missing = layers.iter().all(|layer| match layer {
+            ConfigLayer::Directory(path) => !path.is_dir(),
+            ConfigLayer::Memory { .. } => false,
+        });
+        let mut config_location = self.config_location;
+        if self.embedded_fallback && directories_missing {
+            let layer_names: Vec<String> = layers.iter().map(|layer| layer.to_string()).collect();
+            log!(Warn, Config, "Config directories {:?} not found, using the embedded defaults.", layer_names);
+            layers = vec![crate::defaults::embedded_layer()];
+            config_location = Some((0, ConfigLocation::Embedded));
+        }
+
+        let mut engine = PinkyTwirlEngine::unloaded(
+            layers,
+            config_location,
+            self.platform,
+            crate::keycode_macos::create_keycode_map(),
+        );
+        engine.active_profile = self.profile.or_else(|| {
+            self.profile_file
+                .as_deref()
+                .and_then(crate::profiles::read_active_profile)
+        });
+        engine.profile_file = self.profile_file;
+        engine.startup = engine.load_configurations();
+        if let (Err(e), Some(profile)) = (&engine.startup, &engine.active_profile) {
+            // A profile that was removed from the config shouldn't keep the engine from starting.
+            log!(Warn, Config, "Cannot load profile '{}', using the base config: {}", profile, e);
+            engine.active_profile = None;
+            engine.startup = engine.load_configurations();
+        }
+
+        if let Err(e) = &engine.startup {
+            log!(Error, Config, "Engine error loading configurations: {}", e);
+        }
+        engine
+    }
 }
 
 impl PinkyTwirlEngine {
+    // Creates an engine from a config directory. Relative paths are resolved against the
+    // directory of the executable. If the directory is missing the embedded defaults are used.
     pub fn new(config_dir: String) -> Self {
-        let mut engine = PinkyTwirlEngine {
+        Self::builder()
+            .config_dir(config_dir)
+            .embedded_fallback(true)
+            .build()
+    }
+
+    pub fn with_platform(config_dir: String, platform: Platform) -> Self {
+        Self::with_layers(vec![config_dir], platform)
+    }
+
+    // Creates an engine from an ordered list of config directories, such as the bundled
+    // defaults, a shared team directory and a personal one. Definitions in later layers
+    // override those in earlier ones, per context, semantic action and chord.
+    pub fn with_layers(config_dirs: Vec<String>, platform: Platform) -> Self {
+        config_dirs
+            .into_iter()
+            .fold(Self::builder().platform(platform), |builder, config_dir| {
+                builder.config_dir(config_dir)
+            })
+            .build()
+    }
+
+    // Creates an engine from config text. The semantics are shared by every platform, so they
+    // can use the `Primary` and `Word` modifiers.
+    pub fn from_strs(contexts: &str, semantics: &str, mappings: &str) -> Self {
+        Self::builder()
+            .config_strs(contexts, semantics, mappings)
+            .build()
+    }
+
+    // Creates an engine from the config in `PINKYTWIRL_CONFIG_DIR`, the platform config
+    // directory or the embedded defaults, whichever is found first.
+    pub fn discover() -> Self {
+        Self::builder().discovered_config().build()
+    }
+
+    fn unloaded(
+        layers: Vec<ConfigLayer>,
+        config_location: Option<(usize, ConfigLocation)>,
+        platform: Platform,
+        keycodes: crate::keycode_macos::KeyCodeLookup,
+    ) -> Self {
+        PinkyTwirlEngine {
             contexts: HashMap::new(),
-            config_dir,
+            layers,
+            config_location,
+            platform,
+            active_profile: None,
+            profiles: Vec::new(),
+            profile_file: None,
+            diagnostics: Vec::new(),
+            config_files: Vec::new(),
+            config_watcher: None,
+            recorder: None,
+            secure_input: false,
+            failure: None,
+            enabled: true,
+            strict_invariants: false,
+            kill_chord: DEFAULT_KILL_CHORD.iter().map(|key| key.to_string()).collect(),
+            held_keys: Vec::new(),
+            suppressed_up_keys: Vec::new(),
+            help: None,
             pressed_keys: VecDeque::new(),
+            pressed_since: HashMap::new(),
+            stuck_key_timeout: Some(DEFAULT_STUCK_KEY_TIMEOUT),
             current_context: None,
-            keycodes: crate::keycode_macos::create_keycode_map(),
+            keycodes,
 
-            no_mapping_until_reset: false,
-            only_mappings_until_reset: false,
-            has_generated_synthetic_keys: false,
+            state: ChordState::Idle,
+            active_layer: None,
+            modal: None,
+            pending_count: None,
+            caps_lock: false,
+            caps_lock_before_press: None,
+            caps_lock_restore: None,
             synthetic_keys: Vec::new(),
             synthetic_keys_on_next_up_event: Vec::new(),
+            synthetic_down_keys: Vec::new(),
 
             startup: Ok(()),
-            debug_key_events: true,
-        };
+        }
+    }
 
-        engine.startup = engine.load_configurations();
+    // An engine with no config that passes every event through, for when creating one failed.
+    pub(crate) fn failed(message: String) -> Self {
+        let mut engine = Self::unloaded(Vec::new(), None, Platform::current(), Default::default());
+        engine.startup = Err(message.clone().into());
+        engine.failure = Some(message);
+        engine
+    }
 
-        if let Err(e) = &engine.startup {
-            eprintln!("Engine error loading configurations: {}", e);
+    pub fn builder() -> EngineBuilder {
+        EngineBuilder {
+            platform: Platform::current(),
+            layers: Vec::new(),
+            embedded_fallback: false,
+            config_location: None,
+            profile: None,
+            profile_file: None,
         }
-        engine
     }
 
     fn reset(&mut self) {
         self.pressed_keys.clear();
+        self.pressed_since.clear();
+        self.pending_count = None;
         self.current_context = None;
-        self.no_mapping_until_reset = false;
-        self.only_mappings_until_reset = false;
-        self.has_generated_synthetic_keys = false;
+        self.transition(ChordState::Idle);
+    }
+
+    // Moves to another state, which has to be one the transition table allows.
+    fn transition(&mut self, state: ChordState) {
+        if state == self.state {
+            return;
+        }
+        if !is_transition(self.state, state) {
+            let violation = format!("Invalid state transition: {} -> {}", self.state, state);
+            assert!(!self.strict_invariants, "{}", violation);
+            log!(Error, State, "{}", violation);
+        }
+        log!(Debug, State, "{} -> {}", self.state, state);
+        self.state = state;
+    }
+
+    // Checks what each state implies about the rest of the engine, after every event. A broken
+    // invariant is logged and every key released, so the engine recovers instead of failing for
+    // the rest of the session.
+    fn check_invariants(&mut self) {
+        let violation = match self.state {
+            _ if self.secure_input && self.state != ChordState::Idle => {
+                Some("a chord is in progress during secure input")
+            }
+            ChordState::Idle if !self.pressed_keys.is_empty() => Some("keys are held"),
+            ChordState::StemHeld | ChordState::Passthrough if self.pressed_keys.is_empty() => {
+                Some("no keys are held")
+            }
+            ChordState::Replaying => Some("the engine rests while replaying"),
+            _ => None,
+        };
+        if let Some(violation) = violation {
+            let violation = format!("Invalid engine state {}: {}", self.state, violation);
+            assert!(!self.strict_invariants, "{}", violation);
+            log!(Error, State, "{}", violation);
+            self.reset_all();
+        }
+    }
+
+    // Makes broken invariants panic instead of resetting the engine, so tests catch them.
+    pub fn set_strict_invariants(&mut self, strict: bool) {
+        self.strict_invariants = strict;
+    }
+
+    pub fn state(&self) -> ChordState {
+        self.state
     }
 
     pub fn load_configurations(&mut self) -> Result<(), Box<dyn Error>> {
-        let path = PathBuf::from(self.config_dir.clone());
-
-        // Get the path of the current executable.
-        let exe_path = std::env::current_exe()?;
-        println!("Current executable path: {:?}", exe_path);
-        // Set the current working directory to the directory of the executable.
-        let exe_dir = exe_path.parent().unwrap();
-        std::env::set_current_dir(exe_dir)?;
-
-        // Print the current working directory.
-        let cwd = std::env::current_dir()?;
-        println!("Current working directory: {:?}", cwd);
-        println!("Loading configurations from: {:?}", path);
-
-        // Fully resolve the path.
-        let path = std::fs::canonicalize(path)?;
-        println!("Resolved path for configurations: {:?}", path);
-
-        let contexts_path = path.join("contexts.txt");
-        println!("Loading contexts from: {:?}", contexts_path);
-        self.contexts = crate::contexts::parse_yaml_file(&contexts_path)?;
-
-        let semantics_path = path.join("semantics_macos.txt");
-        println!("Loading semantics from: {:?}", semantics_path);
-        crate::semantics::parse_semantics_file(
-            &semantics_path,
-            &mut self.contexts,
+        let loaded = crate::loader::load_config(
+            &self.layers,
+            self.active_profile.as_deref(),
+            self.platform,
             &self.keycodes,
         )?;
+        self.apply_loaded_config(loaded);
+        Ok(())
+    }
+
+    // Loads the config into a fresh set of contexts and only swaps it in when it loaded without
+    // errors, so a broken edit keeps the previous config active.
+    pub fn reload_configurations(&mut self) -> Result<ReloadReport, Box<dyn Error>> {
+        let loaded = crate::loader::load_config(
+            &self.layers,
+            self.active_profile.as_deref(),
+            self.platform,
+            &self.keycodes,
+        )?;
+        Ok(self.apply_loaded_config(loaded))
+    }
 
-        let mappings_path = path.join("mappings.txt");
-        println!("Loading mappings from: {:?}", mappings_path);
-        crate::mappings::parse_mappings_file(&mappings_path, &mut self.contexts, &self.keycodes)?;
+    fn apply_loaded_config(&mut self, loaded: LoadedConfig) -> ReloadReport {
+        let report = ReloadReport::between(&self.contexts, &loaded);
+        for diagnostic in &loaded.diagnostics {
+            log!(Warn, Config, "{}", diagnostic);
+        }
+        self.contexts = loaded.contexts;
+        self.profiles = loaded.profiles;
+        self.diagnostics = loaded.diagnostics;
+        self.config_files = loaded.files;
+        self.startup = Ok(());
+        report
+    }
 
-        Ok(())
+    // Warnings from the last successful load, such as unknown keys or parents.
+    pub fn diagnostics(&self) -> &[String] {
+        &self.diagnostics
+    }
+
+    // Watches the config files on a background thread. Changes are parsed there and picked up
+    // on the next key event, see `poll_config_watcher`. Reloading on Escape is then disabled.
+    pub fn watch_config(&mut self) {
+        self.watch_config_with(Duration::from_millis(500), Duration::from_millis(250));
+    }
+
+    pub fn watch_config_with(&mut self, poll_interval: Duration, debounce: Duration) {
+        let files = self.watched_files();
+        self.config_watcher = Some(ConfigWatcher::start(
+            self.layers.clone(),
+            self.active_profile.clone(),
+            self.platform,
+            files,
+            poll_interval,
+            debounce,
+        ));
+    }
+
+    pub fn stop_watching_config(&mut self) {
+        self.config_watcher = None;
+    }
+
+    pub fn is_watching_config(&self) -> bool {
+        self.config_watcher.is_some()
+    }
+
+    // Swaps in a config that the watcher finished loading, if there is one. Configs that failed
+    // to load are reported and leave the current config in place.
+    pub fn poll_config_watcher(&mut self) -> Option<Result<ReloadReport, String>> {
+        let result = self.config_watcher.as_ref()?.try_recv()?;
+        Some(result.map(|loaded| self.apply_loaded_config(loaded)))
+    }
+
+    fn watched_files(&self) -> Vec<PathBuf> {
+        if !self.config_files.is_empty() {
+            return self.config_files.clone();
+        }
+        // Nothing loaded yet, so watch every file a config directory can have until it loads.
+        let mut file_names = vec![
+            "contexts.txt".to_string(),
+            "mappings.txt".to_string(),
+            "semantics.txt".to_string(),
+            UNIFIED_CONFIG_FILE.to_string(),
+            PROFILES_FILE.to_string(),
+        ];
+        file_names.extend(Platform::ALL.iter().map(|platform| platform.semantics_file_name()));
+        self.layers
+            .iter()
+            .filter_map(|layer| match layer {
+                ConfigLayer::Directory(path) => Some(path),
+                ConfigLayer::Memory { .. } => None,
+            })
+            .flat_map(|path| file_names.iter().map(|file_name| path.join(file_name)))
+            .collect()
+    }
+
+    pub fn config_layers(&self) -> &[ConfigLayer] {
+        &self.layers
+    }
+
+    pub fn config_location(&self) -> Option<&ConfigLocation> {
+        self.config_location.as_ref().map(|(_, location)| location)
+    }
+
+    pub fn config_location_description(&self) -> String {
+        match self.config_location() {
+            Some(location) => location.to_string(),
+            None => self
+                .layers
+                .iter()
+                .map(|layer| layer.to_string())
+                .collect::<Vec<String>>()
+                .join(", "),
+        }
+    }
+
+    // On a first run the engine runs on the embedded defaults. The host can then offer to write
+    // a copy of them into the platform config directory, so they can be edited.
+    pub fn offers_default_config_install(&self) -> bool {
+        self.config_location() == Some(&ConfigLocation::Embedded)
+            && crate::discovery::platform_config_dir().is_some()
+    }
+
+    // Writes the embedded defaults into the platform config directory and loads them from there.
+    pub fn install_default_config(&mut self) -> Result<PathBuf, Box<dyn Error>> {
+        let index = match &self.config_location {
+            Some((index, ConfigLocation::Embedded)) => *index,
+            _ => return Err("The engine is not running on the embedded defaults".into()),
+        };
+        let config_dir = crate::discovery::platform_config_dir()
+            .ok_or("No platform config directory is known")?;
+
+        for path in crate::discovery::write_default_config(&config_dir)? {
+            log!(Info, Config, "Wrote default config: {:?}", path);
+        }
+
+        self.layers[index] = ConfigLayer::Directory(config_dir.clone());
+        self.config_location = Some((index, ConfigLocation::PlatformDir(config_dir.clone())));
+        self.profile_file = Some(config_dir.join(ACTIVE_PROFILE_FILE));
+        self.try_reload_configurations();
+        // Watch the new directory instead of the embedded defaults.
+        if let Some(watcher) = self.config_watcher.take() {
+            self.watch_config_with(watcher.poll_interval, watcher.debounce);
+        }
+        Ok(config_dir)
+    }
+
+    pub fn macos_install_default_config(&mut self) -> bool {
+        match self.install_default_config() {
+            Ok(_) => true,
+            Err(e) => {
+                log!(Error, Config, "Error installing the default config: {}", e);
+                false
+            }
+        }
+    }
+
+    pub fn context_origin(&self, context_name: &str) -> Option<&Origin> {
+        self.contexts.get(context_name)?.origin.as_ref()
+    }
+
+    pub fn semantic_action_origin(&self, context_name: &str, action_name: &str) -> Option<&Origin> {
+        self.contexts
+            .get(context_name)?
+            .semantic_origins
+            .get(&self.platform)?
+            .get(action_name)
+    }
+
+    pub fn mapping_origin(&self, context_name: &str, chord: &str) -> Option<&Origin> {
+        self.contexts
+            .get(context_name)?
+            .mapping_origins
+            .get(&chord.to_lowercase())
+    }
+
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    pub(crate) fn keycodes(&self) -> &crate::keycode_macos::KeyCodeLookup {
+        &self.keycodes
     }
 
     pub fn try_reload_configurations(&mut self) {
-        let backup_contexts = self.contexts.clone();
-        if let Err(e) = self.load_configurations() {
-            eprintln!("Error reloading configurations: {}", e);
-            self.contexts = backup_contexts;
-        } else {
-            println!("Configurations reloaded successfully.");
-            self.startup = Ok(());
+        match self.reload_configurations() {
+            Ok(report) => log!(Info, Config, "Configurations reloaded successfully.\n{}", report.to_string().trim_end()),
+            Err(e) => log!(Error, Config, "Error reloading configurations: {}", e),
         }
     }
 
+    // Looks up a context by its name rather than by application.
+    pub fn context(&self, context_name: &str) -> Option<&Context> {
+        self.contexts.get(context_name)
+    }
+
+    pub(crate) fn contexts(&self) -> &HashMap<String, Context> {
+        &self.contexts
+    }
+
+    pub fn context_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.contexts.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+
+    // Writes the chords of every context in the `mappings.txt` syntax.
+    pub fn serialize_mappings(&self) -> String {
+        let contexts: Vec<&Context> = self.contexts.values().collect();
+        let action_names = crate::formatter::action_names(contexts.iter().copied());
+        crate::formatter::serialize_mappings(&contexts, &self.keycodes, &action_names)
+    }
+
+    // Writes the effective semantic actions for a platform in the `semantics_*.txt` syntax.
+    // Other platforms only have the semantics that contexts with `semantics:` asked for.
+    pub fn serialize_semantics(&self, platform: Platform) -> String {
+        let contexts: Vec<&Context> = self.contexts.values().collect();
+        let action_names = crate::formatter::action_names(contexts.iter().copied());
+        crate::formatter::serialize_semantics(
+            &contexts,
+            platform,
+            self.platform,
+            &self.keycodes,
+            &action_names,
+        )
+    }
+
     pub fn get_context(&self, app_name: &str, window_name: &str) -> Option<&Context> {
         // Helper function for exact match
         let exact_match = |name: &str| -> Option<&Context> {
@@ -193,127 +712,704 @@ impl PinkyTwirlEngine {
             println!("Context: {}", context.name);
             println!("  Aliases: {:?}", context.aliases);
             println!("  Parent: {:?}", context.parent);
+            println!("  Passthrough all: {}", context.passthrough_all);
+            println!("  Chord counts: {:?}", context.chord_counts);
+            println!("  Semantics platform: {:?}", context.semantics_platform);
+            println!("  Modifier translation: {:?}", context.modifier_translation);
             println!("  Semantic Actions:");
             for (action_name, action) in &context.semantic_actions {
-                println!("    {}: {}", action_name, action);
+                match self.semantic_action_origin(&context.name, action_name) {
+                    Some(origin) => println!("    {}: {}  [{}]", action_name, action, origin),
+                    None => println!("    {}: {}", action_name, action),
+                }
             }
             println!("  Key Mappings:");
             for (key, action) in &context.key_mappings {
-                println!("    {}: {}", key, action);
+                match self.mapping_origin(&context.name, key) {
+                    Some(origin) => println!("    {}: {}  [{}]", key, action, origin),
+                    None => println!("    {}: {}", key, action),
+                }
             }
             println!();
         }
     }
 
+    // Records every key event the engine handles from now on, with the focused window, the
+    // time and the result, until `stop_recording`. Replay it with `replay_recording`.
+    pub fn start_recording<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Box<dyn Error>> {
+        let path = path.as_ref();
+        let recorder = Recorder::create(path)
+            .map_err(|e| format!("Cannot create recording {:?}: {}", path, e))?;
+        self.stop_recording();
+        self.recorder = Some(recorder);
+        Ok(())
+    }
+
+    pub fn macos_start_recording(&mut self, path: String) -> bool {
+        match self.start_recording(&path) {
+            Ok(()) => true,
+            Err(e) => {
+                log!(Error, Recording, "Error starting the recording: {}", e);
+                false
+            }
+        }
+    }
+
+    pub fn stop_recording(&mut self) {
+        if let Some(mut recorder) = self.recorder.take() {
+            if let Err(e) = recorder.flush() {
+                log!(Error, Recording, "Error writing the recording: {}", e);
+            }
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // The host signals secure input while a password field or similar has focus. Until it ends,
+    // every event passes through untouched, and nothing about it is kept or recorded. A chord in
+    // progress ends: pending modifier releases are queued for `get_synthetic_events`, and keys
+    // held back for a stem are dropped rather than typed into the field.
+    pub fn set_secure_input(&mut self, enabled: bool) {
+        if enabled == self.secure_input {
+            return;
+        }
+        log!(Info, State, "Secure input {}", if enabled { "on" } else { "off" });
+        self.release_synthetic_keys();
+        self.reset();
+        // Keys released during secure input are never seen.
+        self.held_keys.clear();
+        self.secure_input = enabled;
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.record_secure_input(enabled) {
+                log!(Error, Recording, "Error writing the recording, recording stopped: {}", e);
+                self.recorder = None;
+            }
+        }
+    }
+
+    pub fn is_secure_input(&self) -> bool {
+        self.secure_input
+    }
+
+    // Ends the chord in progress and queues up events for every key the engine has posted down
+    // events for, along with the pending modifier releases. For when the host has lost track of
+    // the keyboard, e.g. after its event tap was disabled.
+    pub fn reset_all(&mut self) {
+        log!(Info, State, "Resetting all keys");
+        self.release_synthetic_keys();
+        self.reset();
+        self.clear_layer();
+        if let Some(modal) = &mut self.modal {
+            modal.clear();
+        }
+    }
+
+    // A disabled engine passes every event through. Disabling it ends the chord in progress and
+    // queues up releases for the keys it posted down events for, like `reset_all`.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled == self.enabled {
+            return;
+        }
+        log!(Info, State, "Engine {}", if enabled { "enabled" } else { "disabled" });
+        if !enabled {
+            // The host never saw the downs of the keys held in a chord, so it mustn't see their ups.
+            if self.state != ChordState::Passthrough {
+                let held_keys: Vec<String> = self.pressed_keys.iter().map(|key| key.key.clone()).collect();
+                self.suppressed_up_keys.extend(held_keys);
+            }
+            self.release_synthetic_keys();
+            self.reset();
+            self.clear_layer();
+            self.set_modal(false);
+        }
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // Sets the keys that disable the engine when they are all held, by their key names such as
+    // `rightshift`. No keys turn the kill chord off.
+    pub fn set_kill_chord(&mut self, keys: &[&str]) {
+        self.kill_chord = keys.iter().map(|key| key.to_lowercase()).collect();
+    }
+
+    fn track_held_key(&mut self, event: &KeyEvent) {
+        match event.state {
+            KeyState::Down => {
+                if !self.held_keys.contains(&event.key) {
+                    self.held_keys.push(event.key.clone());
+                }
+            }
+            KeyState::Up => self.held_keys.retain(|key| *key != event.key),
+            KeyState::DownUp => (),
+        }
+    }
+
+    // The kill chord is matched against the held keys rather than the chord state, so it works
+    // even when the config or the state is broken.
+    fn is_kill_chord(&self, event: &KeyEvent) -> bool {
+        event.state == KeyState::Down
+            && self.kill_chord.contains(&event.key)
+            && self.kill_chord.iter().all(|key| self.held_keys.contains(key))
+    }
+
+    // Whether the held keys are a chord for `Engine.Toggle`, which a disabled engine still watches for.
+    fn held_keys_toggle_engine(&self, app_name: &str, window_name: &str) -> bool {
+        let Some(context) = self.get_context(app_name, window_name) else {
+            return false;
+        };
+        let platform = self.context_semantics_platform(context);
+        self.find_chord_mapping(context, &self.held_keys.join(" + "))
+            .is_some_and(|(_, action)| {
+                self.resolve_engine_commands(&action, context, platform)
+                    .contains(&EngineCommand::Toggle)
+            })
+    }
+
+    fn run_engine_command(&mut self, command: EngineCommand, app_name: &str, window_name: &str) {
+        log!(Info, State, "Running {}", command);
+        match command {
+            EngineCommand::Reload => self.try_reload_configurations(),
+            EngineCommand::Toggle => self.set_enabled(!self.enabled),
+            EngineCommand::SwitchProfile(profile) => {
+                if let Err(e) = self.switch_profile(&profile) {
+                    log!(Error, Config, "Error switching profiles: {}", e);
+                }
+            }
+            EngineCommand::ToggleLayer(layer) => {
+                if let Err(e) = self.toggle_layer(&layer) {
+                    log!(Error, State, "Error toggling the layer: {}", e);
+                }
+            }
+            EngineCommand::OneShotLayer(layer) => {
+                if let Err(e) = self.one_shot_layer(&layer) {
+                    log!(Error, State, "Error activating the layer: {}", e);
+                }
+            }
+            EngineCommand::Modal => self.set_modal(true),
+            EngineCommand::ShowHelp => self.help = Some(self.help_text(app_name, window_name)),
+            EngineCommand::Reset => self.reset_all(),
+        }
+    }
+
+    // Loads the config again with the layers of another profile, or with only the base layers
+    // for an empty name. A profile that fails to load leaves the current one active. The new
+    // profile is remembered for the next run.
+    pub fn switch_profile(&mut self, profile: &str) -> Result<(), Box<dyn Error>> {
+        let profile = (!profile.is_empty()).then(|| profile.to_string());
+        let previous = std::mem::replace(&mut self.active_profile, profile);
+        if let Err(e) = self.reload_configurations() {
+            self.active_profile = previous;
+            return Err(e);
+        }
+        log!(Info, Config, "Switched to {}", self.profile_description());
+
+        if let Some(profile_file) = &self.profile_file {
+            if let Err(e) =
+                crate::profiles::write_active_profile(profile_file, self.active_profile.as_deref())
+            {
+                log!(Warn, Config, "Cannot remember the active profile: {}", e);
+            }
+        }
+        // Switching can happen on a chord, so the watcher is pointed at the new profile's files
+        // instead of being restarted.
+        let files = self.watched_files();
+        if let Some(watcher) = &mut self.config_watcher {
+            watcher.retarget(self.active_profile.clone(), files);
+        }
+        Ok(())
+    }
+
+    pub fn active_profile(&self) -> Option<&str> {
+        self.active_profile.as_deref()
+    }
+
+    // The profiles the config defines, sorted by name.
+    pub fn profiles(&self) -> &[String] {
+        &self.profiles
+    }
+
+    pub fn profile_description(&self) -> String {
+        match &self.active_profile {
+            Some(profile) => format!("profile {}", profile),
+            None => "the base config".to_string(),
+        }
+    }
+
+    // What the host shows as the state of the engine, e.g. `Enabled, profile Work`.
+    pub fn status_description(&self) -> String {
+        if let Some(failure) = &self.failure {
+            return format!("Failed: {}", failure);
+        }
+        let mut status = if self.enabled { "Enabled" } else { "Disabled" }.to_string();
+        if let Some(profile) = &self.active_profile {
+            status.push_str(&format!(", profile {}", profile));
+        }
+        if let Some(layer) = &self.active_layer {
+            status.push_str(&format!(", layer {}", layer));
+        }
+        if let Some(modal) = &self.modal {
+            status.push_str(format!(", modal {}", modal).trim_end());
+        }
+        status
+    }
+
+    // In modal mode keys typed without a modifier other than shift are commands, such as `5j` or
+    // `d$`, instead of text. `i` and Escape leave it again.
+    pub fn set_modal(&mut self, modal: bool) {
+        if modal == self.modal.is_some() {
+            return;
+        }
+        log!(Info, State, "Modal mode {}", if modal { "on" } else { "off" });
+        self.modal = modal.then(ModalInput::default);
+    }
+
+    pub fn is_modal(&self) -> bool {
+        self.modal.is_some()
+    }
+
+    // The part of a modal command typed so far, e.g. `2d`.
+    pub fn modal_input(&self) -> Option<&ModalInput> {
+        self.modal.as_ref()
+    }
+
+    // Locks the layer of a stem such as `D4` on, or off again if it is the locked layer.
+    pub fn toggle_layer(&mut self, stem: &str) -> Result<(), Box<dyn Error>> {
+        let stem = self.layer_stem(stem)?;
+        if self.active_layer.as_ref().is_some_and(|layer| {
+            layer.stem == stem && layer.mode == LayerMode::Locked
+        }) {
+            self.clear_layer();
+        } else {
+            self.set_layer(stem, LayerMode::Locked);
+        }
+        Ok(())
+    }
+
+    // Applies the layer of a stem to the next chord only.
+    pub fn one_shot_layer(&mut self, stem: &str) -> Result<(), Box<dyn Error>> {
+        let stem = self.layer_stem(stem)?;
+        self.set_layer(stem, LayerMode::OneShot);
+        Ok(())
+    }
+
+    pub fn clear_layer(&mut self) {
+        if let Some(layer) = self.active_layer.take() {
+            log!(Debug, State, "Layer {} off", layer.stem);
+        }
+    }
+
+    pub fn active_layer(&self) -> Option<&ActiveLayer> {
+        self.active_layer.as_ref()
+    }
+
+    fn set_layer(&mut self, stem: String, mode: LayerMode) {
+        let layer = ActiveLayer { stem, mode };
+        log!(Debug, State, "Layer {} on", layer);
+        self.active_layer = Some(layer);
+    }
+
+    // Layers are named by their stem, which some context has to map.
+    fn layer_stem(&self, stem: &str) -> Result<String, Box<dyn Error>> {
+        let stem = crate::query::normalize_chord(stem);
+        let stem_action = SemanticAction::Action("MappingStem".to_string());
+        if self
+            .contexts
+            .values()
+            .any(|context| context.key_mappings.get(&stem) == Some(&stem_action))
+        {
+            Ok(stem)
+        } else {
+            Err(format!("No layer for the stem '{}'", stem).into())
+        }
+    }
+
+    // The help `Engine.ShowHelp` asked for, once.
+    pub fn take_help(&mut self) -> Option<String> {
+        self.help.take()
+    }
+
+    // Disables the engine after a panic. Keys it had posted down events for are released, and
+    // from then on every event passes through.
+    pub(crate) fn fail(&mut self, message: String) {
+        log!(Error, State, "{}, passing every event through", message);
+        self.failure = Some(message);
+        // The state that panicked may panic again while the keys are released, which mustn't
+        // unwind into the host either.
+        let released = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            self.release_synthetic_keys();
+            self.reset();
+        }));
+        if released.is_err() {
+            log!(Error, State, "Panic while releasing the held keys");
+        }
+    }
+
+    // Why the engine stopped handling events, if it did.
+    pub fn failure(&self) -> Option<&str> {
+        self.failure.as_deref()
+    }
+
+    fn release_synthetic_keys(&mut self) {
+        let mut releases = std::mem::take(&mut self.synthetic_keys_on_next_up_event);
+        for key in std::mem::take(&mut self.synthetic_down_keys) {
+            if !releases.iter().any(|release| release.key == key.key) {
+                releases.push(KeyEvent {
+                    key: key.key,
+                    code: key.code,
+                    state: KeyState::Up,
+                    shift: false,
+                    ctrl: false,
+                    alt: false,
+                    meta: false,
+                    func: false,
+                    modifier_down_only: false,
+                });
+            }
+        }
+        self.synthetic_keys.append(&mut releases);
+    }
+
+    // Keys held without an event for longer than the timeout are taken to be stuck, and the chord
+    // they are part of is reset. `None` turns the watchdog off.
+    pub fn set_stuck_key_timeout(&mut self, timeout: Option<Duration>) {
+        self.stuck_key_timeout = timeout;
+    }
+
+    // Modifiers on either side show up in the flags of every event while they are held, other
+    // keys repeat. Caps Lock does neither, so it counts as held for as long as events come in.
+    fn refresh_held_modifiers(&mut self, event: &KeyEvent, now: Instant) {
+        let held_modifiers = [
+            ("shift", event.shift),
+            ("rightshift", event.shift),
+            ("ctrl", event.ctrl),
+            ("rightcontrol", event.ctrl),
+            ("alt", event.alt),
+            ("rightoption", event.alt),
+            ("meta", event.meta),
+            ("rightcommand", event.meta),
+            ("fn", event.func),
+            (CAPS_LOCK_KEY, true),
+        ];
+        for (modifier, held) in held_modifiers {
+            if held {
+                if let Some(since) = self.pressed_since.get_mut(modifier) {
+                    *since = now;
+                }
+            }
+        }
+    }
+
+    fn refresh_pressed_keys(&mut self, event: &KeyEvent, now: Instant) {
+        match event.state {
+            KeyState::Down => {
+                self.pressed_since.insert(event.key.clone(), now);
+            }
+            KeyState::Up => {
+                self.pressed_since.remove(&event.key);
+            }
+            KeyState::DownUp => (),
+        }
+    }
+
+    fn release_stuck_keys(&mut self, now: Instant) {
+        let Some(timeout) = self.stuck_key_timeout else {
+            return;
+        };
+        let stuck: Vec<String> = self
+            .pressed_keys
+            .iter()
+            .filter(|key| {
+                self.pressed_since
+                    .get(&key.key)
+                    .is_some_and(|since| now.duration_since(*since) > timeout)
+            })
+            .map(|key| redact_key(&key.key))
+            .collect();
+        if !stuck.is_empty() {
+            log!(Warn, State, "Keys held for longer than {:?} without an event, releasing them: {}", timeout, stuck.join(", "));
+            self.reset_all();
+        }
+    }
+
+    // The host reports the keys that are physically held, e.g. after a focus change or waking
+    // from sleep. If the engine holds keys that were released in the meantime, or still has keys
+    // down outside a chord, everything is reset.
+    pub fn sync_physical_state(&mut self, held_keys: &[&str]) {
+        self.held_keys.retain(|key| held_keys.contains(&key.as_str()));
+        self.suppressed_up_keys.retain(|key| held_keys.contains(&key.as_str()));
+        let released: Vec<String> = self
+            .pressed_keys
+            .iter()
+            .filter(|key| !held_keys.contains(&key.key.as_str()))
+            .map(|key| redact_key(&key.key))
+            .collect();
+        if !released.is_empty() {
+            log!(Warn, State, "Keys were released without an event: {}", released.join(", "));
+            self.reset_all();
+        } else if self.state == ChordState::Idle && !self.synthetic_down_keys.is_empty() {
+            log!(Warn, State, "Keys are down outside a chord");
+            self.reset_all();
+        }
+    }
+
+    pub fn macos_sync_physical_state(&mut self, held_key_codes: Vec<i64>) {
+        let held_keys: Vec<String> = held_key_codes
+            .iter()
+            .filter_map(|code| self.keycodes.keycode_to_name.get(code).cloned())
+            .collect();
+        let held_keys: Vec<&str> = held_keys.iter().map(String::as_str).collect();
+        self.sync_physical_state(&held_keys);
+    }
+
     pub fn handle_key_event(
         &mut self,
         event: KeyEvent,
         app_name: &str,
         window_name: &str,
     ) -> (bool, Vec<KeyEvent>) {
-        if self.debug_key_events {
-            println!(
-                "Engine key event: {} {:?} {} {} {}",
-                event.key, event.state, event.shift, event.ctrl, event.alt
-            );
+        if self.secure_input {
+            return (false, Vec::new());
+        }
+        self.track_held_key(&event);
+        if event.state == KeyState::Up && self.suppressed_up_keys.contains(&event.key) {
+            self.suppressed_up_keys.retain(|key| *key != event.key);
+            return (true, Vec::new());
+        }
+        if event.key == CAPS_LOCK_KEY && event.state == KeyState::Down {
+            self.caps_lock = !self.caps_lock;
+        }
+        if self.is_kill_chord(&event) {
+            log!(Warn, State, "Kill chord pressed");
+            self.set_enabled(false);
+        }
+        if !self.enabled {
+            if event.state == KeyState::Down && self.held_keys_toggle_engine(app_name, window_name) {
+                self.set_enabled(true);
+                self.suppressed_up_keys.push(event.key);
+                return (true, Vec::new());
+            }
+            return (false, Vec::new());
         }
 
+        let recorded_event = self.recorder.is_some().then(|| event.clone());
+        let caps_lock_event = (event.key == CAPS_LOCK_KEY).then_some(event.state.clone());
+        // Tapping Caps Lock alone keeps its toggle, using it as a stem doesn't.
+        let caps_lock_tapped = self.state == ChordState::StemHeld
+            && self.pressed_keys.iter().all(|key| key.key == CAPS_LOCK_KEY);
+        // A chord that disables the engine was suppressed, and so is the up of its key.
+        let toggle_key = (event.state == KeyState::Down).then(|| event.key.clone());
+        let (suppress, output) = self.process_key_event(event, app_name, window_name);
+        if suppress && !self.enabled {
+            self.suppressed_up_keys.extend(toggle_key);
+        }
+        match caps_lock_event {
+            Some(KeyState::Down) if suppress => {
+                self.caps_lock_before_press = Some(!self.caps_lock);
+            }
+            Some(KeyState::Up) => {
+                if let Some(before) = self.caps_lock_before_press.take() {
+                    if !caps_lock_tapped {
+                        log!(Debug, State, "Restoring Caps Lock {}", if before { "on" } else { "off" });
+                        self.caps_lock = before;
+                        self.caps_lock_restore = Some(before);
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        if let (Some(recorder), Some(event)) = (&mut self.recorder, recorded_event) {
+            let mut result =
+                recorder.record(&event, app_name, window_name, suppress, &output, &self.keycodes);
+            // Write out each chord once it ends, so a recording of a stuck key isn't lost.
+            if result.is_ok() && self.pressed_keys.is_empty() {
+                result = recorder.flush();
+            }
+            if let Err(e) = result {
+                log!(Error, Recording, "Error writing the recording, recording stopped: {}", e);
+                self.recorder = None;
+            }
+        }
+
+        self.check_invariants();
+        (suppress, output)
+    }
+
+    fn process_key_event(
+        &mut self,
+        event: KeyEvent,
+        app_name: &str,
+        window_name: &str,
+    ) -> (bool, Vec<KeyEvent>) {
+        match self.poll_config_watcher() {
+            Some(Ok(report)) => log!(Info, Config, "Configurations reloaded successfully.\n{}", report.to_string().trim_end()),
+            Some(Err(e)) => log!(Error, Config, "Error reloading configurations: {}", e),
+            None => (),
+        }
+
+        let now = Instant::now();
+        // The key of the event is only recorded after the watchdog ran, since releasing stuck keys
+        // forgets when every key was pressed.
+        self.refresh_held_modifiers(&event, now);
+        self.release_stuck_keys(now);
+        self.refresh_pressed_keys(&event, now);
+
+        log!(
+            Trace,
+            Keys,
+            "Key event: {} {:?} {} {} {}",
+            redact_key(&event.key),
+            event.state,
+            event.shift,
+            event.ctrl,
+            event.alt
+        );
+
         match event.state {
             KeyState::Down => {
                 // Add the key to the pressed keys if it's not already there.
                 if !self
                     .pressed_keys
                     .iter()
-                    .any(|k| &k.key == &event.key && k.state == KeyState::Down)
+                    .any(|k| k.key == event.key && k.state == KeyState::Down)
                 {
                     self.pressed_keys.push_back(event.clone());
                 }
 
-                if self.no_mapping_until_reset {
+                if self.state == ChordState::Passthrough {
                     // If we're in a state where no mappings should be applied, always let the event through.
                     return (false, Vec::new());
                 }
 
+                if self.is_modal_key(&event) {
+                    return self.process_modal_key(&event, app_name, window_name);
+                }
+
+                if self.escape_unlocks_layer(&event, app_name, window_name) {
+                    self.clear_layer();
+                    self.transition(ChordState::MappingActive);
+                    return (true, Vec::new());
+                }
+
+                if self.pending_count.is_some() && self.add_to_pending_count(&event, app_name, window_name) {
+                    return (true, Vec::new());
+                }
+
                 let action = if let Some(context) = self.get_context(app_name, window_name) {
-                    if self.debug_key_events {
-                        println!("Engine context: {}", context.name);
-                        println!("Engine pressed keys: {:?}", self.pressed_keys);
-                    }
+                    log!(Trace, Keys, "Context: {}, pressed keys: {}", context.name, redact_keys(&self.pressed_keys));
 
-                    if let Some(action) = self.find_chord_action(&context, &self.pressed_keys) {
-                        Some(action)
-                    } else {
-                        None
-                    }
+                    self.find_chord_action(context, &self.pressed_keys)
                 } else {
-                    if self.debug_key_events {
-                        println!("No context found for app: {} window: {}", app_name, window_name);
-                    }
+                    log!(Debug, State, "No context found for app: {} window: {}", app_name, window_name);
 
                     // If no context is found, let the key through
+                    if self.state == ChordState::Idle {
+                        self.transition(ChordState::Passthrough);
+                    }
                     return (false, Vec::new());
                 };
 
-                if let Some(action) = action {
+                // A one-shot layer is used up by the next chord, but not by a stem or a modifier
+                // that may still become part of one.
+                let is_stem = action == Some(SemanticAction::Action("MappingStem".to_string()));
+                let starts_chord = action.is_none()
+                    && self.pressed_keys.len() == 1
+                    && normalize_modifier_name(&event.key).is_none();
+                if self.active_layer.as_ref().is_some_and(|layer| layer.mode == LayerMode::OneShot)
+                    && ((action.is_some() && !is_stem) || starts_chord)
+                {
+                    self.clear_layer();
+                }
+
+                if action == Some(SemanticAction::Passthrough) {
+                    // The chord is explicitly unbound, so nothing is applied until all keys are
+                    // released. Keys that were held back as part of a stem are played back.
+                    log!(Debug, State, "Chord is unbound, passing through until all keys are released");
+                    if self.pressed_keys.len() == 1 {
+                        self.transition(ChordState::Passthrough);
+                        (false, Vec::new())
+                    } else {
+                        self.transition(ChordState::Replaying);
+                        let synthetic_events = self.pressed_keys.iter().cloned().collect();
+                        self.transition(ChordState::Passthrough);
+                        (true, synthetic_events)
+                    }
+                } else if let Some(action) = action {
                     if action != SemanticAction::Action("MappingStem".to_string()) {
                         // We have a real action, we don't want to consider this key part of the stem.
-                        self.pressed_keys.retain(|k| &k.key != &event.key);
-                        self.has_generated_synthetic_keys = true;
+                        self.pressed_keys.retain(|k| k.key != event.key);
+                        self.transition(ChordState::MappingActive);
+                    } else if self.state == ChordState::Idle {
+                        self.transition(ChordState::StemHeld);
                     }
                     // There is a mapping for the current chord, so we will suppress the
                     // key events and play back the synthetic events instead. We will stay
                     // in this mode until all keys are released.
                     let context = self.get_context(app_name, window_name).unwrap();
-                    let synthetic_events = self.resolve_semantic_action(&action, context);
-                    self.only_mappings_until_reset = true;
-                    return (true, synthetic_events);
+                    let platform = self.context_semantics_platform(context);
+                    let mut synthetic_events = self.synthetic_events_for(&action, context);
+                    let commands = self.resolve_engine_commands(&action, context, platform);
+                    if action != SemanticAction::Action("MappingStem".to_string()) {
+                        if let Some(count) = self.pending_count.take() {
+                            synthetic_events = (0..count)
+                                .flat_map(|_| synthetic_events.iter().cloned())
+                                .collect();
+                        }
+                    }
+                    for command in commands {
+                        self.run_engine_command(command, app_name, window_name);
+                    }
+                    (true, synthetic_events)
                 } else if self.pressed_keys.len() == 1 {
                     // If it's the first key and doesn't match any chord, let it through.
-                    self.no_mapping_until_reset = true;
-                    return (false, Vec::new());
-                } else {
+                    log!(Debug, State, "No chord starts with this key, passing through until all keys are released");
+                    self.transition(ChordState::Passthrough);
+                    (false, Vec::new())
+                } else if self.add_to_pending_count(&event, app_name, window_name) {
+                    (true, Vec::new())
+                } else if self.state != ChordState::Idle {
                     // If it doesn't match any chord, play back the buffered keys, unless we're in a state where only mappings should be applied.
-                    if self.only_mappings_until_reset {
-                        return (true, Vec::new());
-                    } else {
-                        let synthetic_events = self.pressed_keys.iter().cloned().collect();
-                        // self.no_mapping_until_reset = true;
-                        self.reset();
-                        return (true, synthetic_events);
-                    }
+                    (true, Vec::new())
+                } else {
+                    self.transition(ChordState::Replaying);
+                    let synthetic_events = self.pressed_keys.iter().cloned().collect();
+                    self.reset();
+                    (true, synthetic_events)
                 }
             }
             KeyState::Up => {
-                // If the key is the escape key, reload the configurations.
-                if event.key == "escape" {
-                    self.try_reload_configurations();
-                }
-
-                if self.only_mappings_until_reset && !self.has_generated_synthetic_keys && !self.pressed_keys.is_empty() {
-                    let synthetic_events = self.pressed_keys.iter().cloned().collect();
+                if self.state == ChordState::StemHeld {
+                    // The stem was released without completing a chord, so its keys are played back.
+                    // Caps Lock already toggled when it went down, so it isn't pressed again.
+                    self.transition(ChordState::Replaying);
+                    let synthetic_events = self
+                        .pressed_keys
+                        .iter()
+                        .filter(|key| key.key != CAPS_LOCK_KEY)
+                        .cloned()
+                        .collect();
                     self.reset();
-                    // self.pressed_keys.clear();
-                    // if event.key == "meta" {
-                    //     self.reset();
-                    // }
-                    return (false, synthetic_events);    
+                    (false, synthetic_events)
                 } else {
-                    self.pressed_keys.retain(|k| &k.key != &event.key);
+                    self.pressed_keys.retain(|k| k.key != event.key);
                     if event.key == "meta" || self.pressed_keys.is_empty() {
                         self.reset();
                     }
-                    return (false, Vec::new());
-                };
-            }
-            KeyState::DownUp => {
-                return (false, Vec::new());
+                    (false, Vec::new())
+                }
             }
+            KeyState::DownUp => (false, Vec::new()),
         }
     }
 
-    fn find_chord_action<'a>(
+    fn find_chord_action(
         &self,
-        context: &'a Context,
+        context: &Context,
         chord: &VecDeque<KeyEvent>,
     ) -> Option<SemanticAction> {
         let chord_str = chord
@@ -321,55 +1417,327 @@ impl PinkyTwirlEngine {
             .map(|key| key.key.clone())
             .collect::<Vec<String>>()
             .join(" + ");
-        dbg!(&chord_str);
+        // The active layer maps the chord as if its stem was held, and `CapsLocked` mappings
+        // apply while Caps Lock is on, unless they leave the chord unbound.
+        let mut chords: Vec<String> = self
+            .active_layer
+            .iter()
+            .map(|layer| format!("{} + {}", layer.stem, chord_str))
+            .chain(std::iter::once(chord_str.clone()))
+            .collect();
+        if self.caps_lock {
+            chords = chords
+                .into_iter()
+                .flat_map(|chord| [format!("{} + {}", CAPS_LOCKED_STEM, chord), chord])
+                .collect();
+        }
+        // The chord as typed is looked up last, below.
+        chords.pop();
+        for chord in &chords {
+            if let Some((mapping_context, action)) = self.find_chord_mapping(context, chord) {
+                if action != SemanticAction::Passthrough {
+                    log!(Debug, Chords, "{}: {} -> {}", mapping_context.name, chord, action);
+                    return Some(action);
+                }
+            }
+        }
+        match self.find_chord_mapping(context, &chord_str) {
+            Some((mapping_context, action)) => {
+                if action == SemanticAction::Passthrough && mapping_context.passthrough_all {
+                    log!(Debug, Chords, "Context {} passes through all chords", mapping_context.name);
+                } else {
+                    log!(Debug, Chords, "{}: {} -> {}", mapping_context.name, chord_str, action);
+                }
+                Some(action)
+            }
+            None => {
+                // Only mapped chords are logged as they are, anything else may be typed text.
+                log!(Debug, Chords, "No action found for chord: {}", redact_keys(chord));
+                None
+            }
+        }
+    }
+
+    // The key events of an action in a context, after the context's modifier translation.
+    fn synthetic_events_for(&self, action: &SemanticAction, context: &Context) -> Vec<KeyEvent> {
+        let platform = self.context_semantics_platform(context);
+        let synthetic_events = self.resolve_semantic_action(action, context, platform);
+        match self.context_modifier_translation(context) {
+            Some(table) => synthetic_events
+                .iter()
+                .map(|event| translate_modifiers(event, table))
+                .collect(),
+            None => synthetic_events,
+        }
+    }
+
+    // In a context with `chord_counts` set, a digit typed while a stem is held, that the stem
+    // doesn't map, starts a count for the next chord. Digits after it continue the count even
+    // where the stem maps them, so `Meta + D1, D0` counts ten instead of running `Meta + D0`.
+    // Zero only continues a count.
+    fn add_to_pending_count(&mut self, event: &KeyEvent, app_name: &str, window_name: &str) -> bool {
+        if !matches!(self.state, ChordState::StemHeld | ChordState::MappingActive) || event.shift {
+            return false;
+        }
+        let Some(digit) = crate::modal::digit(&event.key) else {
+            return false;
+        };
+        if self.pending_count.is_none() {
+            let counts = self
+                .get_context(app_name, window_name)
+                .is_some_and(|context| self.context_chord_counts(context));
+            if digit == 0 || !counts {
+                return false;
+            }
+        }
+        let count = (self.pending_count.unwrap_or(0) * 10 + digit).min(MAX_COUNT);
+        self.pending_count = Some(count);
+        log!(Debug, Chords, "Count: {}", count);
+        // The digit isn't part of the chord, so a later up event can't end it.
+        self.pressed_keys.retain(|k| k.key != event.key);
+        self.transition(ChordState::MappingActive);
+        true
+    }
+
+    // Modal commands are typed without modifiers, other than shift for keys such as `$`.
+    fn is_modal_key(&self, event: &KeyEvent) -> bool {
+        let is_shift = |key: &str| key == "shift" || key == "rightshift";
+        self.modal.is_some()
+            && !(event.ctrl || event.alt || event.meta || event.func)
+            && (is_shift(&event.key) || normalize_modifier_name(&event.key).is_none())
+            && self
+                .pressed_keys
+                .iter()
+                .all(|key| key.key == event.key || is_shift(&key.key))
+    }
+
+    fn process_modal_key(
+        &mut self,
+        event: &KeyEvent,
+        app_name: &str,
+        window_name: &str,
+    ) -> (bool, Vec<KeyEvent>) {
+        self.transition(ChordState::MappingActive);
+        if event.key == "shift" || event.key == "rightshift" {
+            return (true, Vec::new());
+        }
+        let Some(modal) = &mut self.modal else {
+            return (true, Vec::new());
+        };
+        let actions = match modal.key(&event.key, event.shift) {
+            ModalStep::Pending => None,
+            ModalStep::Run(actions) => Some(actions),
+            ModalStep::Leave(actions) => {
+                self.set_modal(false);
+                actions
+            }
+            ModalStep::Cancelled => {
+                log!(Debug, Chords, "No modal command for: {}", redact_key(&event.key));
+                None
+            }
+        };
+        let (Some(actions), Some(context)) = (actions, self.get_context(app_name, window_name)) else {
+            return (true, Vec::new());
+        };
+        log!(Debug, Chords, "Modal command: {}", actions);
+        let action = crate::contexts::parse_semantic_action(&actions, &self.keycodes, self.platform);
+        (true, self.synthetic_events_for(&action, context))
+    }
+
+    // The host reports whether Caps Lock is on, e.g. from the flags of each event, since the
+    // engine only sees the presses it is sent.
+    pub fn set_caps_lock(&mut self, on: bool) {
+        self.caps_lock = on;
+    }
+
+    pub fn is_caps_lock_on(&self) -> bool {
+        self.caps_lock
+    }
+
+    // When Caps Lock was used as a stem, the lock state it had before it went down, once. The
+    // host should set the lock to it, since the key press itself toggled the lock.
+    pub fn take_caps_lock_restore(&mut self) -> Option<bool> {
+        self.caps_lock_restore.take()
+    }
+
+    // Escape alone turns a locked layer off, unless the layer maps it.
+    fn escape_unlocks_layer(&self, event: &KeyEvent, app_name: &str, window_name: &str) -> bool {
+        let Some(layer) = &self.active_layer else {
+            return false;
+        };
+        if layer.mode != LayerMode::Locked || event.key != "escape" || self.pressed_keys.len() != 1 {
+            return false;
+        }
+        self.get_context(app_name, window_name).is_none_or(|context| {
+            self.find_chord_mapping(context, &format!("{} + escape", layer.stem))
+                .is_none()
+        })
+    }
+
+    // Looks up a chord such as `meta + j` in the context and then in its parents. Returns the
+    // context that maps it along with the action.
+    pub(crate) fn find_chord_mapping<'a>(
+        &'a self,
+        context: &'a Context,
+        chord_str: &str,
+    ) -> Option<(&'a Context, SemanticAction)> {
         // Lookup the chord in the context's key mappings. If it's not found, try the parent context, then the parent parent, etc.
+        // A context with `passthrough_all` set ends the lookup, as does an explicit `Passthrough` mapping.
         let mut current_context = Some(context);
         while let Some(context) = current_context {
-            if let Some(action) = context.key_mappings.get(&chord_str) {
-                println!("Found action: {}", action);
-                return Some(action.clone());
+            if context.passthrough_all {
+                return Some((context, SemanticAction::Passthrough));
+            }
+            if let Some(action) = context.key_mappings.get(chord_str) {
+                return Some((context, action.clone()));
             }
             current_context = context
                 .parent
                 .as_ref()
                 .and_then(|parent| self.contexts.get(parent));
         }
+        None
+    }
 
-        println!("No action found for chord: {}", chord_str);
+    // The semantics platform of a context, inherited from its parents unless it sets one itself.
+    pub(crate) fn context_semantics_platform(&self, context: &Context) -> Platform {
+        let mut current_context = Some(context);
+        while let Some(context) = current_context {
+            if let Some(platform) = context.semantics_platform {
+                return platform;
+            }
+            current_context = context
+                .parent
+                .as_ref()
+                .and_then(|parent| self.contexts.get(parent));
+        }
+        self.platform
+    }
+
+    // Whether a context takes digits under a stem as a count, inherited from its parents unless
+    // it sets it itself. Off by default, as apps use chords such as `Meta + D1` themselves.
+    pub(crate) fn context_chord_counts(&self, context: &Context) -> bool {
+        let mut current_context = Some(context);
+        while let Some(context) = current_context {
+            if let Some(chord_counts) = context.chord_counts {
+                return chord_counts;
+            }
+            current_context = context
+                .parent
+                .as_ref()
+                .and_then(|parent| self.contexts.get(parent));
+        }
+        false
+    }
+
+    // The modifier translation of a context, inherited from its parents unless it sets one itself.
+    pub(crate) fn context_modifier_translation<'a>(&'a self, context: &'a Context) -> Option<&'a HashMap<String, String>> {
+        let mut current_context = Some(context);
+        while let Some(context) = current_context {
+            if !context.modifier_translation.is_empty() {
+                return Some(&context.modifier_translation);
+            }
+            current_context = context
+                .parent
+                .as_ref()
+                .and_then(|parent| self.contexts.get(parent));
+        }
         None
     }
 
-    fn resolve_semantic_action(&self, action: &SemanticAction, context: &Context) -> Vec<KeyEvent> {
-        dbg!(&action);
+    pub(crate) fn semantic_actions_for<'a>(
+        &self,
+        context: &'a Context,
+        platform: Platform,
+    ) -> Option<&'a HashMap<String, SemanticAction>> {
+        if platform == self.platform {
+            Some(&context.semantic_actions)
+        } else {
+            context.foreign_semantic_actions.get(&platform)
+        }
+    }
+
+    pub(crate) fn resolve_semantic_action(
+        &self,
+        action: &SemanticAction,
+        context: &Context,
+        platform: Platform,
+    ) -> Vec<KeyEvent> {
         match action {
             SemanticAction::Sequence(actions) => actions
                 .iter()
-                .flat_map(|a| self.resolve_semantic_action(a, context))
+                .flat_map(|a| self.resolve_semantic_action(a, context, platform))
                 .collect(),
             SemanticAction::Action(action_name) => {
-                if let Some(action) = context.semantic_actions.get(action_name) {
-                    self.resolve_semantic_action(action, context)
-                } else if let Some(parent) = &context.parent {
-                    if let Some(parent_context) = self.contexts.get(parent) {
-                        self.resolve_semantic_action(action, parent_context)
-                    } else {
-                        Vec::new()
+                match self.find_semantic_definition(action_name, context, platform) {
+                    Some((action, defining_context)) => {
+                        self.resolve_semantic_action(action, defining_context, platform)
                     }
-                } else {
-                    Vec::new()
+                    None => Vec::new(),
                 }
             }
             SemanticAction::KeyEvent(keys) => {
                 vec![keys.clone()]
             }
-            SemanticAction::LiteralString(s) => {
+            SemanticAction::LiteralString(_s) => {
                 //vec![s.clone()]
                 //FIXME: Iterate over the string and return a sequence of key events.
                 Vec::new()
             }
+            SemanticAction::Passthrough | SemanticAction::Engine(_) => Vec::new(),
+        }
+    }
+
+    // Mirrors `resolve_semantic_action`, but collects the engine commands instead of the keys.
+    pub(crate) fn resolve_engine_commands(
+        &self,
+        action: &SemanticAction,
+        context: &Context,
+        platform: Platform,
+    ) -> Vec<EngineCommand> {
+        match action {
+            SemanticAction::Sequence(actions) => actions
+                .iter()
+                .flat_map(|a| self.resolve_engine_commands(a, context, platform))
+                .collect(),
+            SemanticAction::Action(action_name) => {
+                match self.find_semantic_definition(action_name, context, platform) {
+                    Some((action, defining_context)) => {
+                        self.resolve_engine_commands(action, defining_context, platform)
+                    }
+                    None => Vec::new(),
+                }
+            }
+            SemanticAction::Engine(command) => vec![command.clone()],
+            _ => Vec::new(),
+        }
+    }
+
+    // The definition of a named action, from the context or the closest parent that defines it,
+    // along with that context.
+    fn find_semantic_definition<'a>(
+        &'a self,
+        action_name: &str,
+        context: &'a Context,
+        platform: Platform,
+    ) -> Option<(&'a SemanticAction, &'a Context)> {
+        let mut current_context = Some(context);
+        while let Some(context) = current_context {
+            if let Some(action) = self
+                .semantic_actions_for(context, platform)
+                .and_then(|actions| actions.get(action_name))
+            {
+                return Some((action, context));
+            }
+            current_context = context
+                .parent
+                .as_ref()
+                .and_then(|parent| self.contexts.get(parent));
         }
+        None
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn macos_handle_key_event(
         &mut self,
         key_code: i64,
@@ -398,8 +1766,22 @@ impl PinkyTwirlEngine {
             func: false,
             modifier_down_only: false,
         };
+        self.handle_host_key_event(event, app_name, window_name)
+    }
+
+    // Handles a key event from the host's event tap. The synthetic events are normalised into
+    // separate down and up events with key codes, ready for `get_synthetic_events`.
+    pub fn handle_host_key_event(&mut self, event: KeyEvent, app_name: &str, window_name: &str) -> bool {
+        // Leave the modifier releases that ended the chord for the host to post.
+        if self.secure_input || self.failure.is_some() {
+            return false;
+        }
+
+        let down = event.state == KeyState::Down;
         let (suppress, synthetic_keys) = self.handle_key_event(event, app_name, window_name);
-        
+        // Releases queued by the stuck key watchdog go out first.
+        let mut released_keys = std::mem::take(&mut self.synthetic_keys);
+
         // Convert DownUp events to a Down event followed by an Up event.
         self.synthetic_keys = synthetic_keys
             .iter()
@@ -469,8 +1851,11 @@ impl PinkyTwirlEngine {
                 .unwrap_or(0);
         }
 
-        // Add modifier resets.
-        if suppress && down {
+        released_keys.append(&mut self.synthetic_keys);
+        self.synthetic_keys = released_keys;
+
+        // Add modifier resets. Disabling the engine has queued them already.
+        if suppress && down && self.enabled {
             self.synthetic_keys_on_next_up_event.push(KeyEvent {
                 key: "shift".to_string(),
                 code: self.keycodes.name_to_keycode.get("shift").cloned().unwrap_or(0),
@@ -531,7 +1916,7 @@ impl PinkyTwirlEngine {
         }
 
         if !down {
-            self.synthetic_keys.extend(self.synthetic_keys_on_next_up_event.drain(..));
+            self.synthetic_keys.append(&mut self.synthetic_keys_on_next_up_event);
         }
 
         // Return whether the event should be suppressed.
@@ -539,8 +1924,19 @@ impl PinkyTwirlEngine {
     }
 
     pub fn get_synthetic_events(&mut self) -> Vec<KeyEvent> {
-        let synthetic_keys = self.synthetic_keys.clone();
-        self.synthetic_keys.clear();
+        let synthetic_keys = std::mem::take(&mut self.synthetic_keys);
+        // Keep track of the keys the host leaves down, so they can be released on a reset.
+        for key in &synthetic_keys {
+            match key.state {
+                KeyState::Down => {
+                    if !self.synthetic_down_keys.iter().any(|down| down.key == key.key) {
+                        self.synthetic_down_keys.push(key.clone());
+                    }
+                }
+                KeyState::Up => self.synthetic_down_keys.retain(|down| down.key != key.key),
+                KeyState::DownUp => (),
+            }
+        }
         synthetic_keys
     }
 }
@@ -0,0 +1,239 @@
+use std::any::Any;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::contexts::KeyEvent;
+use crate::engine::PinkyTwirlEngine;
+use crate::logging::log;
+
+// A panic that unwinds into the host aborts it, possibly with keys still held down. Every engine
+// entry point of the bridge goes through one of these wrappers instead, which catch the panic,
+// disable the engine and report the failure through `PinkyTwirlEngine::failure`. The accessors
+// of `KeyEvent` only read fields, and are bridged directly.
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
+}
+
+// Creates an engine, or one that passes everything through if creating it panicked.
+fn create_engine(entry_point: &str, create: impl FnOnce() -> PinkyTwirlEngine) -> PinkyTwirlEngine {
+    catch_unwind(AssertUnwindSafe(create)).unwrap_or_else(|payload| {
+        let message = format!("Panic in {}: {}", entry_point, panic_message(payload));
+        log!(Error, State, "{}, passing every event through", message);
+        PinkyTwirlEngine::failed(message)
+    })
+}
+
+impl PinkyTwirlEngine {
+    // Runs part of the engine the way the host's entry points do. If it panics, the engine is
+    // disabled and `fallback` is returned, which should leave the host's event alone.
+    pub fn catch_panic<T>(
+        &mut self,
+        entry_point: &str,
+        fallback: T,
+        run: impl FnOnce(&mut Self) -> T,
+    ) -> T {
+        match catch_unwind(AssertUnwindSafe(|| run(self))) {
+            Ok(result) => result,
+            Err(payload) => {
+                self.fail(format!(
+                    "Panic in {}: {}",
+                    entry_point,
+                    panic_message(payload)
+                ));
+                fallback
+            }
+        }
+    }
+
+    pub(crate) fn ffi_new(config_dir: String) -> Self {
+        create_engine("new", || PinkyTwirlEngine::new(config_dir))
+    }
+
+    pub(crate) fn ffi_discover() -> Self {
+        create_engine("discover", PinkyTwirlEngine::discover)
+    }
+
+    pub(crate) fn ffi_config_location_description(&mut self) -> String {
+        self.catch_panic("config_location_description", String::new(), |engine| {
+            engine.config_location_description()
+        })
+    }
+
+    pub(crate) fn ffi_offers_default_config_install(&mut self) -> bool {
+        self.catch_panic("offers_default_config_install", false, |engine| {
+            engine.offers_default_config_install()
+        })
+    }
+
+    pub(crate) fn ffi_install_default_config(&mut self) -> bool {
+        self.catch_panic("install_default_config", false, |engine| {
+            engine.macos_install_default_config()
+        })
+    }
+
+    pub(crate) fn ffi_watch_config(&mut self) {
+        self.catch_panic("watch_config", (), |engine| engine.watch_config())
+    }
+
+    pub(crate) fn ffi_start_recording(&mut self, path: String) -> bool {
+        self.catch_panic("start_recording", false, |engine| {
+            engine.macos_start_recording(path)
+        })
+    }
+
+    pub(crate) fn ffi_stop_recording(&mut self) {
+        self.catch_panic("stop_recording", (), |engine| engine.stop_recording())
+    }
+
+    pub(crate) fn ffi_set_secure_input(&mut self, enabled: bool) {
+        self.catch_panic("set_secure_input", (), |engine| {
+            engine.set_secure_input(enabled)
+        })
+    }
+
+    pub(crate) fn ffi_set_enabled(&mut self, enabled: bool) {
+        self.catch_panic("set_enabled", (), |engine| engine.set_enabled(enabled))
+    }
+
+    pub(crate) fn ffi_is_enabled(&mut self) -> bool {
+        self.catch_panic("is_enabled", false, |engine| engine.is_enabled())
+    }
+
+    pub(crate) fn ffi_switch_profile(&mut self, profile: String) -> bool {
+        self.catch_panic("switch_profile", false, |engine| {
+            match engine.switch_profile(&profile) {
+                Ok(()) => true,
+                Err(e) => {
+                    log!(Error, Config, "Error switching profiles: {}", e);
+                    false
+                }
+            }
+        })
+    }
+
+    // The active profile, or an empty string for the base config.
+    pub(crate) fn ffi_active_profile(&mut self) -> String {
+        self.catch_panic("active_profile", String::new(), |engine| {
+            engine.active_profile().unwrap_or_default().to_string()
+        })
+    }
+
+    // The profiles the config defines, one per line.
+    pub(crate) fn ffi_profile_names(&mut self) -> String {
+        self.catch_panic("profile_names", String::new(), |engine| {
+            engine.profiles().join("\n")
+        })
+    }
+
+    pub(crate) fn ffi_toggle_layer(&mut self, stem: String) -> bool {
+        self.catch_panic("toggle_layer", false, |engine| {
+            match engine.toggle_layer(&stem) {
+                Ok(()) => true,
+                Err(e) => {
+                    log!(Error, State, "Error toggling the layer: {}", e);
+                    false
+                }
+            }
+        })
+    }
+
+    pub(crate) fn ffi_clear_layer(&mut self) {
+        self.catch_panic("clear_layer", (), |engine| engine.clear_layer())
+    }
+
+    pub(crate) fn ffi_set_modal(&mut self, modal: bool) {
+        self.catch_panic("set_modal", (), |engine| engine.set_modal(modal))
+    }
+
+    pub(crate) fn ffi_is_modal(&mut self) -> bool {
+        self.catch_panic("is_modal", false, |engine| engine.is_modal())
+    }
+
+    pub(crate) fn ffi_set_caps_lock(&mut self, on: bool) {
+        self.catch_panic("set_caps_lock", (), |engine| engine.set_caps_lock(on))
+    }
+
+    pub(crate) fn ffi_take_caps_lock_restore(&mut self) -> Option<bool> {
+        self.catch_panic("take_caps_lock_restore", None, |engine| {
+            engine.take_caps_lock_restore()
+        })
+    }
+
+    // The active layer, e.g. `d4 (locked)`, or an empty string if there is none.
+    pub(crate) fn ffi_active_layer(&mut self) -> String {
+        self.catch_panic("active_layer", String::new(), |engine| {
+            engine
+                .active_layer()
+                .map(|layer| layer.to_string())
+                .unwrap_or_default()
+        })
+    }
+
+    pub(crate) fn ffi_status_description(&mut self) -> String {
+        self.catch_panic("status_description", String::new(), |engine| {
+            engine.status_description()
+        })
+    }
+
+    pub(crate) fn ffi_reset_all(&mut self) {
+        self.catch_panic("reset_all", (), |engine| engine.reset_all())
+    }
+
+    pub(crate) fn ffi_sync_physical_state(&mut self, held_key_codes: Vec<i64>) {
+        self.catch_panic("sync_physical_state", (), |engine| {
+            engine.macos_sync_physical_state(held_key_codes)
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn ffi_handle_key_event(
+        &mut self,
+        key_code: i64,
+        down: bool,
+        shift: bool,
+        ctrl: bool,
+        option: bool,
+        meta: bool,
+        app_name: &str,
+        window_name: &str,
+    ) -> bool {
+        self.catch_panic("handle_key_event", false, |engine| {
+            engine.macos_handle_key_event(
+                key_code,
+                down,
+                shift,
+                ctrl,
+                option,
+                meta,
+                app_name,
+                window_name,
+            )
+        })
+    }
+
+    pub(crate) fn ffi_get_synthetic_events(&mut self) -> Vec<KeyEvent> {
+        self.catch_panic("get_synthetic_events", Vec::new(), |engine| {
+            engine.get_synthetic_events()
+        })
+    }
+
+    // The help for the host to show, or an empty string if none was asked for.
+    pub(crate) fn ffi_take_help_text(&mut self) -> String {
+        self.catch_panic("take_help", String::new(), |engine| {
+            engine.take_help().unwrap_or_default()
+        })
+    }
+
+    // The failure for the host to show, or an empty string while the engine works.
+    pub(crate) fn ffi_failure_description(&mut self) -> String {
+        self.catch_panic("failure_description", String::new(), |engine| {
+            engine.failure().unwrap_or_default().to_string()
+        })
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::contexts::{Context, KeyEvent, SemanticAction};
+use crate::keycode_macos::{capitalize, KeyCodeLookup};
+use crate::platform::Platform;
+use crate::sources::{parse_include, Origin};
+
+// The two indentation based formats. Only mappings have stems, i.e. indented `Meta:` lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFileKind {
+    Semantics,
+    Mappings,
+}
+
+impl ConfigFileKind {
+    pub fn for_file_name(file_name: &str) -> Option<ConfigFileKind> {
+        match file_name {
+            "mappings.txt" => Some(ConfigFileKind::Mappings),
+            name if name.starts_with("semantics") && name.ends_with(".txt") => {
+                Some(ConfigFileKind::Semantics)
+            }
+            _ => None,
+        }
+    }
+}
+
+const MODIFIER_KEYS: [&str; 11] = [
+    "shift", "ctrl", "control", "alt", "option", "meta", "command", "metadown", "fn", "primary",
+    "word",
+];
+
+// The canonical spelling of a key or modifier name, or `None` if it isn't one.
+fn display_key_name(name: &str, keycodes: &KeyCodeLookup) -> Option<String> {
+    let lower_name = name.trim().to_lowercase();
+    match lower_name.as_str() {
+        "primary" => Some("Primary".to_string()),
+        "word" => Some("Word".to_string()),
+        "metadown" => Some("MetaDown".to_string()),
+        "passthrough" => Some("Passthrough".to_string()),
+        "unbound" => Some("Unbound".to_string()),
+        _ => keycodes.display_names.get(&lower_name).cloned(),
+    }
+}
+
+// `meta + semicolon` becomes `Meta + Semicolon`. Anything that isn't a key is kept as written.
+fn normalize_chord(chord: &str, keycodes: &KeyCodeLookup) -> String {
+    chord
+        .split('+')
+        .map(|part| display_key_name(part, keycodes).unwrap_or_else(|| part.trim().to_string()))
+        .collect::<Vec<String>>()
+        .join(" + ")
+}
+
+// Like `normalize_chord`, for the lowercase chords the engine stores.
+pub(crate) fn display_chord(chord: &str, keycodes: &KeyCodeLookup) -> String {
+    chord
+        .split('+')
+        .map(|part| {
+            display_key_name(part, keycodes).unwrap_or_else(|| capitalize(part.trim().to_string()))
+        })
+        .collect::<Vec<String>>()
+        .join(" + ")
+}
+
+fn normalize_definition(definition: &str, keycodes: &KeyCodeLookup) -> String {
+    definition
+        .split('|')
+        .map(|part| {
+            let part = part.trim();
+            if part.starts_with('"') {
+                return part.to_string();
+            }
+            match part.split_once('*') {
+                Some((count, key)) if !part.contains('+') => {
+                    format!("{} * {}", count.trim(), normalize_chord(key, keycodes))
+                }
+                _ => normalize_chord(part, keycodes),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" | ")
+}
+
+enum FormattedLine {
+    Verbatim(String),
+    Entry {
+        indent: usize,
+        key: String,
+        value: String,
+    },
+}
+
+// Formats a semantics or mappings file: indentation is normalised to four spaces per level,
+// key names get their canonical casing and the `=` of consecutive entries line up. Comments,
+// includes and single blank lines are kept where they are.
+pub(crate) fn format_config_with(
+    text: &str,
+    kind: ConfigFileKind,
+    keycodes: &KeyCodeLookup,
+) -> String {
+    let mut lines = Vec::new();
+    let mut in_stem = false;
+
+    for line in text.lines() {
+        let trimmed_line = line.trim();
+        let indented = line.starts_with(' ') || line.starts_with('\t');
+
+        if trimmed_line.is_empty() {
+            if matches!(lines.last(), Some(FormattedLine::Verbatim(last)) if last.is_empty())
+                || lines.is_empty()
+            {
+                continue;
+            }
+            lines.push(FormattedLine::Verbatim(String::new()));
+        } else if trimmed_line.starts_with("//") || parse_include(line).is_some() {
+            lines.push(FormattedLine::Verbatim(line.trim_end().to_string()));
+        } else if !indented {
+            // A context name.
+            lines.push(FormattedLine::Verbatim(format!(
+                "{}:",
+                trimmed_line.trim_end_matches(':').trim_end()
+            )));
+            in_stem = false;
+        } else if kind == ConfigFileKind::Mappings
+            && trimmed_line.ends_with(':')
+            && !trimmed_line.contains('=')
+        {
+            let stem = normalize_chord(trimmed_line.trim_end_matches(':'), keycodes);
+            lines.push(FormattedLine::Verbatim(format!("    {}:", stem)));
+            in_stem = true;
+        } else if let Some((key, value)) = trimmed_line.split_once('=') {
+            let key = match kind {
+                ConfigFileKind::Mappings => normalize_chord(key, keycodes),
+                ConfigFileKind::Semantics => key.trim().to_string(),
+            };
+            lines.push(FormattedLine::Entry {
+                indent: if in_stem { 8 } else { 4 },
+                key,
+                value: normalize_definition(value, keycodes),
+            });
+        } else {
+            lines.push(FormattedLine::Verbatim(line.trim_end().to_string()));
+        }
+    }
+
+    while matches!(lines.last(), Some(FormattedLine::Verbatim(last)) if last.is_empty()) {
+        lines.pop();
+    }
+
+    // Align the `=` of each run of consecutive entries.
+    let mut widths = vec![0; lines.len()];
+    let mut start = 0;
+    while start < lines.len() {
+        let mut end = start;
+        let mut width = 0;
+        while let Some(FormattedLine::Entry { key, .. }) = lines.get(end) {
+            width = width.max(key.chars().count());
+            end += 1;
+        }
+        for line_width in &mut widths[start..end] {
+            *line_width = width;
+        }
+        start = end.max(start + 1);
+    }
+
+    let mut formatted = String::new();
+    for (line, width) in lines.iter().zip(widths) {
+        match line {
+            FormattedLine::Verbatim(text) => formatted.push_str(text),
+            FormattedLine::Entry { indent, key, value } => {
+                formatted.push_str(&format!("{:indent$}{:width$} = {}", "", key, value));
+            }
+        }
+        formatted.push('\n');
+    }
+    formatted
+}
+
+// Formats the text of a semantics or mappings file, see `format_config_with`.
+pub fn format_config(text: &str, kind: ConfigFileKind) -> String {
+    format_config_with(text, kind, &crate::keycode_macos::create_keycode_map())
+}
+
+// The original spelling of every semantic action, keyed by its lowercase name. Chords store
+// their actions in lowercase, so this is needed to write them back out.
+pub(crate) fn action_names<'a>(
+    contexts: impl IntoIterator<Item = &'a Context>,
+) -> HashMap<String, String> {
+    let mut names = HashMap::new();
+    for context in contexts {
+        let actions = context.semantic_actions.keys().chain(
+            context
+                .foreign_semantic_actions
+                .values()
+                .flat_map(|actions| actions.keys()),
+        );
+        for action in actions {
+            if action.chars().any(char::is_uppercase) {
+                names.insert(action.to_lowercase(), action.clone());
+            }
+        }
+    }
+    names
+}
+
+pub(crate) fn key_event_to_string(event: &KeyEvent, keycodes: &KeyCodeLookup) -> String {
+    // A modifier pressed on its own, e.g. `Shift` or `MetaDown`.
+    if MODIFIER_KEYS.contains(&event.key.as_str()) {
+        return display_key_name(&event.key, keycodes)
+            .unwrap_or_else(|| capitalize(event.key.clone()));
+    }
+
+    let mut parts = Vec::new();
+    for (held, modifier) in [
+        (event.ctrl, "Ctrl"),
+        (event.shift, "Shift"),
+        (event.alt, "Alt"),
+        (event.meta && !event.modifier_down_only, "Meta"),
+        (event.modifier_down_only, "MetaDown"),
+        (event.func, "Fn"),
+    ] {
+        if held {
+            parts.push(modifier.to_string());
+        }
+    }
+    if !event.key.is_empty() {
+        parts.push(
+            display_key_name(&event.key, keycodes).unwrap_or_else(|| capitalize(event.key.clone())),
+        );
+    }
+    parts.join(" + ")
+}
+
+// Writes a semantic action in the syntax of the config files. Runs of the same plain key are
+// written as `12 * Up`.
+pub(crate) fn semantic_action_to_string(
+    action: &SemanticAction,
+    keycodes: &KeyCodeLookup,
+    action_names: &HashMap<String, String>,
+) -> String {
+    match action {
+        SemanticAction::Sequence(actions) => {
+            let mut parts: Vec<String> = Vec::new();
+            let mut index = 0;
+            while index < actions.len() {
+                let mut count = 1;
+                if let SemanticAction::KeyEvent(event) = &actions[index] {
+                    let plain =
+                        !(event.ctrl || event.shift || event.alt || event.meta || event.func);
+                    while plain && actions.get(index + count) == Some(&actions[index]) {
+                        count += 1;
+                    }
+                }
+                let part = semantic_action_to_string(&actions[index], keycodes, action_names);
+                if count > 1 {
+                    parts.push(format!("{} * {}", count, part));
+                } else {
+                    parts.push(part);
+                }
+                index += count;
+            }
+            parts.join(" | ")
+        }
+        SemanticAction::Action(name) => action_names
+            .get(&name.to_lowercase())
+            .cloned()
+            .unwrap_or_else(|| capitalize(name.clone())),
+        SemanticAction::KeyEvent(event) => key_event_to_string(event, keycodes),
+        SemanticAction::LiteralString(s) => format!("\"{}\"", s),
+        SemanticAction::Passthrough => "Passthrough".to_string(),
+        SemanticAction::Engine(command) => command.to_string(),
+    }
+}
+
+// A definition with its origin, name and value.
+type Entry<'a, T> = (Option<&'a Origin>, String, T);
+
+// Orders definitions the way they were written, falling back to their names.
+fn sort_by_origin<T>(entries: &mut [(Option<&Origin>, String, T)]) {
+    entries.sort_by(|(a_origin, a_name, _), (b_origin, b_name, _)| {
+        let key = |origin: &Option<&Origin>| {
+            origin.map(|origin| (origin.layer, origin.file.clone(), origin.line_number))
+        };
+        // Definitions without an origin go last.
+        (a_origin.is_none(), key(a_origin), a_name).cmp(&(
+            b_origin.is_none(),
+            key(b_origin),
+            b_name,
+        ))
+    });
+}
+
+fn sorted_contexts<'a>(contexts: &[&'a Context]) -> Vec<&'a Context> {
+    let mut entries: Vec<(Option<&Origin>, String, &Context)> = contexts
+        .iter()
+        .map(|context| (context.origin.as_ref(), context.name.clone(), *context))
+        .collect();
+    sort_by_origin(&mut entries);
+    entries.into_iter().map(|(_, _, context)| context).collect()
+}
+
+// Writes the chords of the given contexts in the `mappings.txt` syntax.
+pub(crate) fn serialize_mappings(
+    contexts: &[&Context],
+    keycodes: &KeyCodeLookup,
+    action_names: &HashMap<String, String>,
+) -> String {
+    let stem_action = SemanticAction::Action("MappingStem".to_string());
+    let mut text = String::new();
+
+    for context in sorted_contexts(contexts) {
+        let stems: Vec<&String> = context
+            .key_mappings
+            .iter()
+            .filter(|(_, action)| **action == stem_action)
+            .map(|(chord, _)| chord)
+            .collect();
+
+        // Each chord goes under the longest stem it starts with.
+        let mut blocks: HashMap<Option<&String>, Vec<Entry<String>>> = HashMap::new();
+        for (chord, action) in &context.key_mappings {
+            if *action == stem_action {
+                continue;
+            }
+            let stem = stems
+                .iter()
+                .filter(|stem| chord.starts_with(&format!("{} + ", stem)))
+                .max_by_key(|stem| stem.len())
+                .copied();
+            let key = match stem {
+                Some(stem) => display_chord(&chord[stem.len() + 3..], keycodes),
+                None => display_chord(chord, keycodes),
+            };
+            let value = semantic_action_to_string(action, keycodes, action_names);
+            blocks
+                .entry(stem)
+                .or_default()
+                .push((context.mapping_origins.get(chord), key, value));
+        }
+
+        text.push_str(&format!("{}:\n", context.name));
+        if let Some(entries) = blocks.get_mut(&None) {
+            sort_by_origin(entries);
+            for (_, key, value) in entries.iter() {
+                text.push_str(&format!("    {} = {}\n", key, value));
+            }
+        }
+
+        let mut stem_entries: Vec<(Option<&Origin>, String, &String)> = stems
+            .iter()
+            .map(|stem| (context.mapping_origins.get(*stem), (*stem).clone(), *stem))
+            .collect();
+        sort_by_origin(&mut stem_entries);
+        for (_, _, stem) in stem_entries {
+            text.push_str(&format!("    {}:\n", display_chord(stem, keycodes)));
+            if let Some(entries) = blocks.get_mut(&Some(stem)) {
+                sort_by_origin(entries);
+                for (_, key, value) in entries.iter() {
+                    text.push_str(&format!("        {} = {}\n", key, value));
+                }
+            }
+        }
+        text.push('\n');
+    }
+
+    format_config_with(&text, ConfigFileKind::Mappings, keycodes)
+}
+
+// Writes the semantic actions the given contexts have for a platform in the `semantics_*.txt`
+// syntax. `host_platform` is the platform the contexts were loaded for.
+pub(crate) fn serialize_semantics(
+    contexts: &[&Context],
+    platform: Platform,
+    host_platform: Platform,
+    keycodes: &KeyCodeLookup,
+    action_names: &HashMap<String, String>,
+) -> String {
+    let mut text = String::new();
+
+    for context in sorted_contexts(contexts) {
+        let actions = if platform == host_platform {
+            Some(&context.semantic_actions)
+        } else {
+            context.foreign_semantic_actions.get(&platform)
+        };
+        let Some(actions) = actions.filter(|actions| !actions.is_empty()) else {
+            continue;
+        };
+        let origins = context.semantic_origins.get(&platform);
+
+        // Every action is stored under its own and its lowercase name, write it once.
+        let cased_names: HashSet<String> = actions
+            .keys()
+            .filter(|name| name.chars().any(char::is_uppercase))
+            .map(|name| name.to_lowercase())
+            .collect();
+        let mut entries: Vec<(Option<&Origin>, String, String)> = actions
+            .iter()
+            .filter(|(name, _)| !cased_names.contains(*name))
+            .map(|(name, action)| {
+                (
+                    origins.and_then(|origins| origins.get(name)),
+                    name.clone(),
+                    semantic_action_to_string(action, keycodes, action_names),
+                )
+            })
+            .collect();
+        sort_by_origin(&mut entries);
+
+        text.push_str(&format!("{}:\n", context.name));
+        for (_, name, value) in entries {
+            text.push_str(&format!("    {} = {}\n", name, value));
+        }
+        text.push('\n');
+    }
+
+    format_config_with(&text, ConfigFileKind::Semantics, keycodes)
+}
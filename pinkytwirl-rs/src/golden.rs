@@ -0,0 +1,227 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::contexts::{KeyEvent, KeyState};
+use crate::engine::PinkyTwirlEngine;
+use crate::formatter::key_event_to_string;
+use crate::keycode_macos::KeyCodeLookup;
+use crate::simulator::{split_words, state_name, HeldKeys};
+
+// Test cases for chord behaviour, one step per line:
+//
+//     // Meta + J moves the cursor left.
+//     focus "Visual Studio Code" "main.rs"
+//     down meta => suppress
+//     down j    => suppress: Left
+//     up j      => pass
+//     pressed meta
+//     up meta   => pass
+//
+// A key event can be followed by `=>`, whether the engine should suppress or pass the event,
+// and after a `:` the events it should output. Output events are written like in the semantics
+// files, with `down` or `up` after keys that are only pressed or released. `pressed` checks the
+// keys the engine holds, `pressed none` that it holds nothing. `secure on` and `secure off`
+// start and end secure input.
+pub const CASE_FILE_EXTENSION: &str = "case";
+
+// A step whose result differs from what the case file expects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaseFailure {
+    pub file: PathBuf,
+    pub line_number: usize,
+    pub step: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for CaseFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}:{}: {}",
+            self.file.display(),
+            self.line_number,
+            self.step
+        )?;
+        writeln!(f, "  - expected: {}", self.expected)?;
+        writeln!(f, "  + actual:   {}", self.actual)
+    }
+}
+
+// The results of running every case file in a directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CaseReport {
+    pub cases: usize,
+    pub steps: usize,
+    pub failures: Vec<CaseFailure>,
+}
+
+impl CaseReport {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl fmt::Display for CaseReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for failure in &self.failures {
+            write!(f, "{}", failure)?;
+        }
+        writeln!(
+            f,
+            "{} case(s), {} step(s), {} failure(s).",
+            self.cases,
+            self.steps,
+            self.failures.len()
+        )
+    }
+}
+
+fn event_to_string(event: &KeyEvent, keycodes: &KeyCodeLookup) -> String {
+    let key = key_event_to_string(event, keycodes);
+    match event.state {
+        KeyState::DownUp => key,
+        _ => format!("{} {}", key, state_name(&event.state)),
+    }
+}
+
+// The result of a key event as a case file writes it, e.g. `suppress: Left`.
+pub(crate) fn describe_result(
+    suppress: bool,
+    output: &[KeyEvent],
+    keycodes: &KeyCodeLookup,
+) -> String {
+    let result = if suppress { "suppress" } else { "pass" };
+    if output.is_empty() {
+        return result.to_string();
+    }
+    let output: Vec<String> = output
+        .iter()
+        .map(|event| event_to_string(event, keycodes))
+        .collect();
+    format!("{}: {}", result, output.join(", "))
+}
+
+// Expected and actual results are compared without regard to case or spacing.
+pub(crate) fn normalize(text: &str) -> String {
+    text.split(',')
+        .map(|part| part.split_whitespace().collect::<Vec<&str>>().join(" "))
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<String>>()
+        .join(", ")
+        .to_lowercase()
+}
+
+// Runs the steps of a case file against the engine and returns the steps that differ. A case
+// file that can't be parsed is an error.
+pub fn run_case(
+    engine: &mut PinkyTwirlEngine,
+    file: &Path,
+    text: &str,
+) -> Result<(usize, Vec<CaseFailure>), Box<dyn Error>> {
+    let mut app_name = String::new();
+    let mut window_name = String::new();
+    let mut held_keys = HeldKeys::default();
+    let mut steps = 0;
+    let mut failures = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let error = |message: String| format!("{}:{}: {}", file.display(), line_number, message);
+        let (step, expected) = match line.split_once("=>") {
+            Some((step, expected)) => (step.trim(), Some(expected.trim())),
+            None => (line.trim(), None),
+        };
+        if step.is_empty() || step.starts_with("//") {
+            continue;
+        }
+        let words = split_words(step).map_err(error)?;
+        let mut failure = |expected: &str, actual: String| {
+            if normalize(expected) != normalize(&actual) {
+                failures.push(CaseFailure {
+                    file: file.to_path_buf(),
+                    line_number,
+                    step: step.to_string(),
+                    expected: expected.to_string(),
+                    actual,
+                });
+            }
+        };
+
+        match (words[0].as_str(), &words[1..]) {
+            ("focus", [app]) | ("focus", [app, _]) => {
+                app_name = app.clone();
+                window_name = words.get(2).cloned().unwrap_or_default();
+            }
+            ("secure", [state]) if state == "on" || state == "off" => {
+                engine.set_secure_input(state == "on")
+            }
+            ("pressed", _) => {
+                steps += 1;
+                let pressed_keys: Vec<String> = engine
+                    .pressed_keys
+                    .iter()
+                    .map(|key| key.key.clone())
+                    .collect();
+                let actual = if pressed_keys.is_empty() {
+                    "none".to_string()
+                } else {
+                    pressed_keys.join(", ")
+                };
+                let expected = step["pressed".len()..].trim();
+                failure(expected, actual);
+            }
+            (command @ ("down" | "up"), [key]) => {
+                let state = if command == "down" {
+                    KeyState::Down
+                } else {
+                    KeyState::Up
+                };
+                let event = held_keys
+                    .event(engine.keycodes(), key, state)
+                    .map_err(error)?;
+                let (suppress, output) = engine.handle_key_event(event, &app_name, &window_name);
+                let Some(expected) = expected else {
+                    continue;
+                };
+                steps += 1;
+                failure(
+                    expected,
+                    describe_result(suppress, &output, engine.keycodes()),
+                );
+            }
+            _ => return Err(error(format!("Unknown step: {}", step)).into()),
+        }
+    }
+
+    Ok((steps, failures))
+}
+
+// Runs every `.case` file in the directory, in order of their names, each against a new engine.
+pub fn run_case_dir(
+    dir: &Path,
+    mut new_engine: impl FnMut() -> PinkyTwirlEngine,
+) -> Result<CaseReport, Box<dyn Error>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| format!("Cannot read case directory {:?}: {}", dir, e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext == CASE_FILE_EXTENSION)
+        })
+        .collect();
+    files.sort();
+
+    let mut report = CaseReport::default();
+    for file in files {
+        let text =
+            fs::read_to_string(&file).map_err(|e| format!("Cannot read {:?}: {}", file, e))?;
+        let (steps, failures) = run_case(&mut new_engine(), &file, &text)?;
+        report.cases += 1;
+        report.steps += steps;
+        report.failures.extend(failures);
+    }
+    Ok(report)
+}
@@ -4,20 +4,26 @@ use std::collections::HashMap;
 pub fn capitalize(s: String) -> String {
     // Capitalize the first letter of a string.
     let mut chars = s.chars();
-    return match chars.next() {
+    match chars.next() {
         None => String::new(),
         Some(f) => f.to_uppercase().collect::<String>() + chars.as_str(),
-    };
+    }
 }
 
+#[derive(Default)]
 pub struct KeyCodeLookup {
     pub keycode_to_name: HashMap<i64, String>,
     pub name_to_keycode: HashMap<String, i64>,
+    // The canonical spelling of each lowercase key name, e.g. `pageup -> PageUp`.
+    pub display_names: HashMap<String, String>,
 }
 
 impl KeyCodeLookup {
     pub fn add_pair(&mut self, keycode: i64, name: &str) {
         self.keycode_to_name.insert(keycode, name.to_string());
+        self.display_names
+            .entry(name.to_lowercase())
+            .or_insert_with(|| capitalize(name.to_string()));
         self.keycode_to_name
             .insert(keycode, name.to_string().to_lowercase());
 
@@ -39,6 +45,7 @@ pub fn create_keycode_map() -> KeyCodeLookup {
     let mut lookup = KeyCodeLookup {
         keycode_to_name: HashMap::new(),
         name_to_keycode: HashMap::new(),
+        display_names: HashMap::new(),
     };
 
     // Special keys
@@ -1,12 +1,61 @@
+// The code generated by swift-bridge casts the engine pointer to its own type.
+#![allow(clippy::unnecessary_cast)]
+
+mod commands;
 mod contexts;
+mod defaults;
+mod discovery;
 mod engine;
+mod ffi;
+mod formatter;
+mod golden;
 mod keycode_macos;
+mod lint;
+mod loader;
+mod logging;
 mod mappings;
+mod modal;
+mod platform;
+mod profiles;
+mod query;
+mod recording;
 mod semantics;
+mod simulator;
+mod sources;
+mod state;
+mod unified;
+mod watcher;
 
+pub use crate::commands::EngineCommand;
 pub use crate::contexts::KeyEvent;
 pub use crate::contexts::KeyState;
-pub use crate::engine::PinkyTwirlEngine;
+pub use crate::discovery::{
+    discover_config_location, platform_config_dir, process_env, write_default_config,
+    ConfigLocation, CONFIG_DIR_ENV,
+};
+pub use crate::engine::{
+    EngineBuilder, PinkyTwirlEngine, CAPS_LOCKED_STEM, CAPS_LOCK_KEY, DEFAULT_KILL_CHORD,
+    DEFAULT_STUCK_KEY_TIMEOUT,
+};
+pub use crate::formatter::{format_config, ConfigFileKind};
+pub use crate::golden::{run_case, run_case_dir, CaseFailure, CaseReport, CASE_FILE_EXTENSION};
+pub use crate::lint::{lint_config, LintIssue, LintKind};
+pub use crate::loader::ReloadReport;
+pub use crate::logging::{
+    configure_logging, log_settings, set_log_sink, LogCategory, LogLevel, LogRecord, LogSettings,
+    LogSink, LOG_ENV,
+};
+pub use crate::modal::{ModalInput, ModalStep, MAX_COUNT};
+pub use crate::platform::Platform;
+pub use crate::profiles::{ACTIVE_PROFILE_FILE, PROFILES_FILE};
+pub use crate::query::{ActionChord, ChordMapping, ChordQuery, SemanticNode};
+pub use crate::recording::replay_recording;
+pub use crate::simulator::{Simulator, SIMULATOR_HELP};
+pub use crate::sources::{ConfigLayer, Origin};
+pub use crate::state::{
+    is_transition, transition_graph, ActiveLayer, ChordState, LayerMode, TRANSITIONS,
+};
+pub use crate::unified::{txt_to_unified, unified_to_txt, UNIFIED_CONFIG_FILE};
 
 #[swift_bridge::bridge]
 mod ff {
@@ -22,12 +71,66 @@ mod ff {
         fn get_fn(self: &KeyEvent) -> bool;
     }
 
+    // Every entry point catches panics, see `ffi.rs`.
     extern "Rust" {
         type PinkyTwirlEngine;
 
-        #[swift_bridge(associated_to = PinkyTwirlEngine)]
+        #[swift_bridge(associated_to = PinkyTwirlEngine, rust_name = "ffi_new")]
         fn new(config_dir: String) -> PinkyTwirlEngine;
 
+        #[swift_bridge(associated_to = PinkyTwirlEngine, rust_name = "ffi_discover")]
+        fn discover() -> PinkyTwirlEngine;
+
+        #[swift_bridge(rust_name = "ffi_config_location_description")]
+        fn config_location_description(&mut self) -> String;
+        #[swift_bridge(rust_name = "ffi_offers_default_config_install")]
+        fn offers_default_config_install(&mut self) -> bool;
+        #[swift_bridge(rust_name = "ffi_install_default_config")]
+        fn macos_install_default_config(&mut self) -> bool;
+        #[swift_bridge(rust_name = "ffi_watch_config")]
+        fn watch_config(&mut self);
+        #[swift_bridge(rust_name = "ffi_start_recording")]
+        fn macos_start_recording(&mut self, path: String) -> bool;
+        #[swift_bridge(rust_name = "ffi_stop_recording")]
+        fn stop_recording(&mut self);
+        #[swift_bridge(rust_name = "ffi_set_secure_input")]
+        fn set_secure_input(&mut self, enabled: bool);
+        #[swift_bridge(rust_name = "ffi_set_enabled")]
+        fn set_enabled(&mut self, enabled: bool);
+        #[swift_bridge(rust_name = "ffi_is_enabled")]
+        fn is_enabled(&mut self) -> bool;
+        #[swift_bridge(rust_name = "ffi_switch_profile")]
+        fn switch_profile(&mut self, profile: String) -> bool;
+        #[swift_bridge(rust_name = "ffi_active_profile")]
+        fn active_profile(&mut self) -> String;
+        #[swift_bridge(rust_name = "ffi_profile_names")]
+        fn profile_names(&mut self) -> String;
+        #[swift_bridge(rust_name = "ffi_toggle_layer")]
+        fn toggle_layer(&mut self, stem: String) -> bool;
+        #[swift_bridge(rust_name = "ffi_clear_layer")]
+        fn clear_layer(&mut self);
+        #[swift_bridge(rust_name = "ffi_active_layer")]
+        fn active_layer(&mut self) -> String;
+        #[swift_bridge(rust_name = "ffi_set_caps_lock")]
+        fn set_caps_lock(&mut self, on: bool);
+        #[swift_bridge(rust_name = "ffi_take_caps_lock_restore")]
+        fn take_caps_lock_restore(&mut self) -> Option<bool>;
+        #[swift_bridge(rust_name = "ffi_set_modal")]
+        fn set_modal(&mut self, modal: bool);
+        #[swift_bridge(rust_name = "ffi_is_modal")]
+        fn is_modal(&mut self) -> bool;
+        #[swift_bridge(rust_name = "ffi_status_description")]
+        fn status_description(&mut self) -> String;
+        #[swift_bridge(rust_name = "ffi_reset_all")]
+        fn reset_all(&mut self);
+        #[swift_bridge(rust_name = "ffi_sync_physical_state")]
+        fn macos_sync_physical_state(&mut self, held_key_codes: Vec<i64>);
+        #[swift_bridge(rust_name = "ffi_take_help_text")]
+        fn take_help_text(&mut self) -> String;
+        #[swift_bridge(rust_name = "ffi_failure_description")]
+        fn failure_description(&mut self) -> String;
+
+        #[swift_bridge(rust_name = "ffi_handle_key_event")]
         fn macos_handle_key_event(
             &mut self,
             key_code: i64,
@@ -40,6 +143,7 @@ mod ff {
             window_name: &str,
         ) -> bool;
 
+        #[swift_bridge(rust_name = "ffi_get_synthetic_events")]
         fn get_synthetic_events(&mut self) -> Vec<KeyEvent>;
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,566 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::contexts::{Context, SemanticAction};
+use crate::loader::LayerSources;
+use crate::platform::Platform;
+use crate::sources::{ConfigLayer, Origin, SourceLine};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LintKind {
+    // An action used by a chord that no context the chord can fire in defines.
+    UndefinedAction,
+    // A context block in the semantics or mappings for a context that doesn't exist.
+    MissingContext,
+    // A chord a context defines exactly like the context it inherits it from.
+    ShadowedChord,
+    // The same chord or action defined twice in one block.
+    DuplicateDefinition,
+    // An alias that can never select its context.
+    UnmatchedAlias,
+    // Mappings of a context that can never be selected, or that passes everything through.
+    UnreachableMapping,
+    // Any other warning from loading the config.
+    ConfigWarning,
+}
+
+impl fmt::Display for LintKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LintKind::UndefinedAction => "undefined-action",
+            LintKind::MissingContext => "missing-context",
+            LintKind::ShadowedChord => "shadowed-chord",
+            LintKind::DuplicateDefinition => "duplicate-definition",
+            LintKind::UnmatchedAlias => "unmatched-alias",
+            LintKind::UnreachableMapping => "unreachable-mapping",
+            LintKind::ConfigWarning => "config-warning",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LintIssue {
+    // The file and line the issue was found at, when it has one.
+    pub location: Option<(PathBuf, usize)>,
+    pub kind: LintKind,
+    pub message: String,
+    // The platforms the issue shows up on, when it depends on the platform.
+    pub platforms: Vec<Platform>,
+}
+
+impl LintIssue {
+    fn new(kind: LintKind, location: Option<(PathBuf, usize)>, message: String) -> Self {
+        LintIssue {
+            location,
+            kind,
+            message,
+            platforms: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for LintIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some((file, line_number)) = &self.location {
+            write!(f, "{}:{}: ", file.display(), line_number)?;
+        }
+        write!(f, "{}: {}", self.kind, self.message)?;
+        if !self.platforms.is_empty() {
+            let names: Vec<&str> = self
+                .platforms
+                .iter()
+                .map(|platform| platform.name())
+                .collect();
+            write!(f, " ({})", names.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+fn line_location(line: &SourceLine) -> Option<(PathBuf, usize)> {
+    Some((line.file.clone(), line.line_number))
+}
+
+fn origin_location(origin: Option<&Origin>) -> Option<(PathBuf, usize)> {
+    origin.map(|origin| (origin.file.clone(), origin.line_number))
+}
+
+// Loads the config for each of the platforms and reports every problem found in it, sorted by
+// location. Issues that only show up on some of the platforms say which.
+pub fn lint_config(
+    layers: &[ConfigLayer],
+    platforms: &[Platform],
+) -> Result<Vec<LintIssue>, Box<dyn Error>> {
+    let mut merged: BTreeMap<LintIssue, Vec<Platform>> = BTreeMap::new();
+    for platform in platforms {
+        for issue in lint_platform(layers, *platform)? {
+            let issue_platforms = issue.platforms.clone();
+            let entry = merged
+                .entry(LintIssue {
+                    platforms: Vec::new(),
+                    ..issue
+                })
+                .or_default();
+            entry.extend(issue_platforms);
+        }
+    }
+
+    Ok(merged
+        .into_iter()
+        .map(|(issue, mut issue_platforms)| {
+            issue_platforms.sort();
+            issue_platforms.dedup();
+            LintIssue {
+                platforms: issue_platforms,
+                ..issue
+            }
+        })
+        .collect())
+}
+
+fn lint_platform(
+    layers: &[ConfigLayer],
+    platform: Platform,
+) -> Result<Vec<LintIssue>, Box<dyn Error>> {
+    let keycodes = crate::keycode_macos::create_keycode_map();
+    let loaded = crate::loader::load_config(layers, None, platform, &keycodes)?;
+    let contexts = &loaded.contexts;
+
+    let mut issues = Vec::new();
+    lint_sources(layers, contexts, &mut issues)?;
+
+    // Warnings about missing contexts are reported above with their own kind.
+    let reported: HashSet<String> = issues
+        .iter()
+        .filter_map(|issue| issue.location.as_ref())
+        .map(|(file, line_number)| format!("{}:{}", file.display(), line_number))
+        .collect();
+    for diagnostic in &loaded.diagnostics {
+        let location = diagnostic.split(": ").next().unwrap_or_default();
+        if !reported.contains(location) {
+            issues.push(LintIssue::new(
+                LintKind::ConfigWarning,
+                None,
+                diagnostic.clone(),
+            ));
+        }
+    }
+
+    // Actions are stored in lowercase, messages name them as they were written.
+    let mut action_names = crate::formatter::action_names(contexts.values());
+    add_used_action_names(layers, &mut action_names)?;
+    lint_undefined_actions(contexts, platform, &action_names, &mut issues);
+    lint_shadowed_chords(contexts, &mut issues);
+    lint_aliases(contexts, &mut issues);
+    lint_unreachable_mappings(contexts, &mut issues);
+
+    issues.sort();
+    issues.dedup();
+    Ok(issues)
+}
+
+// The contexts a context inherits from, starting with itself.
+fn ancestors<'a>(context: &'a Context, contexts: &'a HashMap<String, Context>) -> Vec<&'a Context> {
+    let mut chain = vec![context];
+    while let Some(parent) = chain.last().and_then(|context| context.parent.as_ref()) {
+        match contexts.get(parent) {
+            // Guard against parent cycles.
+            Some(parent) if !chain.iter().any(|context| context.name == parent.name) => {
+                chain.push(parent)
+            }
+            _ => break,
+        }
+    }
+    chain
+}
+
+fn mapping_location(context: &Context, chord: &str) -> Option<(PathBuf, usize)> {
+    origin_location(context.mapping_origins.get(chord))
+}
+
+fn is_stem(action: &SemanticAction) -> bool {
+    *action == SemanticAction::Action("MappingStem".to_string())
+}
+
+// The files that define semantic actions and mappings.
+fn config_file_names() -> Vec<String> {
+    let mut file_names = vec!["semantics.txt".to_string(), "mappings.txt".to_string()];
+    file_names.extend(
+        Platform::ALL
+            .iter()
+            .map(|platform| platform.semantics_file_name()),
+    );
+    file_names
+}
+
+// Adds the spelling of actions that mappings and definitions use but nothing defines, e.g.
+// `ViewSymbols`, so they can be named as written too. Definitions keep their own spelling.
+fn add_used_action_names(
+    layers: &[ConfigLayer],
+    action_names: &mut HashMap<String, String>,
+) -> Result<(), Box<dyn Error>> {
+    let mut sources = LayerSources::new(layers, &mut Vec::new())?;
+    for file_name in config_file_names() {
+        for (_, lines) in sources.optional_files(&file_name)? {
+            let values = lines
+                .iter()
+                .filter_map(|line| line.text.split_once('=').map(|(_, value)| value));
+            for part in values.flat_map(|value| value.split('|')).map(str::trim) {
+                let is_name = part.chars().all(|c| c.is_alphanumeric() || c == '_');
+                if is_name && part.chars().any(char::is_uppercase) {
+                    action_names
+                        .entry(part.to_lowercase())
+                        .or_insert_with(|| part.to_string());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Checks the raw files for context blocks of unknown contexts and for duplicates in a block.
+fn lint_sources(
+    layers: &[ConfigLayer],
+    contexts: &HashMap<String, Context>,
+    issues: &mut Vec<LintIssue>,
+) -> Result<(), Box<dyn Error>> {
+    let mut sources = LayerSources::new(layers, &mut Vec::new())?;
+    for file_name in config_file_names() {
+        let stems = file_name == "mappings.txt";
+        for (_, lines) in sources.optional_files(&file_name)? {
+            lint_blocks(&lines, contexts, stems, issues);
+        }
+    }
+    Ok(())
+}
+
+fn lint_blocks(
+    lines: &[SourceLine],
+    contexts: &HashMap<String, Context>,
+    stems: bool,
+    issues: &mut Vec<LintIssue>,
+) {
+    let mut block: HashMap<String, &SourceLine> = HashMap::new();
+    let mut current_prefix = String::new();
+    let mut current_file = None;
+
+    for line in lines {
+        if current_file != Some(&line.file) {
+            current_file = Some(&line.file);
+            block.clear();
+            current_prefix.clear();
+        }
+
+        let trimmed_line = line.text.trim();
+        if trimmed_line.is_empty() || trimmed_line.starts_with("//") {
+            continue;
+        }
+
+        if !line.text.starts_with(' ') && !line.text.starts_with('\t') {
+            let context_name = trimmed_line.trim_end_matches(':');
+            if !contexts.contains_key(context_name) {
+                issues.push(LintIssue::new(
+                    LintKind::MissingContext,
+                    line_location(line),
+                    format!("Context '{}' is not defined in contexts.txt", context_name),
+                ));
+            }
+            block.clear();
+            current_prefix.clear();
+        } else if stems && trimmed_line.ends_with(':') {
+            current_prefix = trimmed_line.trim_end_matches(':').to_string();
+        } else if let Some((key, _)) = trimmed_line.split_once('=') {
+            let key = if current_prefix.is_empty() {
+                key.trim().to_string()
+            } else {
+                format!("{} + {}", current_prefix, key.trim())
+            };
+            let normalized_key = key
+                .split('+')
+                .map(|part| part.trim().to_lowercase())
+                .collect::<Vec<String>>()
+                .join(" + ");
+            if let Some(first) = block.get(&normalized_key) {
+                issues.push(LintIssue::new(
+                    LintKind::DuplicateDefinition,
+                    line_location(line),
+                    format!(
+                        "'{}' is already defined in this block at {}",
+                        key,
+                        first.location()
+                    ),
+                ));
+            } else {
+                block.insert(normalized_key, line);
+            }
+        }
+    }
+}
+
+// The semantic actions a context has for a platform.
+fn semantic_actions(
+    context: &Context,
+    platform: Platform,
+    host_platform: Platform,
+) -> Option<&HashMap<String, SemanticAction>> {
+    if platform == host_platform {
+        Some(&context.semantic_actions)
+    } else {
+        context.foreign_semantic_actions.get(&platform)
+    }
+}
+
+// Collects the actions that can't be resolved, the same way the engine resolves them: an action
+// is looked up in the context and then in its parents, and what it refers to is resolved from
+// the context that defined it.
+fn undefined_actions(
+    action: &SemanticAction,
+    context: &Context,
+    contexts: &HashMap<String, Context>,
+    platform: Platform,
+    host_platform: Platform,
+    visiting: &mut Vec<(String, String)>,
+    undefined: &mut Vec<String>,
+) {
+    match action {
+        SemanticAction::Sequence(actions) => {
+            for action in actions {
+                undefined_actions(
+                    action,
+                    context,
+                    contexts,
+                    platform,
+                    host_platform,
+                    visiting,
+                    undefined,
+                );
+            }
+        }
+        SemanticAction::Action(name) => {
+            let definition = ancestors(context, contexts)
+                .into_iter()
+                .find_map(|ancestor| {
+                    semantic_actions(ancestor, platform, host_platform)
+                        .and_then(|actions| actions.get(name))
+                        .map(|action| (ancestor, action))
+                });
+            match definition {
+                Some((ancestor, action)) => {
+                    let key = (ancestor.name.clone(), name.clone());
+                    if visiting.contains(&key) {
+                        return;
+                    }
+                    visiting.push(key);
+                    undefined_actions(
+                        action,
+                        ancestor,
+                        contexts,
+                        platform,
+                        host_platform,
+                        visiting,
+                        undefined,
+                    );
+                    visiting.pop();
+                }
+                None => undefined.push(name.clone()),
+            }
+        }
+        SemanticAction::KeyEvent(_)
+        | SemanticAction::LiteralString(_)
+        | SemanticAction::Passthrough
+        | SemanticAction::Engine(_) => (),
+    }
+}
+
+// The semantics platform of a context, inherited from its parents unless it sets one itself.
+fn semantics_platform(
+    context: &Context,
+    contexts: &HashMap<String, Context>,
+    host_platform: Platform,
+) -> Platform {
+    ancestors(context, contexts)
+        .into_iter()
+        .find_map(|context| context.semantics_platform)
+        .unwrap_or(host_platform)
+}
+
+// Reports actions that can't be resolved in any context a chord fires in: the context that maps
+// the chord and every descendant that inherits it.
+fn lint_undefined_actions(
+    contexts: &HashMap<String, Context>,
+    platform: Platform,
+    action_names: &HashMap<String, String>,
+    issues: &mut Vec<LintIssue>,
+) {
+
+    // How many contexts each chord fires in, and in how many of them each action is undefined.
+    let mut fires: BTreeMap<(String, String), usize> = BTreeMap::new();
+    let mut undefined_counts: BTreeMap<(String, String, String), usize> = BTreeMap::new();
+
+    for context in contexts.values() {
+        let context_platform = semantics_platform(context, contexts, platform);
+        let mut seen_chords = HashSet::new();
+        for ancestor in ancestors(context, contexts) {
+            if ancestor.passthrough_all {
+                break;
+            }
+            for (chord, action) in &ancestor.key_mappings {
+                if is_stem(action) || !seen_chords.insert(chord.clone()) {
+                    continue;
+                }
+                *fires
+                    .entry((ancestor.name.clone(), chord.clone()))
+                    .or_default() += 1;
+
+                let mut undefined = Vec::new();
+                undefined_actions(
+                    action,
+                    context,
+                    contexts,
+                    context_platform,
+                    platform,
+                    &mut Vec::new(),
+                    &mut undefined,
+                );
+                undefined.sort();
+                undefined.dedup();
+                for name in undefined {
+                    *undefined_counts
+                        .entry((ancestor.name.clone(), chord.clone(), name))
+                        .or_default() += 1;
+                }
+            }
+        }
+    }
+
+    for ((context_name, chord, action), count) in undefined_counts {
+        if count < fires[&(context_name.clone(), chord.clone())] {
+            continue;
+        }
+        let context = &contexts[&context_name];
+        let action = action_names.get(&action).unwrap_or(&action);
+        issues.push(LintIssue {
+            platforms: vec![platform],
+            ..LintIssue::new(
+                LintKind::UndefinedAction,
+                mapping_location(context, &chord),
+                format!(
+                    "Action '{}' of chord '{}' in context '{}' is not defined in any context it can fire in",
+                    action, chord, context_name
+                ),
+            )
+        });
+    }
+}
+
+// Reports chords a context defines exactly as it would inherit them.
+fn lint_shadowed_chords(contexts: &HashMap<String, Context>, issues: &mut Vec<LintIssue>) {
+    for context in contexts.values() {
+        for (chord, action) in &context.key_mappings {
+            if is_stem(action) {
+                continue;
+            }
+            let inherited = ancestors(context, contexts)
+                .into_iter()
+                .skip(1)
+                .take_while(|ancestor| !ancestor.passthrough_all)
+                .find_map(|ancestor| {
+                    ancestor
+                        .key_mappings
+                        .get(chord)
+                        .map(|action| (ancestor, action))
+                });
+            if let Some((ancestor, inherited_action)) = inherited {
+                if inherited_action == action {
+                    issues.push(LintIssue::new(
+                        LintKind::ShadowedChord,
+                        mapping_location(context, chord),
+                        format!(
+                            "Chord '{}' in context '{}' is the same as the one it inherits from '{}'",
+                            chord, context.name, ancestor.name
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+// Reports aliases that can't select their context: empty ones, which match every application,
+// and ones another context has too, since only one of the two can ever match.
+fn lint_aliases(contexts: &HashMap<String, Context>, issues: &mut Vec<LintIssue>) {
+    let mut owners: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for context in contexts.values() {
+        for alias in &context.aliases {
+            if alias.trim().is_empty() {
+                issues.push(LintIssue::new(
+                    LintKind::UnmatchedAlias,
+                    origin_location(context.origin.as_ref()),
+                    format!(
+                        "Context '{}' has an empty alias, which matches any application",
+                        context.name
+                    ),
+                ));
+                continue;
+            }
+            owners
+                .entry(alias.to_lowercase())
+                .or_default()
+                .push(&context.name);
+        }
+    }
+
+    for (alias, mut names) in owners {
+        names.sort();
+        names.dedup();
+        if names.len() > 1 {
+            issues.push(LintIssue::new(
+                LintKind::UnmatchedAlias,
+                None,
+                format!(
+                    "Alias '{}' is used by contexts {:?}, only one of them can match it",
+                    alias, names
+                ),
+            ));
+        }
+    }
+}
+
+// Reports mappings that never fire: those of a context that passes everything through, and
+// those of a context that neither it nor any descendant can be selected for.
+fn lint_unreachable_mappings(contexts: &HashMap<String, Context>, issues: &mut Vec<LintIssue>) {
+    let selectable = |context: &Context| {
+        !context.aliases.is_empty() || context.name.eq_ignore_ascii_case("default")
+    };
+    let reachable: HashSet<&str> = contexts
+        .values()
+        .filter(|context| selectable(context))
+        .flat_map(|context| ancestors(context, contexts))
+        .map(|context| context.name.as_str())
+        .collect();
+
+    for context in contexts.values() {
+        let mapped = context.key_mappings.values().any(|action| !is_stem(action));
+        if !mapped {
+            continue;
+        }
+        let reason = if context.passthrough_all {
+            "passes all chords through"
+        } else if !reachable.contains(context.name.as_str()) {
+            "has no aliases and no descendant with aliases"
+        } else {
+            continue;
+        };
+        issues.push(LintIssue::new(
+            LintKind::UnreachableMapping,
+            origin_location(context.origin.as_ref()),
+            format!(
+                "The mappings of context '{}' never fire, the context {}",
+                context.name, reason
+            ),
+        ));
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::contexts::{Context, SemanticAction};
+use crate::keycode_macos::KeyCodeLookup;
+use crate::logging::log;
+use crate::platform::Platform;
+use crate::profiles::PROFILES_FILE;
+use crate::sources::{ConfigLayer, SourceLine};
+use crate::unified::UNIFIED_CONFIG_FILE;
+
+// The lines of a config file, together with the index of the layer it was read from.
+type LayerFile = (usize, Vec<SourceLine>);
+
+// A fully parsed config, ready to be swapped into the engine.
+pub struct LoadedConfig {
+    pub contexts: HashMap<String, Context>,
+    pub diagnostics: Vec<String>,
+    // Every file the config was read from, plus the files a directory layer could add.
+    pub files: Vec<PathBuf>,
+    // The names of the profiles the config defines, sorted.
+    pub profiles: Vec<String>,
+}
+
+// Loads and merges every layer, with the layers of the given profile on top. This does not touch
+// the engine, so it can run on another thread and the result is only swapped in once it loaded
+// without errors.
+pub fn load_config(
+    layers: &[ConfigLayer],
+    profile: Option<&str>,
+    platform: Platform,
+    keycodes: &KeyCodeLookup,
+) -> Result<LoadedConfig, Box<dyn Error>> {
+    let (profiles, profile_files) = crate::profiles::load_profiles(layers)?;
+    let profile = match profile {
+        Some(name) => Some(
+            profiles
+                .get(name)
+                .ok_or_else(|| format!("No profile named '{}'", name))?,
+        ),
+        None => None,
+    };
+    let layers: Vec<ConfigLayer> = layers
+        .iter()
+        .chain(profile.iter().flat_map(|profile| profile.layers.iter()))
+        .cloned()
+        .collect();
+    for layer in &layers {
+        log!(Debug, Config, "Loading configurations from: {}", layer);
+    }
+
+    let mut diagnostics = Vec::new();
+    let mut sources = LayerSources::new(&layers, &mut diagnostics)?;
+
+    let mut contexts = HashMap::new();
+    for (layer, lines) in sources.files("contexts.txt")? {
+        // A context defined again in a later layer replaces the earlier definition.
+        contexts.extend(crate::contexts::parse_yaml_lines(&lines, layer, &mut diagnostics)?);
+    }
+    crate::contexts::validate_parents(&mut contexts, &mut diagnostics);
+
+    load_semantics(&mut sources, &mut contexts, keycodes, platform, false, &mut diagnostics)?;
+
+    // Contexts such as remote desktops can use the semantics of another platform.
+    let foreign_platforms: HashSet<Platform> = contexts
+        .values()
+        .filter_map(|context| context.semantics_platform)
+        .filter(|foreign_platform| *foreign_platform != platform)
+        .collect();
+    for foreign_platform in foreign_platforms {
+        load_semantics(
+            &mut sources,
+            &mut contexts,
+            keycodes,
+            foreign_platform,
+            true,
+            &mut diagnostics,
+        )?;
+    }
+
+    for (layer, lines) in sources.files("mappings.txt")? {
+        crate::mappings::parse_mappings_lines(
+            &lines,
+            &mut contexts,
+            keycodes,
+            platform,
+            layer,
+            &mut diagnostics,
+        );
+    }
+
+    // A profile can narrow the mappings down to some of the contexts. The other contexts are
+    // kept, so their applications still fall back to the mappings of their parents.
+    if let Some((profile, profile_contexts)) = profile
+        .and_then(|profile| Some((profile, profile.contexts.as_ref()?)))
+    {
+        for name in profile_contexts {
+            if !contexts.contains_key(name) {
+                diagnostics.push(format!(
+                    "Context '{}' of profile '{}' not found. Skipping.",
+                    name, profile.name
+                ));
+            }
+        }
+        for context in contexts.values_mut() {
+            if !profile_contexts.contains(&context.name) {
+                context.key_mappings.clear();
+                context.mapping_origins.clear();
+            }
+        }
+    }
+
+    // New files in a directory layer should be picked up too.
+    let mut files = sources.read_files;
+    files.extend(profile_files);
+    for layer in &layers {
+        if let ConfigLayer::Directory(path) = layer {
+            files.push(path.clone());
+            for file_name in [
+                "contexts.txt",
+                "semantics.txt",
+                "mappings.txt",
+                UNIFIED_CONFIG_FILE,
+                PROFILES_FILE,
+            ] {
+                files.push(path.join(file_name));
+            }
+            files.push(path.join(platform.semantics_file_name()));
+        }
+    }
+    files.sort();
+    files.dedup();
+
+    Ok(LoadedConfig {
+        contexts,
+        diagnostics,
+        files,
+        profiles: profiles.into_keys().collect(),
+    })
+}
+
+// The config files of every layer. A layer with a unified config provides the `.txt` files
+// translated from it, instead of its own.
+pub(crate) struct LayerSources<'a> {
+    layers: &'a [ConfigLayer],
+    unified: Vec<Option<HashMap<String, Vec<SourceLine>>>>,
+    read_files: Vec<PathBuf>,
+}
+
+impl<'a> LayerSources<'a> {
+    pub(crate) fn new(layers: &'a [ConfigLayer], diagnostics: &mut Vec<String>) -> Result<Self, Box<dyn Error>> {
+        let mut read_files = Vec::new();
+        let mut unified = Vec::new();
+        for layer in layers {
+            if !layer.has_file(UNIFIED_CONFIG_FILE) {
+                unified.push(None);
+                continue;
+            }
+            log!(Debug, Config, "Loading {} from: {}", UNIFIED_CONFIG_FILE, layer);
+            let lines = layer.read_lines(UNIFIED_CONFIG_FILE)?;
+            read_files.extend(lines.iter().map(|line| line.file.clone()));
+            for file_name in ["contexts.txt", "mappings.txt"] {
+                if layer.has_file(file_name) {
+                    diagnostics.push(format!(
+                        "{} in {} is ignored in favour of {}.",
+                        file_name, layer, UNIFIED_CONFIG_FILE
+                    ));
+                }
+            }
+            unified.push(Some(crate::unified::unified_to_txt_lines(&lines)?));
+        }
+        Ok(LayerSources {
+            layers,
+            unified,
+            read_files,
+        })
+    }
+
+    // Reads the given config file from every layer that provides it, in order.
+    pub(crate) fn optional_files(&mut self, file_name: &str) -> Result<Vec<LayerFile>, Box<dyn Error>> {
+        let mut layer_files = Vec::new();
+        for (index, layer) in self.layers.iter().enumerate() {
+            if let Some(unified) = &self.unified[index] {
+                if let Some(lines) = unified.get(file_name) {
+                    layer_files.push((index, lines.clone()));
+                }
+            } else if layer.has_file(file_name) {
+                log!(Debug, Config, "Loading {} from: {}", file_name, layer);
+                let lines = layer.read_lines(file_name)?;
+                self.read_files.extend(lines.iter().map(|line| line.file.clone()));
+                self.read_files.dedup();
+                layer_files.push((index, lines));
+            }
+        }
+        Ok(layer_files)
+    }
+
+    // Like `optional_files`, but at least one of the layers has to provide the file.
+    fn files(&mut self, file_name: &str) -> Result<Vec<LayerFile>, Box<dyn Error>> {
+        let layer_files = self.optional_files(file_name)?;
+        if layer_files.is_empty() {
+            let layers: Vec<String> = self.layers.iter().map(|layer| layer.to_string()).collect();
+            return Err(format!("No {} found in config layers {:?}", file_name, layers).into());
+        }
+        Ok(layer_files)
+    }
+}
+
+// Loads the shared `semantics.txt` and then the platform specific file of each layer in turn, so
+// a later layer overrides an earlier one whichever file it defines an action in. Either file is
+// enough on its own.
+fn load_semantics(
+    sources: &mut LayerSources,
+    contexts: &mut HashMap<String, Context>,
+    keycodes: &KeyCodeLookup,
+    platform: Platform,
+    foreign: bool,
+    diagnostics: &mut Vec<String>,
+) -> Result<(), Box<dyn Error>> {
+    let mut semantics_files = sources.optional_files("semantics.txt")?;
+    let platform_files = sources.optional_files(&platform.semantics_file_name())?;
+    if semantics_files.is_empty() && platform_files.is_empty() {
+        // Report the platform file as the missing one.
+        sources.files(&platform.semantics_file_name())?;
+    }
+    semantics_files.extend(platform_files);
+    // Stable, so the shared file stays ahead of the platform file of the same layer.
+    semantics_files.sort_by_key(|(layer, _)| *layer);
+
+    for (layer, lines) in semantics_files {
+        crate::semantics::parse_semantics_lines(
+            &lines,
+            contexts,
+            keycodes,
+            platform,
+            foreign,
+            layer,
+            diagnostics,
+        );
+    }
+
+    Ok(())
+}
+
+// What changed between two configs, as reported after a reload.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReloadReport {
+    pub added_contexts: Vec<String>,
+    pub removed_contexts: Vec<String>,
+    // Mappings and semantic actions are listed as `Context: key`.
+    pub added_mappings: Vec<String>,
+    pub removed_mappings: Vec<String>,
+    pub changed_mappings: Vec<String>,
+    pub changed_semantic_actions: Vec<String>,
+    pub diagnostics: Vec<String>,
+}
+
+impl ReloadReport {
+    pub fn between(old: &HashMap<String, Context>, new: &LoadedConfig) -> Self {
+        let mut report = ReloadReport {
+            diagnostics: new.diagnostics.clone(),
+            ..ReloadReport::default()
+        };
+
+        let context_names: BTreeSet<&String> = old.keys().chain(new.contexts.keys()).collect();
+        for name in context_names {
+            let empty = HashMap::new();
+            let (old_mappings, old_semantics) = match old.get(name) {
+                Some(context) => (&context.key_mappings, &context.semantic_actions),
+                None => {
+                    report.added_contexts.push(name.clone());
+                    (&empty, &empty)
+                }
+            };
+            let (new_mappings, new_semantics) = match new.contexts.get(name) {
+                Some(context) => (&context.key_mappings, &context.semantic_actions),
+                None => {
+                    report.removed_contexts.push(name.clone());
+                    (&empty, &empty)
+                }
+            };
+
+            let chords: BTreeSet<&String> = old_mappings.keys().chain(new_mappings.keys()).collect();
+            for chord in chords {
+                let entry = format!("{}: {}", name, chord);
+                match (old_mappings.get(chord), new_mappings.get(chord)) {
+                    (None, Some(_)) => report.added_mappings.push(entry),
+                    (Some(_), None) => report.removed_mappings.push(entry),
+                    (Some(old_action), Some(new_action)) if old_action != new_action => {
+                        report.changed_mappings.push(entry)
+                    }
+                    _ => (),
+                }
+            }
+
+            // Semantic actions are stored under their own and their lowercase name.
+            let lowercase = |actions: &HashMap<String, SemanticAction>| -> BTreeSet<String> {
+                actions.keys().map(|action| action.to_lowercase()).collect()
+            };
+            let actions: BTreeSet<String> =
+                lowercase(old_semantics).union(&lowercase(new_semantics)).cloned().collect();
+            for action in actions {
+                if old_semantics.get(&action) != new_semantics.get(&action) {
+                    report.changed_semantic_actions.push(format!("{}: {}", name, action));
+                }
+            }
+        }
+
+        report
+    }
+
+    pub fn has_changes(&self) -> bool {
+        !(self.added_contexts.is_empty()
+            && self.removed_contexts.is_empty()
+            && self.added_mappings.is_empty()
+            && self.removed_mappings.is_empty()
+            && self.changed_mappings.is_empty()
+            && self.changed_semantic_actions.is_empty())
+    }
+}
+
+impl fmt::Display for ReloadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.has_changes() {
+            writeln!(f, "No changes.")?;
+        }
+        let sections = [
+            ("Added contexts", &self.added_contexts),
+            ("Removed contexts", &self.removed_contexts),
+            ("Added mappings", &self.added_mappings),
+            ("Removed mappings", &self.removed_mappings),
+            ("Changed mappings", &self.changed_mappings),
+            ("Changed semantic actions", &self.changed_semantic_actions),
+            ("Diagnostics", &self.diagnostics),
+        ];
+        for (title, entries) in sections {
+            if entries.is_empty() {
+                continue;
+            }
+            writeln!(f, "{}:", title)?;
+            for entry in entries {
+                writeln!(f, "  {}", entry)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{OnceLock, RwLock};
+
+use crate::contexts::KeyEvent;
+
+// Logging is configured once for the process, like the environment it reads from:
+//
+//     PINKYTWIRL_LOG=debug,config=info,typed-keys
+//
+// sets the level of every category, then of a single category, and `typed-keys` opts in to
+// logging the keys that are typed. Without it, keys that type text are written as `<key>`, so
+// passwords never end up in a log.
+pub const LOG_ENV: &str = "PINKYTWIRL_LOG";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn from_name(name: &str) -> Option<LogLevel> {
+        match name.trim().to_lowercase().as_str() {
+            "off" => Some(LogLevel::Off),
+            "error" => Some(LogLevel::Error),
+            "warn" | "warning" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            "trace" => Some(LogLevel::Trace),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LogLevel::Off => "off",
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LogCategory {
+    // Loading, reloading and watching the config.
+    Config,
+    // Every key event the engine sees.
+    Keys,
+    // Chords that matched a mapping and what they resolved to.
+    Chords,
+    // Changes to how the engine treats the current chord.
+    State,
+    Recording,
+}
+
+impl LogCategory {
+    pub const ALL: [LogCategory; 5] = [
+        LogCategory::Config,
+        LogCategory::Keys,
+        LogCategory::Chords,
+        LogCategory::State,
+        LogCategory::Recording,
+    ];
+
+    pub fn from_name(name: &str) -> Option<LogCategory> {
+        LogCategory::ALL
+            .into_iter()
+            .find(|category| category.to_string() == name.trim().to_lowercase())
+    }
+}
+
+impl fmt::Display for LogCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LogCategory::Config => "config",
+            LogCategory::Keys => "keys",
+            LogCategory::Chords => "chords",
+            LogCategory::State => "state",
+            LogCategory::Recording => "recording",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    pub level: LogLevel,
+    pub category: LogCategory,
+    pub message: String,
+}
+
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{} {}] {}", self.level, self.category, self.message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogSettings {
+    pub level: LogLevel,
+    pub category_levels: HashMap<LogCategory, LogLevel>,
+    // Logs the keys that type text instead of redacting them. Only for debug sessions.
+    pub typed_keys: bool,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        LogSettings {
+            level: LogLevel::Info,
+            category_levels: HashMap::new(),
+            typed_keys: false,
+        }
+    }
+}
+
+impl LogSettings {
+    // Parses settings in the syntax of `PINKYTWIRL_LOG`.
+    pub fn from_spec(spec: &str) -> Result<LogSettings, String> {
+        let mut settings = LogSettings::default();
+        for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            if entry == "typed-keys" {
+                settings.typed_keys = true;
+            } else if let Some((category_name, level_name)) = entry.split_once('=') {
+                let category = LogCategory::from_name(category_name)
+                    .ok_or_else(|| format!("Unknown log category '{}'", category_name))?;
+                let level = LogLevel::from_name(level_name)
+                    .ok_or_else(|| format!("Unknown log level '{}'", level_name))?;
+                settings.category_levels.insert(category, level);
+            } else {
+                settings.level =
+                    LogLevel::from_name(entry).ok_or_else(|| format!("Unknown log level '{}'", entry))?;
+            }
+        }
+        Ok(settings)
+    }
+
+    pub fn enabled(&self, level: LogLevel, category: LogCategory) -> bool {
+        level != LogLevel::Off && level <= *self.category_levels.get(&category).unwrap_or(&self.level)
+    }
+}
+
+pub type LogSink = Box<dyn Fn(&LogRecord) + Send + Sync>;
+
+struct Logger {
+    settings: LogSettings,
+    sink: Option<LogSink>,
+}
+
+fn logger() -> &'static RwLock<Logger> {
+    static LOGGER: OnceLock<RwLock<Logger>> = OnceLock::new();
+    LOGGER.get_or_init(|| {
+        let settings = match std::env::var(LOG_ENV) {
+            Ok(spec) => LogSettings::from_spec(&spec).unwrap_or_else(|e| {
+                eprintln!("Ignoring {}: {}", LOG_ENV, e);
+                LogSettings::default()
+            }),
+            Err(_) => LogSettings::default(),
+        };
+        RwLock::new(Logger { settings, sink: None })
+    })
+}
+
+pub fn log_settings() -> LogSettings {
+    logger().read().unwrap_or_else(|e| e.into_inner()).settings.clone()
+}
+
+pub fn configure_logging(settings: LogSettings) {
+    logger().write().unwrap_or_else(|e| e.into_inner()).settings = settings;
+}
+
+// Sends log records somewhere other than standard error, or back there with `None`.
+pub fn set_log_sink(sink: Option<LogSink>) {
+    logger().write().unwrap_or_else(|e| e.into_inner()).sink = sink;
+}
+
+pub(crate) fn log_enabled(level: LogLevel, category: LogCategory) -> bool {
+    logger()
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .settings
+        .enabled(level, category)
+}
+
+pub(crate) fn write_log(level: LogLevel, category: LogCategory, message: String) {
+    let logger = logger().read().unwrap_or_else(|e| e.into_inner());
+    let record = LogRecord {
+        level,
+        category,
+        message,
+    };
+    match &logger.sink {
+        Some(sink) => sink(&record),
+        None => eprintln!("{}", record),
+    }
+}
+
+// Logs a message if its level is enabled for the category, e.g.
+// `log!(Debug, Chords, "Found action: {}", action)`.
+macro_rules! log {
+    ($level:ident, $category:ident, $($arg:tt)+) => {
+        if $crate::logging::log_enabled(
+            $crate::logging::LogLevel::$level,
+            $crate::logging::LogCategory::$category,
+        ) {
+            $crate::logging::write_log(
+                $crate::logging::LogLevel::$level,
+                $crate::logging::LogCategory::$category,
+                format!($($arg)+),
+            );
+        }
+    };
+}
+pub(crate) use log;
+
+// Keys that never type text, so they can be logged without giving anything away.
+const NON_TYPING_KEYS: [&str; 23] = [
+    "shift", "ctrl", "alt", "meta", "command", "fn", "capslock", "escape", "tab", "return",
+    "returnkey", "enter", "backspace", "delete", "left", "right", "up", "down", "home", "end",
+    "pageup", "pagedown", "help",
+];
+
+fn is_typing_key(key: &str) -> bool {
+    let key = key.to_lowercase();
+    let function_key = key
+        .strip_prefix('f')
+        .is_some_and(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()));
+    !(function_key || NON_TYPING_KEYS.contains(&key.as_str()))
+}
+
+// The name of a key for the log, `<key>` for keys that type text unless typed keys are logged.
+pub(crate) fn redact_key(key: &str) -> String {
+    let typed_keys = logger().read().unwrap_or_else(|e| e.into_inner()).settings.typed_keys;
+    if is_typing_key(key) && !typed_keys {
+        "<key>".to_string()
+    } else {
+        key.to_string()
+    }
+}
+
+// The keys of a chord for the log, such as `meta + <key>`.
+pub(crate) fn redact_keys<'a>(keys: impl IntoIterator<Item = &'a KeyEvent>) -> String {
+    keys.into_iter()
+        .map(|key| redact_key(&key.key))
+        .collect::<Vec<String>>()
+        .join(" + ")
+}
@@ -1,22 +1,32 @@
 use crate::contexts::{parse_semantic_action, Context, SemanticAction};
 use crate::keycode_macos::KeyCodeLookup;
+use crate::logging::log;
+use crate::platform::Platform;
+use crate::sources::{Origin, SourceLine};
 use std::collections::HashMap;
-use std::fs;
 use std::path::Path;
 
-pub fn parse_mappings_file(
-    file_path: &Path,
+pub fn parse_mappings_lines(
+    lines: &[SourceLine],
     contexts: &mut HashMap<String, Context>,
     keycodes: &KeyCodeLookup,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let debug = false;
-    let warn = true;
-
-    let content = fs::read_to_string(file_path)?;
+    platform: Platform,
+    layer: usize,
+    diagnostics: &mut Vec<String>,
+) {
     let mut current_context: Option<&mut Context> = None;
     let mut current_prefix = String::new();
+    let mut current_file: Option<&Path> = None;
 
-    for line in content.lines() {
+    for source_line in lines {
+        // A context block never continues across an included file.
+        if current_file != Some(source_line.file.as_path()) {
+            current_file = Some(source_line.file.as_path());
+            current_context = None;
+            current_prefix.clear();
+        }
+
+        let line = source_line.text.as_str();
         let trimmed_line = line.trim();
         if trimmed_line.is_empty() || trimmed_line.starts_with("//") {
             continue;
@@ -27,12 +37,11 @@ pub fn parse_mappings_file(
             let context_name = trimmed_line.trim_end_matches(':');
             current_context = contexts.get_mut(context_name);
             if current_context.is_none() {
-                if warn {
-                    println!(
-                        "Warning: Context '{}' not found in mappings. Skipping.",
-                        context_name
-                    );
-                }
+                diagnostics.push(format!(
+                    "{}: Context '{}' not found in mappings. Skipping.",
+                    source_line.location(),
+                    context_name
+                ));
             }
             current_prefix.clear();
         } else if trimmed_line.ends_with(':') {
@@ -43,6 +52,9 @@ pub fn parse_mappings_file(
                 context
                     .key_mappings
                     .insert(current_prefix.to_lowercase(), SemanticAction::Action("MappingStem".to_string()));
+                context
+                    .mapping_origins
+                    .insert(current_prefix.to_lowercase(), Origin::new(layer, source_line));
             }
         } else if let Some(context) = &mut current_context {
             // This is a key mapping.
@@ -53,22 +65,28 @@ pub fn parse_mappings_file(
                     format!("{} + {}", current_prefix, key.trim())
                 };
                 let action = value.trim().to_string();
-                let semantic_action = parse_semantic_action(&action, keycodes);
-
-                if debug {
-                    println!(
-                        "Added key mapping: {} -> {}",
-                        full_key.to_lowercase(),
-                        semantic_action
-                    );
+                let semantic_action = parse_semantic_action(&action, keycodes, platform);
+                // A chord is either unbound or sends something, never both.
+                if semantic_action != SemanticAction::Passthrough
+                    && semantic_action.contains_passthrough()
+                {
+                    diagnostics.push(format!(
+                        "{}: Passthrough has to be the whole mapping of '{}'. Skipping.",
+                        source_line.location(),
+                        full_key
+                    ));
+                    continue;
                 }
 
+                log!(Trace, Config, "Added key mapping: {} -> {}", full_key.to_lowercase(), semantic_action);
+
                 context
                     .key_mappings
                     .insert(full_key.to_lowercase(), semantic_action);
+                context
+                    .mapping_origins
+                    .insert(full_key.to_lowercase(), Origin::new(layer, source_line));
             }
         }
     }
-
-    Ok(())
 }
@@ -0,0 +1,197 @@
+use std::fmt;
+
+// Counts above this are clamped, so a mistyped `9999 j` doesn't flood the host with key events.
+pub const MAX_COUNT: usize = 100;
+
+// What an operator does with the text its motion selected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Delete,
+    Yank,
+    Change,
+}
+
+impl Operator {
+    fn from_key(key: &str, shift: bool) -> Option<Operator> {
+        match (key, shift) {
+            ("d", false) => Some(Operator::Delete),
+            ("y", false) => Some(Operator::Yank),
+            ("c", false) => Some(Operator::Change),
+            _ => None,
+        }
+    }
+
+    fn action(self) -> &'static str {
+        match self {
+            Operator::Delete => "Delete",
+            Operator::Yank => "Copy",
+            Operator::Change => "Cut",
+        }
+    }
+
+    fn key(self) -> char {
+        match self {
+            Operator::Delete => 'd',
+            Operator::Yank => 'y',
+            Operator::Change => 'c',
+        }
+    }
+}
+
+// The part of the semantic action names a motion shares, e.g. `NextWord` for `NavNextWord` and
+// `SelectNextWord`. `gg` is handled separately, since it takes two keys. Unlike in vim, `{` and
+// `}` aren't paragraph motions, they move by the big step of `NavBigUp` and `NavBigDown`.
+fn motion(key: &str, shift: bool) -> Option<&'static str> {
+    match (key, shift) {
+        ("h", false) => Some("Left"),
+        ("j", false) => Some("Down"),
+        ("k", false) => Some("Up"),
+        ("l", false) => Some("Right"),
+        ("w", false) => Some("NextWord"),
+        ("b", false) => Some("PreviousWord"),
+        ("d0", false) => Some("Home"),
+        ("d4", true) => Some("End"),
+        ("leftbracket", true) => Some("BigUp"),
+        ("rightbracket", true) => Some("BigDown"),
+        ("g", true) => Some("Bottom"),
+        _ => None,
+    }
+}
+
+// The digit of a number key, e.g. 5 for `d5`.
+pub fn digit(key: &str) -> Option<usize> {
+    let digit = key.strip_prefix('d')?;
+    (digit.len() == 1).then(|| digit.parse().ok()).flatten()
+}
+
+// The actions a name stands for, repeated, e.g. `NavDown | NavDown`.
+fn repeat(name: &str, count: usize) -> String {
+    vec![name; count].join(" | ")
+}
+
+// What a key typed in modal mode did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModalStep {
+    // The command needs more keys, like after `5` or `d`.
+    Pending,
+    // A complete command, as semantic actions, e.g. `SelectNextWord | Copy`.
+    Run(String),
+    // Leaves modal mode, after running the actions of a command such as `cw`.
+    Leave(Option<String>),
+    // The key completes no command, and what was typed so far is dropped.
+    Cancelled,
+}
+
+// Vim-like commands typed one key at a time: an optional count, an optional operator such as
+// `d`, `y` or `c`, and a motion such as `w`, `$` or `}`. Motions alone navigate, with an operator
+// they select and then delete, copy or cut the selection. Doubling the operator, as in `3dd`,
+// applies it to whole lines.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModalInput {
+    count: Option<usize>,
+    operator: Option<Operator>,
+    // The count typed before the operator, as in `2d3w`.
+    operator_count: Option<usize>,
+    // The first `g` of `gg`.
+    g: bool,
+}
+
+impl ModalInput {
+    pub fn is_pending(&self) -> bool {
+        *self != ModalInput::default()
+    }
+
+    pub fn clear(&mut self) {
+        *self = ModalInput::default();
+    }
+
+    // Feeds the next key, by its key name and whether shift is held.
+    pub fn key(&mut self, key: &str, shift: bool) -> ModalStep {
+        if let (Some(digit), false) = (digit(key), shift) {
+            // A leading zero is the motion to the start of the line.
+            if digit != 0 || self.count.is_some() {
+                let count = self.count.unwrap_or(0) * 10 + digit;
+                self.count = Some(count.min(MAX_COUNT));
+                return ModalStep::Pending;
+            }
+        }
+
+        if key == "g" && !shift && !self.g {
+            self.g = true;
+            return ModalStep::Pending;
+        }
+        let motion = if std::mem::take(&mut self.g) {
+            match (key, shift) {
+                ("g", false) => Some("Top"),
+                _ => {
+                    self.clear();
+                    return ModalStep::Cancelled;
+                }
+            }
+        } else {
+            motion(key, shift)
+        };
+
+        let had_count = self.count.is_some();
+        let count = self.count.take().unwrap_or(1);
+        let step = match (self.operator, motion) {
+            (None, Some(motion)) => ModalStep::Run(repeat(&format!("Nav{}", motion), count)),
+            (Some(operator), Some(motion)) => {
+                let count = (count * self.operator_count.unwrap_or(1)).min(MAX_COUNT);
+                let selection = repeat(&format!("Select{}", motion), count);
+                Self::apply(operator, selection)
+            }
+            (Some(operator), None) if Operator::from_key(key, shift) == Some(operator) => {
+                let count = (count * self.operator_count.unwrap_or(1)).min(MAX_COUNT);
+                let selection = format!("NavHome | {}", repeat("SelectDown", count));
+                Self::apply(operator, selection)
+            }
+            (Some(_), None) => ModalStep::Cancelled,
+            (None, None) => {
+                if let Some(operator) = Operator::from_key(key, shift) {
+                    self.operator = Some(operator);
+                    self.operator_count = Some(count);
+                    return ModalStep::Pending;
+                }
+                match (key, shift) {
+                    ("x", false) => ModalStep::Run(repeat("DeleteRight", count)),
+                    ("p", false) => ModalStep::Run(repeat("Paste", count)),
+                    ("u", false) => ModalStep::Run(repeat("Undo", count)),
+                    ("i", false) => ModalStep::Leave(None),
+                    // Escape first drops a half typed command, and then leaves.
+                    ("escape", _) if !had_count => ModalStep::Leave(None),
+                    _ => ModalStep::Cancelled,
+                }
+            }
+        };
+        self.clear();
+        step
+    }
+
+    fn apply(operator: Operator, selection: String) -> ModalStep {
+        let actions = format!("{} | {}", selection, operator.action());
+        match operator {
+            Operator::Change => ModalStep::Leave(Some(actions)),
+            Operator::Delete | Operator::Yank => ModalStep::Run(actions),
+        }
+    }
+}
+
+// What was typed of the pending command, e.g. `2d3`.
+impl fmt::Display for ModalInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(operator) = self.operator {
+            if let Some(count) = self.operator_count.filter(|count| *count != 1) {
+                write!(f, "{}", count)?;
+            }
+            write!(f, "{}", operator.key())?;
+        }
+        if let Some(count) = self.count {
+            write!(f, "{}", count)?;
+        }
+        if self.g {
+            write!(f, "g")?;
+        }
+        Ok(())
+    }
+}
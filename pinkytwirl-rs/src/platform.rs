@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::contexts::KeyEvent;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Platform {
+    MacOS,
+    Windows,
+}
+
+impl Platform {
+    pub const ALL: [Platform; 2] = [Platform::MacOS, Platform::Windows];
+
+    // The platform the engine is compiled for. Anything other than macOS uses the Ctrl based semantics.
+    pub fn current() -> Platform {
+        if cfg!(target_os = "macos") {
+            Platform::MacOS
+        } else {
+            Platform::Windows
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Platform> {
+        match name.trim().to_lowercase().as_str() {
+            "macos" | "mac" | "osx" => Some(Platform::MacOS),
+            "windows" | "win" => Some(Platform::Windows),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Platform::MacOS => "macos",
+            Platform::Windows => "windows",
+        }
+    }
+
+    pub fn semantics_file_name(&self) -> String {
+        format!("semantics_{}.txt", self.name())
+    }
+
+    // The abstract `Primary` modifier: Cmd on macOS, Ctrl elsewhere.
+    pub fn primary_modifier(&self) -> &'static str {
+        match self {
+            Platform::MacOS => "meta",
+            Platform::Windows => "ctrl",
+        }
+    }
+
+    // The abstract `Word` modifier used for word-wise navigation: Option on macOS, Ctrl elsewhere.
+    pub fn word_modifier(&self) -> &'static str {
+        match self {
+            Platform::MacOS => "alt",
+            Platform::Windows => "ctrl",
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// Maps the different spellings of a modifier onto the names used by `KeyEvent`.
+pub fn normalize_modifier_name(name: &str) -> Option<&'static str> {
+    match name.trim().to_lowercase().as_str() {
+        "shift" => Some("shift"),
+        "ctrl" | "control" => Some("ctrl"),
+        "alt" | "option" => Some("alt"),
+        "meta" | "command" | "cmd" => Some("meta"),
+        "fn" | "function" => Some("fn"),
+        _ => None,
+    }
+}
+
+fn modifier_flag(event: &KeyEvent, modifier: &str) -> bool {
+    match modifier {
+        "shift" => event.shift,
+        "ctrl" => event.ctrl,
+        "alt" => event.alt,
+        "meta" => event.meta,
+        "fn" => event.func,
+        _ => false,
+    }
+}
+
+fn set_modifier_flag(event: &mut KeyEvent, modifier: &str) {
+    match modifier {
+        "shift" => event.shift = true,
+        "ctrl" => event.ctrl = true,
+        "alt" => event.alt = true,
+        "meta" => event.meta = true,
+        "fn" => event.func = true,
+        _ => (),
+    }
+}
+
+// Rewrites the modifiers of an event through a translation table such as `meta -> ctrl`.
+// Modifiers without an entry are kept as they are, and a modifier key itself is renamed.
+pub fn translate_modifiers(event: &KeyEvent, table: &HashMap<String, String>) -> KeyEvent {
+    let mut translated = KeyEvent {
+        shift: false,
+        ctrl: false,
+        alt: false,
+        meta: false,
+        func: false,
+        ..event.clone()
+    };
+
+    for modifier in ["shift", "ctrl", "alt", "meta", "fn"] {
+        if modifier_flag(event, modifier) {
+            let target = table.get(modifier).map(String::as_str).unwrap_or(modifier);
+            set_modifier_flag(&mut translated, target);
+        }
+    }
+
+    if let Some(target) = normalize_modifier_name(&event.key).and_then(|m| table.get(m)) {
+        translated.key = target.clone();
+    }
+
+    translated
+}
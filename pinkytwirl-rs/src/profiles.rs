@@ -0,0 +1,103 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use crate::logging::log;
+use crate::sources::{yaml_error_at, ConfigLayer};
+
+// Profiles are defined in this file of any config layer, e.g.
+//
+//     Work:
+//       layers: [work]
+//     Pairing:
+//       contexts: [Default, Terminal]
+pub const PROFILES_FILE: &str = "profiles.txt";
+
+// Remembers the active profile between runs, next to the config.
+pub const ACTIVE_PROFILE_FILE: &str = "active_profile.txt";
+
+#[derive(Debug, Deserialize)]
+struct YamlProfile {
+    #[serde(default)]
+    layers: Vec<String>,
+    #[serde(default)]
+    contexts: Option<Vec<String>>,
+}
+
+pub type Profiles = BTreeMap<String, Profile>;
+
+// A named variant of the config that can be switched to at runtime.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    // Config directories loaded after the base layers, relative to the `profiles.txt` that
+    // defines the profile.
+    pub layers: Vec<ConfigLayer>,
+    // When set, only these contexts keep their mappings.
+    pub contexts: Option<Vec<String>>,
+}
+
+// Reads the profiles of every layer. A profile defined again in a later layer replaces the
+// earlier definition. Also returns the files the profiles were read from.
+pub fn load_profiles(layers: &[ConfigLayer]) -> Result<(Profiles, Vec<PathBuf>), Box<dyn Error>> {
+    let mut profiles = BTreeMap::new();
+    let mut files = Vec::new();
+    for layer in layers {
+        if !layer.has_file(PROFILES_FILE) {
+            continue;
+        }
+        log!(Debug, Config, "Loading {} from: {}", PROFILES_FILE, layer);
+        let lines = layer.read_lines(PROFILES_FILE)?;
+        files.extend(lines.iter().map(|line| line.file.clone()));
+
+        let yaml_str = lines
+            .iter()
+            .map(|line| line.text.as_str())
+            .collect::<Vec<&str>>()
+            .join("\n");
+        let yaml_profiles: Option<BTreeMap<String, YamlProfile>> =
+            serde_yaml::from_str(&yaml_str).map_err(|e| yaml_error_at(&lines, e))?;
+
+        let file = match layer {
+            ConfigLayer::Directory(path) => path.join(PROFILES_FILE),
+            ConfigLayer::Memory { name, .. } => Path::new(name).join(PROFILES_FILE),
+        };
+        let directory = file.parent().unwrap_or(Path::new("")).to_path_buf();
+        for (name, yaml_profile) in yaml_profiles.unwrap_or_default() {
+            let profile = Profile {
+                name: name.clone(),
+                layers: yaml_profile
+                    .layers
+                    .iter()
+                    .map(|layer| ConfigLayer::Directory(directory.join(layer)))
+                    .collect(),
+                contexts: yaml_profile.contexts,
+            };
+            profiles.insert(name, profile);
+        }
+    }
+    files.sort();
+    files.dedup();
+    Ok((profiles, files))
+}
+
+// Reads the profile that was active when the engine last ran, if any.
+pub fn read_active_profile(file: &Path) -> Option<String> {
+    let profile = std::fs::read_to_string(file).ok()?;
+    let profile = profile.trim();
+    (!profile.is_empty()).then(|| profile.to_string())
+}
+
+// Remembers the active profile, or forgets it when switching back to the base config.
+pub fn write_active_profile(file: &Path, profile: Option<&str>) -> Result<(), Box<dyn Error>> {
+    match profile {
+        Some(profile) => std::fs::write(file, format!("{}\n", profile))
+            .map_err(|e| format!("Cannot write {:?}: {}", file, e))?,
+        None if file.exists() => {
+            std::fs::remove_file(file).map_err(|e| format!("Cannot remove {:?}: {}", file, e))?
+        }
+        None => (),
+    }
+    Ok(())
+}
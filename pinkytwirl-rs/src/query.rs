@@ -0,0 +1,362 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::contexts::{Context, KeyEvent, SemanticAction};
+use crate::engine::PinkyTwirlEngine;
+use crate::formatter::{action_names, display_chord, key_event_to_string};
+use crate::platform::{translate_modifiers, Platform};
+use crate::sources::Origin;
+
+// One step in resolving a semantic action. Named actions list the context they were found in,
+// and resolve to their definition as the only child.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SemanticNode {
+    pub label: String,
+    // The context a named action was defined in, or `None` for everything else.
+    pub context: Option<String>,
+    pub children: Vec<SemanticNode>,
+    // Whether this is a named action that no context defines.
+    pub undefined: bool,
+}
+
+impl SemanticNode {
+    fn is_action(&self) -> bool {
+        self.context.is_some() || self.undefined
+    }
+
+    // The labels from this node down to the first named action called `action_name`.
+    fn path_to(&self, action_name: &str) -> Option<Vec<String>> {
+        if self.is_action() && self.label.eq_ignore_ascii_case(action_name) {
+            return Some(vec![self.label.clone()]);
+        }
+        self.children.iter().find_map(|child| {
+            let mut path = child.path_to(action_name)?;
+            if self.is_action() {
+                path.insert(0, self.label.clone());
+            }
+            Some(path)
+        })
+    }
+
+    fn write_indented(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        write!(f, "{}{}", "  ".repeat(depth), self.label)?;
+        match &self.context {
+            Some(context) => writeln!(f, " ({})", context)?,
+            None if self.undefined => writeln!(f, " (undefined)")?,
+            None => writeln!(f)?,
+        }
+        for child in &self.children {
+            child.write_indented(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SemanticNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_indented(f, 0)
+    }
+}
+
+// The mapping a chord matched, in the context that defines it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChordMapping {
+    pub context: String,
+    pub action: String,
+    pub origin: Option<Origin>,
+}
+
+// Everything the engine does with a chord in a context, as answered by `query_chord`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChordQuery {
+    pub context: String,
+    pub chord: String,
+    pub mapping: Option<ChordMapping>,
+    pub is_stem: bool,
+    pub semantic_tree: Option<SemanticNode>,
+    // The synthetic key events, after the context's modifier translation.
+    pub events: Vec<KeyEvent>,
+    event_names: Vec<String>,
+}
+
+impl fmt::Display for ChordQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Context: {}", self.context)?;
+        writeln!(f, "Chord: {}", self.chord)?;
+        match &self.mapping {
+            Some(mapping) => {
+                if self.is_stem {
+                    write!(
+                        f,
+                        "Mapping: {} is a stem in {}",
+                        self.chord, mapping.context
+                    )?;
+                } else {
+                    write!(
+                        f,
+                        "Mapping: {} = {} in {}",
+                        self.chord, mapping.action, mapping.context
+                    )?;
+                }
+                match &mapping.origin {
+                    Some(origin) => {
+                        writeln!(f, " ({}:{})", origin.file.display(), origin.line_number)?
+                    }
+                    None => writeln!(f)?,
+                }
+            }
+            None => writeln!(f, "Mapping: none, the chord is passed through")?,
+        }
+        if let Some(tree) = &self.semantic_tree {
+            writeln!(f, "Semantics:")?;
+            tree.write_indented(f, 1)?;
+        }
+        if self.event_names.is_empty() {
+            writeln!(f, "Events: none")
+        } else {
+            writeln!(f, "Events: {}", self.event_names.join(", "))
+        }
+    }
+}
+
+// A chord that reaches an action, as found by `chords_for_action`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActionChord {
+    pub chord: String,
+    // The context that maps the chord.
+    pub context: String,
+    // The named actions from the chord's own action down to the one asked for.
+    pub path: Vec<String>,
+}
+
+impl fmt::Display for ActionChord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}): {}",
+            self.chord,
+            self.context,
+            self.path.join(" -> ")
+        )
+    }
+}
+
+// Chords are typed like `Meta + J`, and stored like `meta + j`.
+pub(crate) fn normalize_chord(chord: &str) -> String {
+    chord
+        .split('+')
+        .map(|key| key.trim().to_lowercase())
+        .collect::<Vec<String>>()
+        .join(" + ")
+}
+
+impl PinkyTwirlEngine {
+    // Answers what the engine does with a chord in the context of an application, without
+    // changing any state. Returns `None` when no context matches the application.
+    pub fn query_chord(
+        &self,
+        app_name: &str,
+        window_name: &str,
+        chord: &str,
+    ) -> Option<ChordQuery> {
+        let context = self.get_context(app_name, window_name)?;
+        let names = action_names(self.contexts().values());
+        let chord = normalize_chord(chord);
+
+        let mut query = ChordQuery {
+            context: context.name.clone(),
+            chord: display_chord(&chord, self.keycodes()),
+            mapping: None,
+            is_stem: false,
+            semantic_tree: None,
+            events: Vec::new(),
+            event_names: Vec::new(),
+        };
+
+        let Some((mapping_context, action)) = self.find_chord_mapping(context, &chord) else {
+            return Some(query);
+        };
+        query.mapping = Some(ChordMapping {
+            context: mapping_context.name.clone(),
+            action: crate::formatter::semantic_action_to_string(&action, self.keycodes(), &names),
+            origin: mapping_context.mapping_origins.get(&chord).cloned(),
+        });
+        query.is_stem = action == SemanticAction::Action("MappingStem".to_string());
+        if query.is_stem || action == SemanticAction::Passthrough {
+            return Some(query);
+        }
+
+        // Actions resolve from the application's context, like `handle_key_event` does.
+        let platform = self.context_semantics_platform(context);
+        query.semantic_tree =
+            Some(self.explain_semantic_action(&action, context, platform, &names));
+        let mut events = self.resolve_semantic_action(&action, context, platform);
+        if let Some(table) = self.context_modifier_translation(context) {
+            events = events
+                .iter()
+                .map(|event| translate_modifiers(event, table))
+                .collect();
+        }
+        query.event_names = events
+            .iter()
+            .map(|event| key_event_to_string(event, self.keycodes()))
+            .collect();
+        query.events = events;
+        Some(query)
+    }
+
+    // Lists every chord that reaches the named action in the context of an application, directly
+    // or through other actions, sorted by chord. Chords a child context maps again are left out.
+    pub fn chords_for_action(
+        &self,
+        action_name: &str,
+        app_name: &str,
+        window_name: &str,
+    ) -> Vec<ActionChord> {
+        let Some(context) = self.get_context(app_name, window_name) else {
+            return Vec::new();
+        };
+        let names = action_names(self.contexts().values());
+        let platform = self.context_semantics_platform(context);
+
+        let mut seen = HashSet::new();
+        let mut chords = Vec::new();
+        let mut current_context = Some(context);
+        while let Some(mapping_context) = current_context {
+            if mapping_context.passthrough_all {
+                break;
+            }
+            for (chord, action) in &mapping_context.key_mappings {
+                if !seen.insert(chord) {
+                    continue;
+                }
+                let tree = self.explain_semantic_action(action, context, platform, &names);
+                if let Some(path) = tree.path_to(action_name) {
+                    chords.push(ActionChord {
+                        chord: display_chord(chord, self.keycodes()),
+                        context: mapping_context.name.clone(),
+                        path,
+                    });
+                }
+            }
+            current_context = mapping_context
+                .parent
+                .as_ref()
+                .and_then(|parent| self.contexts().get(parent));
+        }
+
+        chords.sort_by(|a, b| a.chord.cmp(&b.chord));
+        chords
+    }
+
+    // Every chord that does something in the focused context, with the action it's mapped to.
+    // Chords a context overrides are listed once, for the context that wins.
+    pub fn help_chords(&self, app_name: &str, window_name: &str) -> Vec<ActionChord> {
+        let Some(context) = self.get_context(app_name, window_name) else {
+            return Vec::new();
+        };
+        let names = action_names(self.contexts().values());
+
+        let mut seen = HashSet::new();
+        let mut chords = Vec::new();
+        let mut current_context = Some(context);
+        while let Some(mapping_context) = current_context {
+            if mapping_context.passthrough_all {
+                break;
+            }
+            for (chord, action) in &mapping_context.key_mappings {
+                let is_stem = *action == SemanticAction::Action("MappingStem".to_string());
+                if !seen.insert(chord) || is_stem || *action == SemanticAction::Passthrough {
+                    continue;
+                }
+                chords.push(ActionChord {
+                    chord: display_chord(chord, self.keycodes()),
+                    context: mapping_context.name.clone(),
+                    path: vec![crate::formatter::semantic_action_to_string(
+                        action,
+                        self.keycodes(),
+                        &names,
+                    )],
+                });
+            }
+            current_context = mapping_context
+                .parent
+                .as_ref()
+                .and_then(|parent| self.contexts().get(parent));
+        }
+
+        chords.sort_by(|a, b| a.chord.cmp(&b.chord));
+        chords
+    }
+
+    // The help `Engine.ShowHelp` shows, one chord per line.
+    pub fn help_text(&self, app_name: &str, window_name: &str) -> String {
+        self.help_chords(app_name, window_name)
+            .iter()
+            .map(|chord| format!("{}\n", chord))
+            .collect()
+    }
+
+    // Mirrors `resolve_semantic_action`, but keeps every step instead of only the key events.
+    fn explain_semantic_action(
+        &self,
+        action: &SemanticAction,
+        context: &Context,
+        platform: Platform,
+        names: &HashMap<String, String>,
+    ) -> SemanticNode {
+        let leaf = |label: String| SemanticNode {
+            label,
+            context: None,
+            children: Vec::new(),
+            undefined: false,
+        };
+        match action {
+            SemanticAction::Sequence(actions) => SemanticNode {
+                children: actions
+                    .iter()
+                    .map(|action| self.explain_semantic_action(action, context, platform, names))
+                    .collect(),
+                ..leaf("Sequence".to_string())
+            },
+            SemanticAction::Action(action_name) => {
+                let label = names
+                    .get(&action_name.to_lowercase())
+                    .cloned()
+                    .unwrap_or_else(|| action_name.clone());
+                let mut current_context = Some(context);
+                while let Some(defining_context) = current_context {
+                    if let Some(definition) = self
+                        .semantic_actions_for(defining_context, platform)
+                        .and_then(|actions| actions.get(action_name))
+                    {
+                        let child = self.explain_semantic_action(
+                            definition,
+                            defining_context,
+                            platform,
+                            names,
+                        );
+                        return SemanticNode {
+                            label,
+                            context: Some(defining_context.name.clone()),
+                            children: vec![child],
+                            undefined: false,
+                        };
+                    }
+                    current_context = defining_context
+                        .parent
+                        .as_ref()
+                        .and_then(|parent| self.contexts().get(parent));
+                }
+                SemanticNode {
+                    undefined: true,
+                    ..leaf(label)
+                }
+            }
+            SemanticAction::KeyEvent(event) => leaf(key_event_to_string(event, self.keycodes())),
+            SemanticAction::LiteralString(s) => leaf(format!("\"{}\"", s)),
+            SemanticAction::Passthrough => leaf("Passthrough".to_string()),
+            SemanticAction::Engine(command) => leaf(command.to_string()),
+        }
+    }
+}
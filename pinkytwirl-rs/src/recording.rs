@@ -0,0 +1,194 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use crate::contexts::{KeyEvent, KeyState};
+use crate::engine::PinkyTwirlEngine;
+use crate::golden::{describe_result, normalize, CaseFailure, CaseReport};
+use crate::keycode_macos::KeyCodeLookup;
+
+// A recording is a text file with one tab separated line per key event, preceded by a focus
+// line whenever the focused window changes:
+//
+//     pinkytwirl-recording 1
+//     focus	Visual Studio Code	main.rs
+//     0	down	meta	55	meta	suppress
+//     84	down	j	38	meta	suppress: Left
+//
+// Events list the milliseconds since the recording started, the state, key, key code, the
+// modifier flags (or `-`), and the result in the syntax of the case files. Secure input is
+// marked by `secure-input on` and `off` lines, with no events in between.
+const RECORDING_HEADER: &str = "pinkytwirl-recording 1";
+
+const MODIFIER_FLAGS: [&str; 5] = ["shift", "ctrl", "alt", "meta", "fn"];
+
+fn modifier_flags(event: &KeyEvent) -> String {
+    let flags: Vec<&str> = MODIFIER_FLAGS
+        .iter()
+        .zip([event.shift, event.ctrl, event.alt, event.meta, event.func])
+        .filter(|(_, set)| *set)
+        .map(|(flag, _)| *flag)
+        .collect();
+    if flags.is_empty() {
+        "-".to_string()
+    } else {
+        flags.join("+")
+    }
+}
+
+// Tabs and line breaks would end a field, so they are written as spaces.
+fn field(text: &str) -> String {
+    text.replace(['\t', '\n', '\r'], " ")
+}
+
+// Writes the events the engine handles, see `PinkyTwirlEngine::start_recording`.
+pub(crate) struct Recorder {
+    writer: BufWriter<File>,
+    started: Instant,
+    focus: Option<(String, String)>,
+}
+
+impl Recorder {
+    pub(crate) fn create(path: &Path) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "{}", RECORDING_HEADER)?;
+        Ok(Recorder {
+            writer,
+            started: Instant::now(),
+            focus: None,
+        })
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        event: &KeyEvent,
+        app_name: &str,
+        window_name: &str,
+        suppress: bool,
+        output: &[KeyEvent],
+        keycodes: &KeyCodeLookup,
+    ) -> io::Result<()> {
+        let focus = (app_name.to_string(), window_name.to_string());
+        if self.focus.as_ref() != Some(&focus) {
+            writeln!(
+                self.writer,
+                "focus\t{}\t{}",
+                field(app_name),
+                field(window_name)
+            )?;
+            self.focus = Some(focus);
+        }
+        let state = match event.state {
+            KeyState::Down => "down",
+            KeyState::Up => "up",
+            KeyState::DownUp => "down-up",
+        };
+        writeln!(
+            self.writer,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            self.started.elapsed().as_millis(),
+            state,
+            field(&event.key),
+            event.code,
+            modifier_flags(event),
+            describe_result(suppress, output, keycodes)
+        )
+    }
+
+    // Events are not recorded during secure input, only when it starts and ends.
+    pub(crate) fn record_secure_input(&mut self, enabled: bool) -> io::Result<()> {
+        writeln!(
+            self.writer,
+            "secure-input\t{}",
+            if enabled { "on" } else { "off" }
+        )?;
+        self.writer.flush()
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Feeds a recording through the engine and compares each result with the recorded one. The
+// report counts the recording as a single case.
+pub fn replay_recording(
+    engine: &mut PinkyTwirlEngine,
+    file: &Path,
+    text: &str,
+) -> Result<CaseReport, Box<dyn Error>> {
+    let mut lines = text.lines().enumerate();
+    if lines.next().map(|(_, line)| line.trim()) != Some(RECORDING_HEADER) {
+        return Err(format!("{} is not a pinkytwirl recording", file.display()).into());
+    }
+
+    let mut report = CaseReport {
+        cases: 1,
+        ..CaseReport::default()
+    };
+    let mut app_name = String::new();
+    let mut window_name = String::new();
+    for (index, line) in lines {
+        let line_number = index + 1;
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields.as_slice() {
+            [""] => (),
+            ["secure-input", state] => engine.set_secure_input(*state == "on"),
+            ["focus", app, window] => {
+                app_name = app.to_string();
+                window_name = window.to_string();
+            }
+            [_time, state, key, code, flags, expected] => {
+                let state = match *state {
+                    "down" => KeyState::Down,
+                    "up" => KeyState::Up,
+                    "down-up" => KeyState::DownUp,
+                    _ => {
+                        return Err(format!(
+                            "{}:{}: Unknown key state",
+                            file.display(),
+                            line_number
+                        )
+                        .into())
+                    }
+                };
+                let flags: Vec<&str> = flags.split('+').collect();
+                let event = KeyEvent {
+                    key: key.to_string(),
+                    code: code.parse().unwrap_or(0),
+                    state,
+                    shift: flags.contains(&"shift"),
+                    ctrl: flags.contains(&"ctrl"),
+                    alt: flags.contains(&"alt"),
+                    meta: flags.contains(&"meta"),
+                    func: flags.contains(&"fn"),
+                    modifier_down_only: false,
+                };
+                let step = format!("{} {}", fields[1], key);
+                let (suppress, output) = engine.handle_key_event(event, &app_name, &window_name);
+                let actual = describe_result(suppress, &output, engine.keycodes());
+                report.steps += 1;
+                if normalize(expected) != normalize(&actual) {
+                    report.failures.push(CaseFailure {
+                        file: file.to_path_buf(),
+                        line_number,
+                        step,
+                        expected: expected.to_string(),
+                        actual,
+                    });
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "{}:{}: Malformed recording line",
+                    file.display(),
+                    line_number
+                )
+                .into())
+            }
+        }
+    }
+    Ok(report)
+}
@@ -1,18 +1,30 @@
 use crate::contexts::{parse_semantic_action, Context};
 use crate::keycode_macos::KeyCodeLookup;
+use crate::platform::Platform;
+use crate::sources::{Origin, SourceLine};
 use std::collections::HashMap;
-use std::fs;
 use std::path::Path;
 
-pub fn parse_semantics_file(
-    file_path: &Path,
+pub fn parse_semantics_lines(
+    lines: &[SourceLine],
     contexts: &mut HashMap<String, Context>,
     keycodes: &KeyCodeLookup,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let content = fs::read_to_string(file_path)?;
+    platform: Platform,
+    foreign: bool,
+    layer: usize,
+    diagnostics: &mut Vec<String>,
+) {
     let mut current_context: Option<&mut Context> = None;
+    let mut current_file: Option<&Path> = None;
 
-    for line in content.lines() {
+    for source_line in lines {
+        // A context block never continues across an included file.
+        if current_file != Some(source_line.file.as_path()) {
+            current_file = Some(source_line.file.as_path());
+            current_context = None;
+        }
+
+        let line = source_line.text.as_str();
         let trimmed_line = line.trim();
         if trimmed_line.is_empty() || trimmed_line.starts_with("//") {
             continue;
@@ -23,23 +35,41 @@ pub fn parse_semantics_file(
             let context_name = trimmed_line.trim_end_matches(':');
             current_context = contexts.get_mut(context_name);
             if current_context.is_none() {
-                println!("Warning: Context '{}' not found. Skipping.", context_name);
+                diagnostics.push(format!(
+                    "{}: Context '{}' not found. Skipping.",
+                    source_line.location(),
+                    context_name
+                ));
             }
         } else if let Some(context) = &mut current_context {
             // This is a semantic action
             if let Some((key, value)) = trimmed_line.split_once('=') {
                 let action_name = key.trim().to_string();
                 let action_definition = value.trim().to_string();
-                let semantic_action = parse_semantic_action(&action_definition, keycodes);
-                context
-                    .semantic_actions
-                    .insert(action_name.clone(), semantic_action.clone());
-                context
-                    .semantic_actions
-                    .insert(action_name.to_lowercase(), semantic_action);
+                let semantic_action = parse_semantic_action(&action_definition, keycodes, platform);
+                // Unbinding only means something for a whole chord, see `mappings.rs`.
+                if semantic_action.contains_passthrough() {
+                    diagnostics.push(format!(
+                        "{}: Passthrough can only unbind a chord in mappings, not define '{}'. Skipping.",
+                        source_line.location(),
+                        action_name
+                    ));
+                    continue;
+                }
+                // Semantics of another platform are kept apart from the engine's own.
+                let semantic_actions = if foreign {
+                    context.foreign_semantic_actions.entry(platform).or_default()
+                } else {
+                    &mut context.semantic_actions
+                };
+                semantic_actions.insert(action_name.clone(), semantic_action.clone());
+                semantic_actions.insert(action_name.to_lowercase(), semantic_action);
+
+                let origin = Origin::new(layer, source_line);
+                let semantic_origins = context.semantic_origins.entry(platform).or_default();
+                semantic_origins.insert(action_name.clone(), origin.clone());
+                semantic_origins.insert(action_name.to_lowercase(), origin);
             }
         }
     }
-
-    Ok(())
 }
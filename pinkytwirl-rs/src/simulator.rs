@@ -0,0 +1,291 @@
+use crate::contexts::{KeyEvent, KeyState};
+use crate::engine::PinkyTwirlEngine;
+use crate::formatter::key_event_to_string;
+use crate::keycode_macos::KeyCodeLookup;
+use crate::platform::normalize_modifier_name;
+
+pub const SIMULATOR_HELP: &str = "Commands:
+  down <key>                 Press a key, e.g. `down meta`
+  up <key>                   Release a key
+  tap <key>                  Press and release a key
+  focus <app> [<window>]     Switch the focused application, e.g. `focus \"Chrome\" \"Gmail\"`
+  secure on|off              Start or end secure input, as in a password field
+  enabled on|off             Enable or disable the engine
+  reset                      Release every key, as after the host lost track of the keyboard
+  profile [<name>]           Switch to a profile, or back to the base config without a name
+  capslock on|off            Report whether Caps Lock is on, as the host does
+  modal on|off               Enter or leave the vim-like modal mode
+  layer <stem>|off           Toggle the locked layer of a stem, e.g. `layer D4`, or turn it off
+  state                      Show the context, pressed keys and chord state
+  help                       Show this help";
+
+// Drives the engine from typed commands, the way the host's event tap would, and describes
+// what the engine did after each step.
+pub struct Simulator {
+    pub engine: PinkyTwirlEngine,
+    app_name: String,
+    window_name: String,
+    held_keys: HeldKeys,
+}
+
+// The keys held down, as the host would report them in the modifier flags of each event.
+#[derive(Default)]
+pub(crate) struct HeldKeys {
+    keys: Vec<String>,
+}
+
+impl HeldKeys {
+    // Makes the event for a typed key, e.g. `cmd` or `PageUp`, with the name and code the
+    // host's key code would map to.
+    pub(crate) fn event(
+        &mut self,
+        keycodes: &KeyCodeLookup,
+        key: &str,
+        state: KeyState,
+    ) -> Result<KeyEvent, String> {
+        let key = normalize_modifier_name(key)
+            .map(str::to_string)
+            .unwrap_or_else(|| key.to_lowercase());
+        let code = *keycodes
+            .name_to_keycode
+            .get(&key)
+            .ok_or_else(|| format!("Unknown key: {}", key))?;
+        let key = keycodes.keycode_to_name.get(&code).cloned().unwrap_or(key);
+
+        self.keys.retain(|held| *held != key);
+        if state == KeyState::Down {
+            self.keys.push(key.clone());
+        }
+        let held = |modifier: &str| self.keys.iter().any(|held| held == modifier);
+        Ok(KeyEvent {
+            key: key.clone(),
+            code,
+            shift: held("shift"),
+            ctrl: held("ctrl"),
+            alt: held("alt"),
+            meta: held("meta"),
+            func: held("fn"),
+            state,
+            modifier_down_only: false,
+        })
+    }
+}
+
+// Splits a command into words, keeping double quoted text such as `"Visual Studio Code"` together.
+pub(crate) fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        let mut word = String::new();
+        if c == '"' {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => word.push(c),
+                    None => return Err(format!("Unterminated quote in: {}", line)),
+                }
+            }
+        } else {
+            word.push(c);
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                word.push(c);
+            }
+        }
+        words.push(word);
+    }
+    Ok(words)
+}
+
+pub(crate) fn state_name(state: &KeyState) -> &'static str {
+    match state {
+        KeyState::Down => "down",
+        KeyState::Up => "up",
+        KeyState::DownUp => "down-up",
+    }
+}
+
+impl Simulator {
+    pub fn new(engine: PinkyTwirlEngine) -> Self {
+        Simulator {
+            engine,
+            app_name: String::new(),
+            window_name: String::new(),
+            held_keys: HeldKeys::default(),
+        }
+    }
+
+    // Runs a single command and returns its description. Empty lines and `//` comments do nothing.
+    pub fn run_command(&mut self, line: &str) -> Result<String, String> {
+        let words = split_words(line)?;
+        let Some(command) = words.first() else {
+            return Ok(String::new());
+        };
+        match (command.as_str(), &words[1..]) {
+            (command, _) if command.starts_with("//") => Ok(String::new()),
+            ("down", [key]) => self.key_event(key, KeyState::Down),
+            ("up", [key]) => self.key_event(key, KeyState::Up),
+            ("tap", [key]) => {
+                let down = self.key_event(key, KeyState::Down)?;
+                let up = self.key_event(key, KeyState::Up)?;
+                Ok(format!("{}{}", down, up))
+            }
+            ("focus", [app_name]) | ("focus", [app_name, _]) => {
+                self.app_name = app_name.clone();
+                self.window_name = words.get(2).cloned().unwrap_or_default();
+                Ok(self.state())
+            }
+            ("secure", [state]) if state == "on" || state == "off" => {
+                self.engine.set_secure_input(state == "on");
+                let output = self.output();
+                Ok(format!("{}  output: {}\n", self.state(), output))
+            }
+            ("enabled", [state]) if state == "on" || state == "off" => {
+                self.engine.set_enabled(state == "on");
+                let output = self.output();
+                Ok(format!("{}  output: {}\n", self.state(), output))
+            }
+            ("reset", []) => {
+                self.engine.reset_all();
+                self.held_keys = HeldKeys::default();
+                let output = self.output();
+                Ok(format!("{}  output: {}\n", self.state(), output))
+            }
+            ("profile", []) | ("profile", [_]) => {
+                let profile = words.get(1).map_or("", String::as_str);
+                self.engine
+                    .switch_profile(profile)
+                    .map_err(|e| e.to_string())?;
+                Ok(self.state())
+            }
+            ("capslock", [state]) if state == "on" || state == "off" => {
+                self.engine.set_caps_lock(state == "on");
+                Ok(self.state())
+            }
+            ("modal", [state]) if state == "on" || state == "off" => {
+                self.engine.set_modal(state == "on");
+                Ok(self.state())
+            }
+            ("layer", [stem]) => {
+                if stem == "off" {
+                    self.engine.clear_layer();
+                } else {
+                    self.engine.toggle_layer(stem).map_err(|e| e.to_string())?;
+                }
+                Ok(self.state())
+            }
+            ("state", []) => Ok(self.state()),
+            ("help", []) => Ok(format!("{}\n", SIMULATOR_HELP)),
+            _ => Err(format!(
+                "Unknown command: {}\n{}",
+                line.trim(),
+                SIMULATOR_HELP
+            )),
+        }
+    }
+
+    fn key_event(&mut self, key: &str, state: KeyState) -> Result<String, String> {
+        let down = state == KeyState::Down;
+        let event = self.held_keys.event(self.engine.keycodes(), key, state)?;
+        let key = event.key.clone();
+
+        let suppress = self
+            .engine
+            .handle_host_key_event(event, &self.app_name, &self.window_name);
+        let output = self.output();
+
+        let decision = if suppress {
+            "suppressed"
+        } else {
+            "passed through"
+        };
+        let mut description = format!(
+            "{} {}: {}\n",
+            if down { "down" } else { "up" },
+            key,
+            decision
+        );
+        description.push_str(&self.state());
+        description.push_str(&format!("  output: {}\n", output));
+        if let Some(on) = self.engine.take_caps_lock_restore() {
+            let on = if on { "on" } else { "off" };
+            description.push_str(&format!("  caps lock: restored {}\n", on));
+        }
+        if let Some(help) = self.engine.take_help() {
+            description.push_str("  help:\n");
+            for line in help.lines() {
+                description.push_str(&format!("    {}\n", line));
+            }
+        }
+        Ok(description)
+    }
+
+    // The synthetic events the host would post now, normalised into down and up events.
+    fn output(&mut self) -> String {
+        let output: Vec<String> = self
+            .engine
+            .get_synthetic_events()
+            .iter()
+            .map(|event| {
+                format!(
+                    "{} {}",
+                    key_event_to_string(event, self.engine.keycodes()),
+                    state_name(&event.state)
+                )
+            })
+            .collect();
+        if output.is_empty() {
+            "none".to_string()
+        } else {
+            output.join(", ")
+        }
+    }
+
+    fn state(&self) -> String {
+        let mut context = self
+            .engine
+            .get_context(&self.app_name, &self.window_name)
+            .map_or("none".to_string(), |context| context.name.clone());
+        if let Some(profile) = self.engine.active_profile() {
+            context.push_str(&format!(" (profile {})", profile));
+        }
+        let pressed_keys: Vec<&str> = self
+            .engine
+            .pressed_keys
+            .iter()
+            .map(|key| key.key.as_str())
+            .collect();
+        let mut state = self.engine.state().to_string();
+        if !self.engine.is_enabled() {
+            state.push_str(" (disabled)");
+        }
+        if self.engine.is_secure_input() {
+            state.push_str(" (secure input)");
+        }
+        if self.engine.is_caps_lock_on() {
+            state.push_str(" (caps lock)");
+        }
+        if let Some(layer) = self.engine.active_layer() {
+            state.push_str(&format!(", layer {}", layer));
+        }
+        if let Some(modal) = self.engine.modal_input() {
+            state.push_str(format!(", modal {}", modal).trim_end());
+        }
+
+        let none_if_empty = |items: Vec<&str>| {
+            if items.is_empty() {
+                "none".to_string()
+            } else {
+                items.join(", ")
+            }
+        };
+        format!(
+            "  context: {}\n  pressed keys: {}\n  state: {}\n",
+            context,
+            none_if_empty(pressed_keys),
+            state
+        )
+    }
+}
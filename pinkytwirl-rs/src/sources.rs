@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// A single line of a config file, after `include` directives have been expanded.
+#[derive(Debug, Clone)]
+pub struct SourceLine {
+    pub file: PathBuf,
+    pub line_number: usize,
+    pub text: String,
+}
+
+impl SourceLine {
+    pub fn location(&self) -> String {
+        format!("{}:{}", self.file.display(), self.line_number)
+    }
+}
+
+// Where an effective definition came from: the config layer, file and line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Origin {
+    pub layer: usize,
+    pub file: PathBuf,
+    pub line_number: usize,
+}
+
+impl Origin {
+    pub fn new(layer: usize, line: &SourceLine) -> Self {
+        Origin {
+            layer,
+            file: line.file.clone(),
+            line_number: line.line_number,
+        }
+    }
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "layer {} ({}:{})",
+            self.layer,
+            self.file.display(),
+            self.line_number
+        )
+    }
+}
+
+// Reports a YAML error against the original file and line rather than the expanded text.
+pub fn yaml_error_at(lines: &[SourceLine], e: serde_yaml::Error) -> Box<dyn Error> {
+    match e.location().and_then(|location| lines.get(location.line().saturating_sub(1))) {
+        Some(line) => {
+            let message = e.to_string();
+            let message = message.split(" at line ").next().unwrap_or(&message);
+            format!("{}: {}", line.location(), message).into()
+        }
+        None => e.into(),
+    }
+}
+
+// Returns the path of an `include "file.txt"` directive. Includes are only recognised at the
+// start of a line, outside of any context block.
+pub fn parse_include(line: &str) -> Option<&str> {
+    if line.starts_with(' ') || line.starts_with('\t') {
+        return None;
+    }
+    let path = line.trim().strip_prefix("include ")?.trim();
+    Some(
+        path.strip_prefix('"')
+            .and_then(|path| path.strip_suffix('"'))
+            .unwrap_or(path),
+    )
+}
+
+// A config layer: either a directory on disk or a set of in-memory files, such as the embedded
+// defaults. Both hold the same file names, e.g. `contexts.txt` and `mappings.txt`.
+#[derive(Debug, Clone)]
+pub enum ConfigLayer {
+    Directory(PathBuf),
+    Memory {
+        name: String,
+        files: HashMap<String, String>,
+    },
+}
+
+impl ConfigLayer {
+    pub fn has_file(&self, file_name: &str) -> bool {
+        match self {
+            ConfigLayer::Directory(path) => path.join(file_name).exists(),
+            ConfigLayer::Memory { files, .. } => files.contains_key(file_name),
+        }
+    }
+
+    // Reads a config file of this layer and splices in the lines of every file it includes.
+    // Relative includes are resolved against the directory of the including file.
+    pub fn read_lines(&self, file_name: &str) -> Result<Vec<SourceLine>, Box<dyn Error>> {
+        let mut lines = Vec::new();
+        match self {
+            ConfigLayer::Directory(path) => {
+                let read = |file_path: &Path| -> Result<(PathBuf, String), String> {
+                    let canonical_path = fs::canonicalize(file_path)
+                        .map_err(|e| format!("Cannot read {:?}: {}", file_path, e))?;
+                    let content = fs::read_to_string(&canonical_path)
+                        .map_err(|e| format!("Cannot read {:?}: {}", file_path, e))?;
+                    Ok((canonical_path, content))
+                };
+                read_source_lines_into(&path.join(file_name), &read, None, &mut Vec::new(), &mut lines)?;
+            }
+            ConfigLayer::Memory { name, files } => {
+                let read = |file_path: &Path| -> Result<(PathBuf, String), String> {
+                    let relative_path = file_path.strip_prefix(name).unwrap_or(file_path);
+                    files
+                        .get(relative_path.to_string_lossy().as_ref())
+                        .map(|content| (file_path.to_path_buf(), content.clone()))
+                        .ok_or_else(|| format!("Cannot read {:?}: not found in {}", file_path, name))
+                };
+                let file_path = Path::new(name).join(file_name);
+                read_source_lines_into(&file_path, &read, None, &mut Vec::new(), &mut lines)?;
+            }
+        }
+        Ok(lines)
+    }
+}
+
+impl fmt::Display for ConfigLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigLayer::Directory(path) => write!(f, "{}", path.display()),
+            ConfigLayer::Memory { name, .. } => write!(f, "{}", name),
+        }
+    }
+}
+
+type ReadSource<'a> = dyn Fn(&Path) -> Result<(PathBuf, String), String> + 'a;
+
+fn read_source_lines_into(
+    file_path: &Path,
+    read: &ReadSource,
+    included_from: Option<&SourceLine>,
+    stack: &mut Vec<PathBuf>,
+    lines: &mut Vec<SourceLine>,
+) -> Result<(), Box<dyn Error>> {
+    let at = |message: String| -> Box<dyn Error> {
+        match included_from {
+            Some(line) => format!("{}: {}", line.location(), message).into(),
+            None => message.into(),
+        }
+    };
+
+    let (resolved_path, content) = read(file_path).map_err(at)?;
+
+    if stack.contains(&resolved_path) {
+        let cycle = stack
+            .iter()
+            .chain(std::iter::once(&resolved_path))
+            .map(|path| path.display().to_string())
+            .collect::<Vec<String>>()
+            .join(" -> ");
+        return Err(at(format!("Include cycle: {}", cycle)));
+    }
+
+    let directory = resolved_path.parent().unwrap_or(Path::new("")).to_path_buf();
+
+    stack.push(resolved_path);
+    for (index, text) in content.lines().enumerate() {
+        let line = SourceLine {
+            file: file_path.to_path_buf(),
+            line_number: index + 1,
+            text: text.to_string(),
+        };
+
+        if let Some(include) = parse_include(text) {
+            read_source_lines_into(&directory.join(include), read, Some(&line), stack, lines)?;
+        } else {
+            lines.push(line);
+        }
+    }
+    stack.pop();
+
+    Ok(())
+}
@@ -0,0 +1,149 @@
+use std::fmt;
+
+// Where the engine is in the lifecycle of a chord. Every chord starts and ends in `Idle`, and
+// the engine only moves along the edges in `TRANSITIONS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChordState {
+    // No chord is in progress and no keys are held.
+    Idle,
+    // Keys pass through untouched until they are all released, after a key that starts no chord
+    // or a chord that is mapped to `Passthrough`.
+    Passthrough,
+    // A stem such as `Meta` matched. Its keys are held back until the next key decides whether
+    // they are part of a chord, or released alone and played back.
+    StemHeld,
+    // A mapping produced synthetic keys. Further keys only apply mappings, keys that match
+    // nothing are suppressed until all keys are released.
+    MappingActive,
+    // Held back keys are being played back. The engine only passes through this state while it
+    // handles an event, and never rests in it.
+    Replaying,
+}
+
+impl ChordState {
+    pub const ALL: [ChordState; 5] = [
+        ChordState::Idle,
+        ChordState::Passthrough,
+        ChordState::StemHeld,
+        ChordState::MappingActive,
+        ChordState::Replaying,
+    ];
+}
+
+impl fmt::Display for ChordState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+// How a layer stays active without its stem being held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LayerMode {
+    // Until the layer is toggled off again, or Escape is pressed.
+    Locked,
+    // For the next chord only.
+    OneShot,
+}
+
+// A layer of `mappings.txt` that is active without its stem being held. Every chord is looked up
+// with the stem in front first, e.g. `j` as `d4 + j`, and as typed if the layer doesn't map it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActiveLayer {
+    // The stem as it is stored in the mappings, e.g. `meta + space`.
+    pub stem: String,
+    pub mode: LayerMode,
+}
+
+impl fmt::Display for ActiveLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self.mode {
+            LayerMode::Locked => "locked",
+            LayerMode::OneShot => "one-shot",
+        };
+        write!(f, "{} ({})", self.stem, mode)
+    }
+}
+
+// Every transition the engine makes, with what causes it. A state can also stay as it is, and
+// any state can be reset to `Idle`, e.g. when secure input starts.
+pub const TRANSITIONS: [(ChordState, ChordState, &str); 12] = [
+    (
+        ChordState::Idle,
+        ChordState::Passthrough,
+        "a key starts no chord, or the chord is unbound",
+    ),
+    (ChordState::Idle, ChordState::StemHeld, "a stem matches"),
+    (
+        ChordState::Idle,
+        ChordState::MappingActive,
+        "a mapping matches",
+    ),
+    (
+        ChordState::Idle,
+        ChordState::Replaying,
+        "the held keys match no chord",
+    ),
+    (
+        ChordState::StemHeld,
+        ChordState::MappingActive,
+        "a mapping matches",
+    ),
+    (
+        ChordState::StemHeld,
+        ChordState::Replaying,
+        "the stem is released alone, or the chord is unbound",
+    ),
+    (
+        ChordState::MappingActive,
+        ChordState::Passthrough,
+        "a key starts no chord, or the chord is unbound",
+    ),
+    (
+        ChordState::MappingActive,
+        ChordState::Replaying,
+        "the chord is unbound",
+    ),
+    (
+        ChordState::MappingActive,
+        ChordState::Idle,
+        "all keys or meta are released",
+    ),
+    (
+        ChordState::Passthrough,
+        ChordState::Idle,
+        "all keys or meta are released",
+    ),
+    (
+        ChordState::Replaying,
+        ChordState::Idle,
+        "the held keys are played back",
+    ),
+    (
+        ChordState::Replaying,
+        ChordState::Passthrough,
+        "the held keys of an unbound chord are played back",
+    ),
+];
+
+pub fn is_transition(from: ChordState, to: ChordState) -> bool {
+    from == to
+        || to == ChordState::Idle
+        || TRANSITIONS
+            .iter()
+            .any(|(transition_from, transition_to, _)| {
+                *transition_from == from && *transition_to == to
+            })
+}
+
+// The transitions as a Graphviz graph, for the documentation.
+pub fn transition_graph() -> String {
+    let mut graph = String::from("digraph chord_states {\n");
+    for state in ChordState::ALL {
+        graph.push_str(&format!("    {};\n", state));
+    }
+    for (from, to, cause) in TRANSITIONS {
+        graph.push_str(&format!("    {} -> {} [label=\"{}\"];\n", from, to, cause));
+    }
+    graph.push_str("}\n");
+    graph
+}
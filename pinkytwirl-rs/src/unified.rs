@@ -0,0 +1,341 @@
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::platform::Platform;
+use crate::sources::{yaml_error_at, ConfigLayer, SourceLine};
+
+// A whole config layer in a single YAML file, as an alternative to the `.txt` files:
+//
+//     contexts:
+//       Default:
+//         aliases: []
+//         parent: null
+//     semantics:
+//       shared:
+//         Default:
+//           NavLeft: Left
+//       macos:
+//         Default:
+//           Undo: Meta + Z
+//     mappings:
+//       Default:
+//         Meta:
+//           J: NavLeft
+//         F13: Undo
+//
+// A mapping whose value is a block is a stem with its chords, any other value is a chord on its
+// own. The file is loaded by translating it into the `.txt` files, so both formats behave the same.
+pub const UNIFIED_CONFIG_FILE: &str = "pinkytwirl.yaml";
+
+// The semantics that every platform loads, i.e. `semantics.txt`.
+const SHARED_SEMANTICS: &str = "shared";
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct UnifiedConfig {
+    #[serde(default)]
+    contexts: Mapping,
+    #[serde(default)]
+    semantics: Mapping,
+    #[serde(default)]
+    mappings: Mapping,
+}
+
+// The `.txt` file names in the order they are written.
+fn txt_file_names() -> Vec<String> {
+    let mut file_names = vec!["contexts.txt".to_string(), "semantics.txt".to_string()];
+    file_names.extend(Platform::ALL.iter().map(|platform| platform.semantics_file_name()));
+    file_names.push("mappings.txt".to_string());
+    file_names
+}
+
+fn semantics_file_name(section: &str) -> Option<String> {
+    if section == SHARED_SEMANTICS {
+        return Some("semantics.txt".to_string());
+    }
+    Platform::from_name(section).map(|platform| platform.semantics_file_name())
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+fn block<'a>(value: &'a Value, what: &str) -> Result<Vec<(String, &'a Value)>, String> {
+    match value {
+        Value::Null => Ok(Vec::new()),
+        Value::Mapping(mapping) => mapping
+            .iter()
+            .map(|(key, value)| {
+                scalar(key)
+                    .map(|key| (key, value))
+                    .ok_or_else(|| format!("Expected a name in {}, found {:?}", what, key))
+            })
+            .collect(),
+        _ => Err(format!("Expected a block for {}, found {:?}", what, value)),
+    }
+}
+
+// Finds the source line of each generated line, so errors and origins point into the
+// unified file. Keys are looked up in document order, starting at the section being translated.
+struct Locator<'a> {
+    lines: &'a [SourceLine],
+    cursor: usize,
+    last: usize,
+}
+
+impl<'a> Locator<'a> {
+    fn section(&mut self, name: &str) {
+        self.cursor = 0;
+        self.cursor = self.find(name, true).map_or(0, |index| index + 1);
+    }
+
+    fn find(&self, key: &str, top_level: bool) -> Option<usize> {
+        let candidates = [key.to_string(), format!("\"{}\"", key), format!("'{}'", key)];
+        (self.cursor..self.lines.len()).find(|index| {
+            let text = &self.lines[*index].text;
+            if top_level && (text.starts_with(' ') || text.starts_with('\t')) {
+                return false;
+            }
+            let trimmed = text.trim_start();
+            candidates.iter().any(|candidate| {
+                trimmed
+                    .strip_prefix(candidate.as_str())
+                    .is_some_and(|rest| rest.trim_start().starts_with(':'))
+            })
+        })
+    }
+
+    // A generated line at the location of the given key, or of the last key found.
+    fn line(&mut self, key: Option<&str>, text: String) -> SourceLine {
+        if let Some(index) = key.and_then(|key| self.find(key, false)) {
+            self.cursor = index + 1;
+            self.last = index;
+        }
+        let location = &self.lines[self.last.min(self.lines.len().saturating_sub(1))];
+        SourceLine {
+            file: location.file.clone(),
+            line_number: location.line_number,
+            text,
+        }
+    }
+}
+
+// Translates the lines of a unified config into the lines of the `.txt` files it stands for,
+// keyed by file name. Files without a section in the unified config are left out.
+pub fn unified_to_txt_lines(
+    lines: &[SourceLine],
+) -> Result<HashMap<String, Vec<SourceLine>>, Box<dyn Error>> {
+    let yaml_str = lines
+        .iter()
+        .map(|line| line.text.as_str())
+        .collect::<Vec<&str>>()
+        .join("\n");
+    let config: UnifiedConfig =
+        serde_yaml::from_str(&yaml_str).map_err(|e| yaml_error_at(lines, e))?;
+    if lines.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let mut locator = Locator {
+        lines,
+        cursor: 0,
+        last: 0,
+    };
+
+    let mut files: HashMap<String, Vec<SourceLine>> = HashMap::new();
+
+    if !config.contexts.is_empty() {
+        locator.section("contexts");
+        let contexts = files.entry("contexts.txt".to_string()).or_default();
+        for (name, context) in &config.contexts {
+            let mut single = Mapping::new();
+            single.insert(name.clone(), context.clone());
+            let name = scalar(name).ok_or("Expected a context name in contexts")?;
+            // The context serializes to the name line followed by its fields.
+            let yaml_context = serde_yaml::to_string(&single)?;
+            let mut yaml_lines = yaml_context.lines();
+            let name_line = yaml_lines.next().unwrap_or_default().to_string();
+            contexts.push(locator.line(Some(&name), name_line));
+            for text in yaml_lines {
+                let key = text.trim().split_once(':').map(|(key, _)| key);
+                contexts.push(locator.line(key, text.to_string()));
+            }
+        }
+    }
+
+    for (section, platform_semantics) in block(&Value::Mapping(config.semantics), "semantics")? {
+        let file_name = semantics_file_name(&section)
+            .ok_or_else(|| format!("Unknown semantics platform '{}'", section))?;
+        locator.section("semantics");
+        locator.line(Some(&section), String::new());
+        let semantics = files.entry(file_name).or_default();
+        for (context, actions) in block(platform_semantics, "semantics")? {
+            semantics.push(locator.line(Some(&context), format!("{}:", context)));
+            for (action, definition) in block(actions, &context)? {
+                let definition = scalar(definition).ok_or_else(|| {
+                    format!("Expected a definition for '{}' in '{}'", action, context)
+                })?;
+                let text = format!("    {} = {}", action, definition);
+                semantics.push(locator.line(Some(&action), text));
+            }
+        }
+    }
+
+    if !config.mappings.is_empty() {
+        locator.section("mappings");
+        let mappings = files.entry("mappings.txt".to_string()).or_default();
+        for (context, chords) in block(&Value::Mapping(config.mappings), "mappings")? {
+            mappings.push(locator.line(Some(&context), format!("{}:", context)));
+            let mut in_stem = false;
+            for (chord, value) in block(chords, &context)? {
+                if let Some(action) = scalar(value) {
+                    // A chord without a stem can't follow a stem block, so start the context again.
+                    if in_stem {
+                        mappings.push(locator.line(None, format!("{}:", context)));
+                        in_stem = false;
+                    }
+                    let text = format!("    {} = {}", chord, action);
+                    mappings.push(locator.line(Some(&chord), text));
+                    continue;
+                }
+
+                mappings.push(locator.line(Some(&chord), format!("    {}:", chord)));
+                in_stem = true;
+                for (key, action) in block(value, &chord)? {
+                    let action = scalar(action).ok_or_else(|| {
+                        format!("Expected an action for '{} + {}' in '{}'", chord, key, context)
+                    })?;
+                    let text = format!("        {} = {}", key, action);
+                    mappings.push(locator.line(Some(&key), text));
+                }
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+// Converts a unified config into the contents of the `.txt` files, as file name and content.
+pub fn unified_to_txt(yaml: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let lines: Vec<SourceLine> = yaml
+        .lines()
+        .enumerate()
+        .map(|(index, text)| SourceLine {
+            file: PathBuf::from(UNIFIED_CONFIG_FILE),
+            line_number: index + 1,
+            text: text.to_string(),
+        })
+        .collect();
+    let mut files = unified_to_txt_lines(&lines)?;
+
+    Ok(txt_file_names()
+        .into_iter()
+        .filter_map(|file_name| {
+            let lines = files.remove(&file_name)?;
+            let mut content = String::new();
+            for (index, line) in lines.iter().enumerate() {
+                // Separate the context blocks like the hand written files do.
+                let starts_block = !line.text.starts_with(' ') && !line.text.is_empty();
+                if index > 0 && starts_block {
+                    content.push('\n');
+                }
+                content.push_str(&line.text);
+                content.push('\n');
+            }
+            Some((file_name, content))
+        })
+        .collect())
+}
+
+// Converts the `.txt` files of a config layer into a unified config. Includes are expanded, and
+// comments are not carried over.
+pub fn txt_to_unified(layer: &ConfigLayer) -> Result<String, Box<dyn Error>> {
+    let mut config = UnifiedConfig::default();
+
+    if layer.has_file("contexts.txt") {
+        let lines = layer.read_lines("contexts.txt")?;
+        let yaml_str = lines
+            .iter()
+            .map(|line| line.text.as_str())
+            .collect::<Vec<&str>>()
+            .join("\n");
+        config.contexts = serde_yaml::from_str::<Option<Mapping>>(&yaml_str)
+            .map_err(|e| yaml_error_at(&lines, e))?
+            .unwrap_or_default();
+    }
+
+    let mut sections = vec![(SHARED_SEMANTICS, "semantics.txt".to_string())];
+    sections.extend(
+        Platform::ALL
+            .iter()
+            .map(|platform| (platform.name(), platform.semantics_file_name())),
+    );
+    for (section, file_name) in sections {
+        if layer.has_file(&file_name) {
+            let semantics = txt_blocks(&layer.read_lines(&file_name)?, false);
+            config
+                .semantics
+                .insert(Value::from(section), Value::Mapping(semantics));
+        }
+    }
+
+    if layer.has_file("mappings.txt") {
+        config.mappings = txt_blocks(&layer.read_lines("mappings.txt")?, true);
+    }
+
+    Ok(serde_yaml::to_string(&config)?)
+}
+
+// Reads the context blocks of a semantics or mappings file. Stems are only recognised in
+// mappings, where a line ending in `:` starts a block of chords.
+fn txt_blocks(lines: &[SourceLine], stems: bool) -> Mapping {
+    let mut contexts = Mapping::new();
+    let mut current_context: Option<Value> = None;
+    let mut current_stem: Option<Value> = None;
+
+    for line in lines {
+        let trimmed_line = line.text.trim();
+        if trimmed_line.is_empty() || trimmed_line.starts_with("//") {
+            continue;
+        }
+
+        if !line.text.starts_with(' ') && !line.text.starts_with('\t') {
+            let context = Value::from(trimmed_line.trim_end_matches(':'));
+            if !contexts.contains_key(&context) {
+                contexts.insert(context.clone(), Value::Mapping(Mapping::new()));
+            }
+            current_context = Some(context);
+            current_stem = None;
+            continue;
+        }
+
+        let Some(Value::Mapping(context)) =
+            current_context.as_ref().and_then(|context| contexts.get_mut(context))
+        else {
+            continue;
+        };
+
+        if stems && trimmed_line.ends_with(':') {
+            let stem = Value::from(trimmed_line.trim_end_matches(':'));
+            if !matches!(context.get(&stem), Some(Value::Mapping(_))) {
+                context.insert(stem.clone(), Value::Mapping(Mapping::new()));
+            }
+            current_stem = Some(stem);
+        } else if let Some((key, value)) = trimmed_line.split_once('=') {
+            let block = match current_stem.as_ref().and_then(|stem| context.get_mut(stem)) {
+                Some(Value::Mapping(stem)) => stem,
+                _ => context,
+            };
+            block.insert(Value::from(key.trim()), Value::from(value.trim()));
+        }
+    }
+
+    contexts
+}
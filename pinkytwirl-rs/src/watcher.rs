@@ -0,0 +1,138 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::loader::LoadedConfig;
+use crate::platform::Platform;
+use crate::sources::ConfigLayer;
+
+// A hash of the content of every watched file, or `None` for files that don't exist. Unlike
+// modification times, it also catches edits that keep the size within the resolution of the
+// file system's timestamps.
+type Snapshot = Vec<Option<u64>>;
+
+// Polls the config files on a background thread. Once they stop changing for the debounce
+// interval the config is parsed on that thread, and the result is handed to the engine.
+pub struct ConfigWatcher {
+    // Each loaded config comes with the generation of the target it was loaded for.
+    receiver: Receiver<(u64, Result<LoadedConfig, String>)>,
+    // Dropping the watcher drops this sender, which wakes the thread up and ends it. The thread
+    // is never joined, so dropping doesn't wait for a poll or a load to finish.
+    targets: Sender<Target>,
+    generation: u64,
+    pub poll_interval: Duration,
+    pub debounce: Duration,
+}
+
+// What the thread watches and loads, replaced when the engine switches profiles.
+struct Target {
+    profile: Option<String>,
+    files: Vec<PathBuf>,
+    generation: u64,
+}
+
+impl ConfigWatcher {
+    pub fn start(
+        layers: Vec<ConfigLayer>,
+        profile: Option<String>,
+        platform: Platform,
+        files: Vec<PathBuf>,
+        poll_interval: Duration,
+        debounce: Duration,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let (targets, next_targets) = mpsc::channel::<Target>();
+        // Taken before the thread starts, so no change made after `start` returns is missed.
+        let mut last_snapshot = snapshot(&files);
+
+        thread::spawn(move || {
+            let (mut profile, mut files, mut generation) = (profile, files, 0);
+            let mut changed_at: Option<Instant> = None;
+
+            loop {
+                match next_targets.recv_timeout(poll_interval) {
+                    Ok(target) => {
+                        profile = target.profile;
+                        files = target.files;
+                        generation = target.generation;
+                        last_snapshot = snapshot(&files);
+                        changed_at = None;
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+
+                let current_snapshot = snapshot(&files);
+                if current_snapshot != last_snapshot {
+                    last_snapshot = current_snapshot;
+                    changed_at = Some(Instant::now());
+                    continue;
+                }
+
+                match changed_at {
+                    Some(instant) if instant.elapsed() >= debounce => changed_at = None,
+                    _ => continue,
+                }
+
+                let keycodes = crate::keycode_macos::create_keycode_map();
+                let result = crate::loader::load_config(&layers, profile.as_deref(), platform, &keycodes)
+                    .map_err(|e| e.to_string());
+                if let Ok(loaded) = &result {
+                    // Includes may have been added or removed.
+                    files = loaded.files.clone();
+                    last_snapshot = snapshot(&files);
+                }
+                if sender.send((generation, result)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        ConfigWatcher {
+            receiver,
+            targets,
+            generation: 0,
+            poll_interval,
+            debounce,
+        }
+    }
+
+    // Watches the files of another profile from the next poll on. Doesn't wait for the thread,
+    // so it's cheap enough to call while handling a key event. Configs that were loaded for the
+    // previous profile are dropped.
+    pub fn retarget(&mut self, profile: Option<String>, files: Vec<PathBuf>) {
+        self.generation += 1;
+        let target = Target {
+            profile,
+            files,
+            generation: self.generation,
+        };
+        let _ = self.targets.send(target);
+    }
+
+    // Returns the most recently loaded config, if the watcher loaded one since the last call.
+    pub fn try_recv(&self) -> Option<Result<LoadedConfig, String>> {
+        self.receiver
+            .try_iter()
+            .filter(|(generation, _)| *generation == self.generation)
+            .last()
+            .map(|(_, result)| result)
+    }
+}
+
+fn snapshot(files: &[PathBuf]) -> Snapshot {
+    files
+        .iter()
+        .map(|file| {
+            let content = fs::read(file).ok()?;
+            let mut hasher = DefaultHasher::new();
+            content.hash(&mut hasher);
+            Some(hasher.finish())
+        })
+        .collect()
+}
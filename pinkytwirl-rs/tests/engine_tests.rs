@@ -1,9 +1,17 @@
-use pinkytwirl::{KeyEvent, KeyState, PinkyTwirlEngine};
+use pinkytwirl::{ConfigLocation, KeyEvent, KeyState, PinkyTwirlEngine, Platform, Simulator};
 
 // Helper functions
 fn get_engine() -> PinkyTwirlEngine {
-    let engine = PinkyTwirlEngine::new("../../../src/user_config".to_string());
+    let mut engine = PinkyTwirlEngine::with_platform("../../../src/user_config".to_string(), Platform::MacOS);
     assert!(engine.startup.is_ok(), "Failed to load configurations");
+    engine.set_strict_invariants(true);
+    engine
+}
+
+fn get_fixture_engine(name: &str) -> PinkyTwirlEngine {
+    let mut engine = PinkyTwirlEngine::with_platform(format!("../../../tests/fixtures/{}", name), Platform::MacOS);
+    assert!(engine.startup.is_ok(), "Failed to load fixture configurations");
+    engine.set_strict_invariants(true);
     engine
 }
 
@@ -242,3 +250,1246 @@ fn test_config_loading() {
     let engine = get_engine();
     engine.print_config();
 }
+
+#[test]
+fn test_passthrough_mapping_stops_inheritance() {
+    let mut engine = get_fixture_engine("passthrough");
+
+    // Meta + J is unbound in Game, so the held stem and the key are played back untouched.
+    let (suppress, synthetic_events) = engine.handle_key_event(key_down("meta"), "Game", "");
+    assert!(suppress, "The stem should be held back");
+    assert!(synthetic_events.is_empty());
+
+    let (suppress, synthetic_events) = engine.handle_key_event(key_down("meta + j"), "Game", "");
+    assert!(suppress);
+    let keys: Vec<&str> = synthetic_events.iter().map(|e| e.key.as_str()).collect();
+    assert_eq!(keys, vec!["meta", "j"], "Passthrough should replay the held keys");
+
+    // Nothing else is mapped until the chord is released.
+    let (suppress, synthetic_events) = engine.handle_key_event(key_down("meta + l"), "Game", "");
+    assert!(!suppress);
+    assert!(synthetic_events.is_empty());
+
+    engine.handle_key_event(key_up("meta + l"), "Game", "");
+    engine.handle_key_event(key_up("meta + j"), "Game", "");
+    engine.handle_key_event(key_up("meta"), "Game", "");
+    assert!(engine.pressed_keys.is_empty());
+
+    // Chords that are not unbound are still inherited from Default.
+    engine.handle_key_event(key_down("meta"), "Game", "");
+    let (suppress, synthetic_events) = engine.handle_key_event(key_down("meta + l"), "Game", "");
+    assert!(suppress);
+    assert_eq!(synthetic_events.len(), 1);
+    assert_eq!(synthetic_events[0].key, "right");
+}
+
+#[test]
+fn test_passthrough_all_context() {
+    let mut engine = get_fixture_engine("passthrough");
+
+    let chord_sequence = vec![
+        // Key event, expected to suppress, expected number of pressed keys.
+        (key_down("meta"), false, 1),
+        (key_down("meta + j"), false, 2),
+        (key_up("meta + j"), false, 1),
+        (key_up("meta"), false, 0),
+    ];
+
+    for (event, expected_suppress, expected_pressed_keys) in &chord_sequence {
+        let (suppress, synthetic_events) =
+            engine.handle_key_event(event.clone(), "Remote Desktop", "Work PC");
+        assert!(synthetic_events.is_empty(), "A passthrough_all context should never synthesise keys");
+        assert_eq!(suppress, *expected_suppress, "Key event suppression is wrong");
+        assert_eq!(engine.pressed_keys.len(), *expected_pressed_keys, "Number of keys still pressed is wrong");
+    }
+}
+
+#[test]
+fn test_passthrough_only_as_a_whole_mapping() {
+    let engine = PinkyTwirlEngine::from_strs(
+        "Default:\n  aliases: []\n  parent: null\n",
+        "Default:\n    NavLeft = Left\n    Nothing = Passthrough\n",
+        "Default:\n    Meta:\n        J = NavLeft | Passthrough\n        K = Passthrough\n",
+    );
+    assert!(engine.startup.is_ok());
+    let context = engine.context("Default").unwrap();
+    assert!(!context.semantic_actions.contains_key("Nothing"));
+    assert!(!context.key_mappings.contains_key("meta + j"));
+    assert_eq!(context.key_mappings.get("meta + k").unwrap().to_string(), "Passthrough");
+    assert_eq!(engine.diagnostics().len(), 2, "{:?}", engine.diagnostics());
+    assert!(engine.diagnostics()[0].contains("'Nothing'"));
+    assert!(engine.diagnostics()[1].contains("'Meta + J'"));
+}
+
+fn play_chord(engine: &mut PinkyTwirlEngine, stem: &str, key: &str, app_name: &str) -> Vec<KeyEvent> {
+    let mut synthetic_events = Vec::new();
+    for event in [
+        key_down(stem),
+        key_down(&format!("{} + {}", stem, key)),
+        key_up(&format!("{} + {}", stem, key)),
+        key_up(stem),
+    ] {
+        let (_, events) = engine.handle_key_event(event, app_name, "Untitled");
+        synthetic_events.extend(events);
+    }
+    synthetic_events
+}
+
+#[test]
+fn test_context_semantics_platform() {
+    let mut engine = get_fixture_engine("remote");
+
+    let events = play_chord(&mut engine, "meta", "r", "Remote Desktop");
+    assert_eq!(events.len(), 1);
+    assert_eq!((events[0].key.as_str(), events[0].ctrl, events[0].meta), ("c", true, false));
+
+    let events = play_chord(&mut engine, "meta", "u", "Remote Desktop");
+    assert_eq!(events.len(), 1);
+    assert_eq!((events[0].key.as_str(), events[0].ctrl, events[0].meta), ("home", false, false));
+
+    // Other contexts keep using the engine's own semantics.
+    let events = play_chord(&mut engine, "meta", "r", "Finder");
+    assert_eq!(events.len(), 1);
+    assert_eq!((events[0].key.as_str(), events[0].ctrl, events[0].meta), ("c", false, true));
+}
+
+#[test]
+fn test_context_modifier_translation() {
+    let mut engine = get_fixture_engine("remote");
+
+    let events = play_chord(&mut engine, "meta", "r", "Virtual Machine");
+    assert_eq!(events.len(), 1);
+    assert_eq!((events[0].key.as_str(), events[0].ctrl, events[0].meta), ("c", true, false));
+
+    let events = play_chord(&mut engine, "meta", "u", "Virtual Machine");
+    assert_eq!(events.len(), 1);
+    assert_eq!((events[0].key.as_str(), events[0].ctrl, events[0].meta), ("left", true, false));
+}
+
+#[test]
+fn test_shared_semantics_primary_and_word_modifiers() {
+    for (platform, paste_modifiers, word_modifiers) in [
+        (Platform::MacOS, (false, false, true), (false, true, false)),
+        (Platform::Windows, (true, false, false), (true, false, false)),
+    ] {
+        let mut engine =
+            PinkyTwirlEngine::with_platform("../../../src/user_config".to_string(), platform);
+        assert!(engine.startup.is_ok(), "Failed to load configurations for {}", platform);
+        assert_eq!(engine.platform(), platform);
+
+        // Meta + W = Paste = Primary + V
+        let events = play_chord(&mut engine, "meta", "w", "unknown_app");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].key, "v");
+        assert_eq!((events[0].ctrl, events[0].alt, events[0].meta), paste_modifiers, "Paste on {}", platform);
+
+        // Meta + M = NavNextWord = Word + Right
+        let events = play_chord(&mut engine, "meta", "m", "unknown_app");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].key, "right");
+        assert_eq!((events[0].ctrl, events[0].alt, events[0].meta), word_modifiers, "NavNextWord on {}", platform);
+    }
+}
+
+#[test]
+fn test_layered_config_directories() {
+    let layers = ["base", "team", "personal"]
+        .iter()
+        .map(|layer| format!("../../../tests/fixtures/layers/{}", layer))
+        .collect();
+    let mut engine = PinkyTwirlEngine::with_layers(layers, Platform::MacOS);
+    assert!(engine.startup.is_ok(), "Failed to load layered configurations");
+    assert_eq!(engine.config_layers().len(), 3);
+
+    // The personal layer overrides a single chord, the rest is inherited from the base layer.
+    let events = play_chord(&mut engine, "meta", "j", "unknown_app");
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].key, "right");
+
+    // The team layer overrides a single semantic action.
+    let events = play_chord(&mut engine, "meta", "r", "unknown_app");
+    assert_eq!(events.len(), 1);
+    assert_eq!((events[0].key.as_str(), events[0].ctrl), ("insert", true));
+
+    // The team layer replaces the Chrome context as a whole.
+    let chrome = engine.get_context("Chromium", "New Tab").unwrap();
+    assert_eq!(chrome.name, "Chrome");
+    assert_eq!(chrome.aliases, vec!["Google Chrome", "Chromium"]);
+
+    assert_eq!(engine.context_origin("Default").unwrap().layer, 0);
+    assert_eq!(engine.context_origin("Chrome").unwrap().layer, 1);
+    assert_eq!(engine.semantic_action_origin("Default", "Copy").unwrap().layer, 1);
+    assert_eq!(engine.semantic_action_origin("Default", "NavLeft").unwrap().layer, 0);
+    let origin = engine.mapping_origin("Default", "Meta + J").unwrap();
+    assert_eq!((origin.layer, origin.line_number), (2, 3));
+    assert_eq!(engine.mapping_origin("Default", "Meta + R").unwrap().layer, 0);
+}
+
+#[test]
+fn test_later_layer_shared_semantics_override_earlier_platform_semantics() {
+    let layers = ["base", "personal"]
+        .iter()
+        .map(|layer| format!("../../../tests/fixtures/layers/{}", layer))
+        .collect();
+    let mut engine = PinkyTwirlEngine::with_layers(layers, Platform::MacOS);
+    assert!(engine.startup.is_ok(), "Failed to load layered configurations");
+
+    // The base layer defines NavHome in semantics_macos.txt, the personal one in semantics.txt.
+    let events = play_chord(&mut engine, "meta", "h", "unknown_app");
+    assert_eq!(events.len(), 1);
+    assert_eq!((events[0].key.as_str(), events[0].meta), ("home", false));
+    assert_eq!(engine.semantic_action_origin("Default", "NavHome").unwrap().layer, 1);
+}
+
+#[test]
+fn test_include_directives() {
+    let mut engine = get_fixture_engine("includes");
+
+    assert_eq!(engine.get_context("Terminal", "zsh").unwrap().name, "Terminal");
+
+    let events = play_chord(&mut engine, "d1", "j", "Terminal");
+    let keys: Vec<&str> = events.iter().map(|e| e.key.as_str()).collect();
+    assert_eq!(keys, vec!["b", "left"]);
+
+    let origin = engine.mapping_origin("Terminal", "D1 + J").unwrap();
+    assert!(origin.file.ends_with("modules/tmux_mappings.txt"), "Unexpected origin {}", origin);
+    assert_eq!(origin.line_number, 4);
+    let origin = engine.context_origin("Terminal").unwrap();
+    assert!(origin.file.ends_with("modules/terminal_contexts.txt"), "Unexpected origin {}", origin);
+    assert_eq!(origin.line_number, 1);
+}
+
+#[test]
+fn test_include_cycle_is_reported() {
+    let engine = PinkyTwirlEngine::with_platform(
+        "../../../tests/fixtures/include_cycle".to_string(),
+        Platform::MacOS,
+    );
+    let error = engine.startup.as_ref().unwrap_err().to_string();
+    assert!(error.contains("Include cycle"), "Unexpected error: {}", error);
+    assert!(error.contains("nested.txt:1"), "The error should point at the include: {}", error);
+}
+
+#[test]
+fn test_engine_from_strs() {
+    let cwd = std::env::current_dir().unwrap();
+
+    let mut engine = PinkyTwirlEngine::from_strs(
+        "Default:\n  aliases: []\n  parent: null\n",
+        "Default:\n    NavLeft = Left\n    Paste = Primary + V\n",
+        "Default:\n    Meta:\n        J = NavLeft\n        W = Paste\n",
+    );
+    assert!(engine.startup.is_ok(), "Failed to load configurations from strings");
+    assert_eq!(std::env::current_dir().unwrap(), cwd, "The engine should not change the working directory");
+
+    let events = play_chord(&mut engine, "meta", "j", "unknown_app");
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].key, "left");
+
+    let events = play_chord(&mut engine, "meta", "w", "unknown_app");
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].key, "v");
+    assert!(events[0].ctrl || events[0].meta);
+
+    let origin = engine.mapping_origin("Default", "meta + w").unwrap();
+    assert_eq!(origin.line_number, 4);
+}
+
+#[test]
+fn test_embedded_default_configuration() {
+    let engine = PinkyTwirlEngine::builder()
+        .platform(Platform::MacOS)
+        .embedded_defaults()
+        .build();
+    assert!(engine.startup.is_ok(), "Failed to load the embedded configurations");
+    assert_eq!(engine.get_context("Visual Studio Code", "main.rs").unwrap().name, "VSCode");
+
+    // A missing config directory falls back to the embedded defaults.
+    let engine = PinkyTwirlEngine::new("does/not/exist".to_string());
+    assert!(engine.startup.is_ok(), "Failed to fall back to the embedded configurations");
+    assert_eq!(engine.config_layers().len(), 1);
+    assert_eq!(engine.config_layers()[0].to_string(), "embedded");
+}
+
+#[test]
+fn test_config_discovery_from_environment() {
+    let config_dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/passthrough");
+    let env = |name: &str| (name == pinkytwirl::CONFIG_DIR_ENV).then(|| config_dir.clone().into_os_string());
+    let engine = PinkyTwirlEngine::builder()
+        .platform(Platform::MacOS)
+        .discovered_config_in(&env)
+        .build();
+
+    assert!(engine.startup.is_ok(), "Failed to load the discovered configurations");
+    assert_eq!(engine.config_location(), Some(&ConfigLocation::Environment(config_dir)));
+    assert!(!engine.offers_default_config_install());
+    assert!(engine.config_location_description().contains(pinkytwirl::CONFIG_DIR_ENV));
+}
+
+#[test]
+fn test_write_default_config() {
+    let config_dir = std::env::temp_dir().join(format!("pinkytwirl-defaults-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&config_dir);
+
+    let written = pinkytwirl::write_default_config(&config_dir).unwrap();
+    assert_eq!(written.len(), 5);
+    // Existing files are never overwritten.
+    assert!(pinkytwirl::write_default_config(&config_dir).unwrap().is_empty());
+
+    let engine = PinkyTwirlEngine::builder()
+        .platform(Platform::MacOS)
+        .config_dir(&config_dir)
+        .build();
+    assert!(engine.startup.is_ok(), "Failed to load the written default configurations");
+    assert_eq!(engine.get_context("Visual Studio Code", "main.rs").unwrap().name, "VSCode");
+
+    std::fs::remove_dir_all(&config_dir).unwrap();
+}
+
+// Copies a fixture into a fresh temporary directory, so a test can edit it.
+fn temp_fixture_dir(name: &str, test_name: &str) -> std::path::PathBuf {
+    let fixture_dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+    let config_dir = std::env::temp_dir().join(format!("pinkytwirl-{}-{}", test_name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&config_dir);
+    std::fs::create_dir_all(&config_dir).unwrap();
+    for entry in std::fs::read_dir(fixture_dir).unwrap() {
+        let entry = entry.unwrap();
+        std::fs::copy(entry.path(), config_dir.join(entry.file_name())).unwrap();
+    }
+    config_dir
+}
+
+#[test]
+fn test_reload_report_and_invalid_reload() {
+    let config_dir = temp_fixture_dir("passthrough", "reload");
+    let mut engine = PinkyTwirlEngine::builder()
+        .platform(Platform::MacOS)
+        .config_dir(&config_dir)
+        .build();
+    assert!(engine.startup.is_ok(), "Failed to load configurations");
+
+    let mappings = std::fs::read_to_string(config_dir.join("mappings.txt")).unwrap();
+    let mappings = mappings.replace("        L = NavRight\n", "        K = NavRight\n");
+    std::fs::write(config_dir.join("mappings.txt"), mappings).unwrap();
+    let contexts = std::fs::read_to_string(config_dir.join("contexts.txt")).unwrap();
+    std::fs::write(config_dir.join("contexts.txt"), contexts + "\nEditor:\n  aliases: [\"Editor\"]\n  parent: Missing\n").unwrap();
+
+    let report = engine.reload_configurations().unwrap();
+    assert_eq!(report.added_contexts, vec!["Editor"]);
+    assert!(report.removed_contexts.is_empty());
+    assert_eq!(report.added_mappings, vec!["Default: meta + k"]);
+    assert_eq!(report.removed_mappings, vec!["Default: meta + l"]);
+    assert_eq!(report.diagnostics.len(), 1);
+    assert!(report.diagnostics[0].contains("Missing"));
+    assert_eq!(engine.diagnostics(), report.diagnostics.as_slice());
+
+    // A config that fails to load leaves the previous one active.
+    std::fs::write(config_dir.join("contexts.txt"), "Default: [").unwrap();
+    assert!(engine.reload_configurations().is_err());
+    let events = play_chord(&mut engine, "meta", "k", "Notes");
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].key, "right");
+
+    std::fs::remove_dir_all(&config_dir).unwrap();
+}
+
+#[test]
+fn test_config_watcher_reloads_changes() {
+    let config_dir = temp_fixture_dir("passthrough", "watch");
+    let mut engine = PinkyTwirlEngine::builder()
+        .platform(Platform::MacOS)
+        .config_dir(&config_dir)
+        .build();
+    assert!(engine.startup.is_ok(), "Failed to load configurations");
+    engine.watch_config_with(std::time::Duration::from_millis(10), std::time::Duration::from_millis(30));
+
+    // The edit keeps the size and the modification time, so only the content tells it apart.
+    let mappings_file = config_dir.join("mappings.txt");
+    let modified = std::fs::metadata(&mappings_file).unwrap().modified().unwrap();
+    let mappings = std::fs::read_to_string(&mappings_file).unwrap();
+    std::fs::write(&mappings_file, mappings.replace("L = NavRight", "K = NavRight")).unwrap();
+    std::fs::File::options().write(true).open(&mappings_file).unwrap().set_modified(modified).unwrap();
+
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    let report = loop {
+        if let Some(result) = engine.poll_config_watcher() {
+            break result.unwrap();
+        }
+        assert!(std::time::Instant::now() < deadline, "The watcher did not pick up the change");
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    };
+    assert_eq!(report.added_mappings, vec!["Default: meta + k"]);
+    assert_eq!(report.removed_mappings, vec!["Default: meta + l"]);
+
+    let events = play_chord(&mut engine, "meta", "k", "Notes");
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].key, "right");
+
+    // Stopping doesn't wait for the watcher to wake up from its poll interval.
+    engine.watch_config_with(std::time::Duration::from_secs(60), std::time::Duration::from_millis(30));
+    let start = std::time::Instant::now();
+    engine.stop_watching_config();
+    assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    std::fs::remove_dir_all(&config_dir).unwrap();
+}
+
+#[test]
+fn test_config_watcher_recovers_from_broken_config() {
+    let config_dir = temp_fixture_dir("passthrough", "watch-broken");
+    let contexts_file = config_dir.join("contexts.txt");
+    let contexts = std::fs::read_to_string(&contexts_file).unwrap();
+    std::fs::write(&contexts_file, "Default: [").unwrap();
+    let mut engine = PinkyTwirlEngine::builder()
+        .platform(Platform::MacOS)
+        .config_dir(&config_dir)
+        .build();
+    assert!(engine.startup.is_err(), "The broken config should not load");
+    engine.watch_config_with(std::time::Duration::from_millis(10), std::time::Duration::from_millis(30));
+
+    // Nothing has loaded, so the watcher has to find the fix on its own.
+    std::fs::write(&contexts_file, contexts).unwrap();
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    loop {
+        if let Some(Ok(_)) = engine.poll_config_watcher() {
+            break;
+        }
+        assert!(std::time::Instant::now() < deadline, "The watcher did not pick up the fix");
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    let events = play_chord(&mut engine, "meta", "l", "Notes");
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].key, "right");
+    engine.stop_watching_config();
+    std::fs::remove_dir_all(&config_dir).unwrap();
+}
+
+#[test]
+fn test_unified_config_file() {
+    let mut engine = get_fixture_engine("unified");
+    let events = play_chord(&mut engine, "meta", "j", "Notes");
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].key, "left");
+    let events = play_chord(&mut engine, "meta", "e", "Notes");
+    assert_eq!(events.len(), 1);
+    assert_eq!((events[0].key.as_str(), events[0].meta), ("z", true));
+    assert!(play_chord(&mut engine, "meta", "j", "Game").iter().all(|event| event.key != "left"));
+
+    // Origins point into the unified file.
+    let origin = engine.mapping_origin("Default", "Meta + L").unwrap();
+    assert!(origin.file.ends_with(pinkytwirl::UNIFIED_CONFIG_FILE));
+    assert_eq!(origin.line_number, 23);
+    assert_eq!(engine.semantic_action_origin("Default", "NavRight").unwrap().line_number, 13);
+    assert_eq!(engine.context_origin("Game").unwrap().line_number, 5);
+
+    // Back to the .txt files and the engine still sees the same config.
+    let yaml = std::fs::read_to_string(format!("{}/tests/fixtures/unified/pinkytwirl.yaml", env!("CARGO_MANIFEST_DIR"))).unwrap();
+    let files: std::collections::HashMap<String, String> = pinkytwirl::unified_to_txt(&yaml).unwrap().into_iter().collect();
+    assert!(files["mappings.txt"].contains("    Meta:\n        J = NavLeft\n"));
+    assert!(files["mappings.txt"].contains("    F13 = Undo\n"));
+    assert!(files["semantics_windows.txt"].contains("    Redo = Ctrl + Y\n"));
+}
+
+#[test]
+fn test_txt_and_unified_configs_are_equivalent() {
+    let config_dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src/user_config");
+    let yaml = pinkytwirl::txt_to_unified(&pinkytwirl::ConfigLayer::Directory(config_dir)).unwrap();
+
+    let txt_engine = get_engine();
+    let unified_engine = PinkyTwirlEngine::builder()
+        .platform(Platform::MacOS)
+        .config_unified_str(&yaml)
+        .build();
+    assert!(unified_engine.startup.is_ok(), "Failed to load the converted configurations");
+
+    assert_eq!(txt_engine.context_names(), unified_engine.context_names());
+    for name in txt_engine.context_names() {
+        let (txt_context, unified_context) = (txt_engine.context(name).unwrap(), unified_engine.context(name).unwrap());
+        assert_eq!(txt_context.aliases, unified_context.aliases);
+        assert_eq!(txt_context.parent, unified_context.parent);
+        assert_eq!(txt_context.key_mappings, unified_context.key_mappings, "Mappings of {} differ", name);
+        assert_eq!(txt_context.semantic_actions, unified_context.semantic_actions, "Semantics of {} differ", name);
+        assert_eq!(txt_context.foreign_semantic_actions, unified_context.foreign_semantic_actions);
+    }
+
+    // And the unified config converts back into .txt files the engine reads the same way.
+    let files: Vec<(String, String)> = pinkytwirl::unified_to_txt(&yaml).unwrap();
+    let file = |name: &str| files.iter().find(|(file_name, _)| file_name == name).map(|(_, content)| content.as_str()).unwrap();
+    let roundtrip_engine = PinkyTwirlEngine::builder()
+        .platform(Platform::MacOS)
+        .config_unified_str(&pinkytwirl::txt_to_unified(&pinkytwirl::ConfigLayer::Memory {
+            name: "converted".to_string(),
+            files: files.iter().cloned().collect(),
+        }).unwrap())
+        .build();
+    assert!(file("contexts.txt").starts_with("Default:\n"));
+    for name in txt_engine.context_names() {
+        assert_eq!(txt_engine.context(name).unwrap().key_mappings, roundtrip_engine.context(name).unwrap().key_mappings);
+    }
+}
+
+#[test]
+fn test_format_config_aligns_and_keeps_comments() {
+    let text = "// Team mappings\nDefault:\n\tmeta:\n        j = NavLeft\n        semicolon=NavBottom   \n        // Words\n        m = NavNextWord\n\n\n    f13 = shift + left | 2 * down | \"Hi\"\n";
+    let formatted = pinkytwirl::format_config(text, pinkytwirl::ConfigFileKind::Mappings);
+    assert_eq!(
+        formatted,
+        "// Team mappings\nDefault:\n    Meta:\n        J         = NavLeft\n        Semicolon = NavBottom\n        // Words\n        M = NavNextWord\n\n        F13 = Shift + Left | 2 * Down | \"Hi\"\n"
+    );
+    // Formatting is idempotent, including on the bundled config.
+    assert_eq!(pinkytwirl::format_config(&formatted, pinkytwirl::ConfigFileKind::Mappings), formatted);
+    for (file_name, kind) in [("mappings.txt", pinkytwirl::ConfigFileKind::Mappings), ("semantics.txt", pinkytwirl::ConfigFileKind::Semantics)] {
+        let text = std::fs::read_to_string(format!("{}/src/user_config/{}", env!("CARGO_MANIFEST_DIR"), file_name)).unwrap();
+        let formatted = pinkytwirl::format_config(&text, kind);
+        assert_eq!(pinkytwirl::format_config(&formatted, kind), formatted);
+    }
+}
+
+#[test]
+fn test_serialized_config_round_trips() {
+    let engine = get_engine();
+    let mappings = engine.serialize_mappings();
+    let semantics = engine.serialize_semantics(Platform::MacOS);
+    assert!(mappings.contains("    Meta:\n        I         = NavUp\n"));
+    assert!(semantics.contains("    NavBigUp "));
+
+    let contexts = std::fs::read_to_string(format!("{}/src/user_config/contexts.txt", env!("CARGO_MANIFEST_DIR"))).unwrap();
+    let reloaded = PinkyTwirlEngine::builder()
+        .platform(Platform::MacOS)
+        .config_strs(&contexts, &semantics, &mappings)
+        .build();
+    assert!(reloaded.startup.is_ok(), "Failed to load the serialized configurations");
+    for name in engine.context_names() {
+        let (context, reloaded_context) = (engine.context(name).unwrap(), reloaded.context(name).unwrap());
+        assert_eq!(context.key_mappings, reloaded_context.key_mappings, "Mappings of {} differ", name);
+        assert_eq!(context.semantic_actions, reloaded_context.semantic_actions, "Semantics of {} differ", name);
+    }
+    assert!(reloaded.serialize_mappings().contains("    D1:\n        J             = PaneLeft\n"));
+}
+
+#[test]
+fn test_lint_config() {
+    let config_dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/lint");
+    let issues = pinkytwirl::lint_config(&[pinkytwirl::ConfigLayer::Directory(config_dir)], &[Platform::MacOS]).unwrap();
+    let found = |kind: pinkytwirl::LintKind, line_number: Option<usize>, text: &str| {
+        issues.iter().any(|issue| {
+            issue.kind == kind
+                && issue.location.as_ref().map(|(_, line_number)| *line_number) == line_number
+                && issue.message.contains(text)
+        })
+    };
+
+    assert!(found(pinkytwirl::LintKind::UndefinedAction, Some(5), "'Teleport'"), "{:#?}", issues);
+    assert!(found(pinkytwirl::LintKind::MissingContext, Some(6), "'Missing'"));
+    assert!(found(pinkytwirl::LintKind::MissingContext, Some(17), "'Ghost'"));
+    assert!(found(pinkytwirl::LintKind::ShadowedChord, Some(9), "'meta + j'"));
+    assert!(found(pinkytwirl::LintKind::DuplicateDefinition, Some(4), "'NavLeft'"));
+    assert!(found(pinkytwirl::LintKind::DuplicateDefinition, Some(11), "'Meta + L'"));
+    assert!(found(pinkytwirl::LintKind::UnmatchedAlias, None, "'editor'"));
+    assert!(found(pinkytwirl::LintKind::UnreachableMapping, Some(13), "'Orphan'"));
+    // The duplicate `L` ends up the same as the inherited one, too.
+    assert!(found(pinkytwirl::LintKind::ShadowedChord, Some(11), "'meta + l'"));
+    assert_eq!(issues.len(), 9, "{:#?}", issues);
+
+    // The bundled config still uses actions that nothing defines.
+    let config_dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src/user_config");
+    let issues = pinkytwirl::lint_config(&[pinkytwirl::ConfigLayer::Directory(config_dir)], &Platform::ALL).unwrap();
+    let undefined = |action: &str| {
+        issues.iter().any(|issue| issue.kind == pinkytwirl::LintKind::UndefinedAction && issue.message.contains(action))
+    };
+    // Named as written, whether the action is used by a mapping or by a definition.
+    assert!(undefined("'InputCreds'") && undefined("'ActivateChromeWindow'") && undefined("'StartAltTab'"));
+    assert!(!issues.iter().any(|issue| issue.message.contains("'viewsymbols'")));
+}
+
+#[test]
+fn test_query_chord_and_chords_for_action() {
+    let engine = get_engine();
+
+    let query = engine.query_chord("Visual Studio Code", "Untitled", "Meta + J").unwrap();
+    assert_eq!(query.context, "VSCode");
+    assert_eq!(query.chord, "Meta + J");
+    let mapping = query.mapping.as_ref().unwrap();
+    assert_eq!((mapping.context.as_str(), mapping.action.as_str()), ("Default", "NavLeft"));
+    let tree = query.semantic_tree.as_ref().unwrap();
+    assert_eq!((tree.label.as_str(), tree.context.as_deref()), ("NavLeft", Some("Default")));
+    assert_eq!(query.events.len(), 1);
+    assert_eq!(query.events[0].key, "left");
+    assert!(query.to_string().contains("Events: Left"));
+
+    let stem = engine.query_chord("Visual Studio Code", "Untitled", "Meta + Space").unwrap();
+    assert!(stem.is_stem && stem.events.is_empty());
+    let unmapped = engine.query_chord("Visual Studio Code", "Untitled", "Shift + Q").unwrap();
+    assert!(unmapped.mapping.is_none());
+
+    let chords = engine.chords_for_action("copy", "Chrome", "Untitled");
+    assert!(chords
+        .iter()
+        .any(|chord| chord.chord == "D4 + R" && chord.context == "Default" && chord.path == ["Copy"]));
+
+    // The final events have the context's modifier translation applied.
+    let engine = get_fixture_engine("remote");
+    let query = engine.query_chord("Virtual Machine", "Untitled", "meta + r").unwrap();
+    assert_eq!(query.semantic_tree.unwrap().children[0].label, "Meta + C");
+    assert_eq!((query.events[0].key.as_str(), query.events[0].ctrl, query.events[0].meta), ("c", true, false));
+    let chords = engine.chords_for_action("Copy", "Virtual Machine", "Untitled");
+    assert_eq!(chords.len(), 1);
+    assert_eq!(chords[0].to_string(), "Meta + R (Default): Copy");
+}
+
+#[test]
+fn test_simulator_commands() {
+    let mut simulator = Simulator::new(get_engine());
+
+    let focus = simulator.run_command("focus \"Visual Studio Code\" \"Untitled\"").unwrap();
+    assert!(focus.contains("context: VSCode"));
+    let down = simulator.run_command("down cmd").unwrap();
+    assert!(down.starts_with("down meta: suppressed"));
+    assert!(down.contains("pressed keys: meta\n  state: StemHeld"));
+    let down = simulator.run_command("down J").unwrap();
+    assert!(down.contains("state: MappingActive"));
+    assert!(down.contains("output: Left down, Left up\n"));
+    simulator.run_command("up j").unwrap();
+    let up = simulator.run_command("up meta").unwrap();
+    assert!(up.contains("pressed keys: none\n  state: Idle"));
+    simulator.run_command("down meta").unwrap();
+    let reset = simulator.run_command("reset").unwrap();
+    assert!(reset.contains("pressed keys: none\n  state: Idle"));
+
+    assert_eq!(simulator.run_command("// comment").unwrap(), "");
+    assert!(simulator.run_command("down nosuchkey").is_err());
+    assert!(simulator.run_command("focus \"Chrome").is_err());
+}
+
+#[test]
+fn test_golden_cases() {
+    let cases_dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/cases");
+    let report = pinkytwirl::run_case_dir(&cases_dir, get_engine).unwrap();
+    assert!(report.cases >= 3);
+    assert!(report.passed(), "\n{}", report);
+
+    let case = "focus \"Visual Studio Code\" \"Untitled\"\ndown meta => suppress\ndown j => pass\n";
+    let (steps, failures) = pinkytwirl::run_case(&mut get_engine(), "inline.case".as_ref(), case).unwrap();
+    assert_eq!(steps, 2);
+    assert_eq!(failures.len(), 1);
+    assert_eq!(
+        failures[0].to_string(),
+        "inline.case:3: down j\n  - expected: pass\n  + actual:   suppress: Left\n"
+    );
+    assert!(pinkytwirl::run_case(&mut get_engine(), "inline.case".as_ref(), "press j").is_err());
+}
+
+#[test]
+fn test_record_and_replay() {
+    let path = std::env::temp_dir().join(format!("pinkytwirl-recording-{}.txt", std::process::id()));
+    let mut engine = get_engine();
+    engine.start_recording(&path).unwrap();
+    assert!(engine.is_recording());
+    for event in [key_down("meta"), key_down("meta + j"), key_up("meta + j"), key_up("meta")] {
+        engine.handle_key_event(event, "Visual Studio Code", "Untitled");
+    }
+    engine.stop_recording();
+    assert!(!engine.is_recording());
+
+    let recording = std::fs::read_to_string(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert!(recording.starts_with("pinkytwirl-recording 1\nfocus\tVisual Studio Code\tUntitled\n"));
+    assert!(recording.contains("\tdown\tj\t0\tmeta\tsuppress: Left\n"));
+
+    let report = pinkytwirl::replay_recording(&mut get_engine(), &path, &recording).unwrap();
+    assert_eq!(report.steps, 4);
+    assert!(report.passed(), "\n{}", report);
+
+    // A config where the chord does something else shows up as a difference.
+    let changed = recording.replace("suppress: Left", "suppress: Right");
+    let report = pinkytwirl::replay_recording(&mut get_engine(), &path, &changed).unwrap();
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!((report.failures[0].expected.as_str(), report.failures[0].actual.as_str()), ("suppress: Right", "suppress: Left"));
+}
+
+#[test]
+fn test_logging_redacts_typed_keys() {
+    use pinkytwirl::{LogCategory, LogLevel, LogSettings};
+    use std::sync::{Arc, Mutex};
+
+    let settings = LogSettings::from_spec("warn, chords=debug, keys=trace").unwrap();
+    assert_eq!(settings.level, LogLevel::Warn);
+    assert!(settings.enabled(LogLevel::Trace, LogCategory::Keys));
+    assert!(!settings.enabled(LogLevel::Info, LogCategory::Config));
+    assert!(!settings.typed_keys);
+    assert!(LogSettings::from_spec("typed-keys").unwrap().typed_keys);
+    assert!(LogSettings::from_spec("loud").is_err());
+    assert!(LogSettings::from_spec("mouse=debug").is_err());
+
+    let mut engine = get_engine();
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let sink_records = records.clone();
+    pinkytwirl::set_log_sink(Some(Box::new(move |record| {
+        sink_records.lock().unwrap().push(record.clone());
+    })));
+    pinkytwirl::configure_logging(settings);
+    for event in [key_down("q"), key_up("q"), key_down("meta"), key_down("meta + j"), key_up("meta + j"), key_up("meta")] {
+        engine.handle_key_event(event, "Visual Studio Code", "Untitled");
+    }
+    pinkytwirl::set_log_sink(None);
+    pinkytwirl::configure_logging(LogSettings::default());
+
+    let records = records.lock().unwrap();
+    let messages: Vec<String> = records.iter().map(|record| record.to_string()).collect();
+    // Other tests may log at the same time, but never typed keys.
+    assert!(messages.iter().any(|message| message == "[debug chords] Default: meta + j -> Action(navleft)"));
+    assert!(messages.iter().any(|message| message == "[debug chords] No action found for chord: <key>"));
+    assert!(messages.iter().any(|message| message.starts_with("[trace keys] Key event: <key> Down")));
+    assert!(!messages.iter().any(|message| message.contains(" q ") || message.ends_with(": q")));
+    assert!(records.iter().all(|record| record.level <= LogLevel::Warn || record.category != LogCategory::Config));
+}
+
+#[test]
+fn test_secure_input_passes_through_and_ends_chord() {
+    let path = std::env::temp_dir().join(format!("pinkytwirl-secure-{}.txt", std::process::id()));
+    let mut engine = get_engine();
+    engine.start_recording(&path).unwrap();
+    engine.handle_host_key_event(key_down("meta"), "Visual Studio Code", "Untitled");
+    engine.handle_host_key_event(key_down("meta + j"), "Visual Studio Code", "Untitled");
+    engine.get_synthetic_events();
+
+    // The modifier releases the chord would have sent on the next key up are sent right away.
+    engine.set_secure_input(true);
+    assert!(engine.is_secure_input());
+    assert!(engine.pressed_keys.is_empty());
+    let released = engine.get_synthetic_events();
+    assert!(released.iter().any(|event| event.key == "meta" && event.state == KeyState::Up));
+    assert!(released.iter().all(|event| event.state == KeyState::Up));
+
+    for event in [key_up("meta + j"), key_down("meta + p"), key_down("meta + space"), key_up("meta")] {
+        assert!(!engine.handle_host_key_event(event, "Visual Studio Code", "Untitled"));
+        assert!(engine.get_synthetic_events().is_empty());
+        assert!(engine.pressed_keys.is_empty());
+    }
+    engine.set_secure_input(false);
+    engine.stop_recording();
+
+    let recording = std::fs::read_to_string(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert!(recording.ends_with("secure-input\ton\nsecure-input\toff\n"));
+    assert_eq!(recording.lines().filter(|line| line.contains("\tj\t") || line.contains("\tp\t")).count(), 1);
+}
+
+#[test]
+fn test_chord_states() {
+    use pinkytwirl::ChordState;
+
+    // A stem released alone is played back.
+    let mut engine = get_engine();
+    engine.handle_key_event(key_down("meta"), "Visual Studio Code", "Untitled");
+    assert_eq!(engine.state(), ChordState::StemHeld);
+    let (_, output) = engine.handle_key_event(key_up("meta"), "Visual Studio Code", "Untitled");
+    assert_eq!(output.len(), 1);
+    assert_eq!(engine.state(), ChordState::Idle);
+
+    // A mapping stays active until the stem is released.
+    engine.handle_key_event(key_down("meta"), "Visual Studio Code", "Untitled");
+    engine.handle_key_event(key_down("meta + j"), "Visual Studio Code", "Untitled");
+    assert_eq!(engine.state(), ChordState::MappingActive);
+    engine.handle_key_event(key_up("meta + j"), "Visual Studio Code", "Untitled");
+    assert_eq!(engine.state(), ChordState::MappingActive);
+    engine.handle_key_event(key_up("meta"), "Visual Studio Code", "Untitled");
+    assert_eq!(engine.state(), ChordState::Idle);
+
+    // A key that starts no chord passes through until it's released.
+    engine.handle_key_event(key_down("q"), "Visual Studio Code", "Untitled");
+    assert_eq!(engine.state(), ChordState::Passthrough);
+    engine.handle_key_event(key_up("q"), "Visual Studio Code", "Untitled");
+    assert_eq!(engine.state(), ChordState::Idle);
+
+    // Every state appears in the graph, and only listed transitions are allowed.
+    let graph = pinkytwirl::transition_graph();
+    assert!(ChordState::ALL.iter().all(|state| graph.contains(&format!("    {};", state))));
+    assert_eq!(graph.matches(" -> ").count(), pinkytwirl::TRANSITIONS.len());
+    assert!(pinkytwirl::is_transition(ChordState::Passthrough, ChordState::Idle));
+    assert!(!pinkytwirl::is_transition(ChordState::Passthrough, ChordState::MappingActive));
+
+    // Outside of tests a broken invariant is logged and the engine resets, instead of panicking.
+    engine.set_strict_invariants(false);
+    engine.pressed_keys.push_back(key_down("j"));
+    engine.handle_key_event(key_up("k"), "Visual Studio Code", "Untitled");
+    assert_eq!(engine.state(), ChordState::Idle);
+    assert!(engine.pressed_keys.is_empty());
+}
+
+#[test]
+fn test_stuck_keys_are_released() {
+    // Meta missed its up event, so the next key is no longer part of a chord.
+    let mut engine = get_engine();
+    engine.set_stuck_key_timeout(Some(std::time::Duration::ZERO));
+    engine.handle_key_event(key_down("meta"), "Visual Studio Code", "Untitled");
+    std::thread::sleep(std::time::Duration::from_millis(2));
+    let (suppress, _) = engine.handle_key_event(key_down("j"), "Visual Studio Code", "Untitled");
+    assert!(!suppress);
+    assert_eq!(engine.pressed_keys.len(), 1);
+
+    // The key that triggered the release is watched too, in case its own up event goes missing.
+    std::thread::sleep(std::time::Duration::from_millis(2));
+    engine.handle_key_event(key_down("k"), "Visual Studio Code", "Untitled");
+    assert_eq!(engine.pressed_keys.iter().map(|key| key.key.as_str()).collect::<Vec<_>>(), ["k"]);
+    engine.handle_key_event(key_up("k"), "Visual Studio Code", "Untitled");
+    assert!(engine.pressed_keys.is_empty());
+
+    // Meta is in the flags of every event while it's held, so it's never stuck.
+    engine.handle_key_event(key_down("meta + meta"), "Visual Studio Code", "Untitled");
+    std::thread::sleep(std::time::Duration::from_millis(2));
+    let (suppress, _) = engine.handle_key_event(key_down("meta + j"), "Visual Studio Code", "Untitled");
+    assert!(suppress);
+
+    // Caps Lock isn't in the flags, but a stem held past the timeout isn't stuck either.
+    let mut engine = get_fixture_engine("capslock");
+    engine.set_stuck_key_timeout(Some(std::time::Duration::from_millis(20)));
+    engine.handle_key_event(key_down("capslock"), "Untitled", "Untitled");
+    std::thread::sleep(std::time::Duration::from_millis(30));
+    let (suppress, output) = engine.handle_key_event(key_down("capslock + j"), "Untitled", "Untitled");
+    assert!(suppress);
+    assert_eq!(output[0].key, "left");
+}
+
+#[test]
+fn test_reset_all_and_sync_physical_state() {
+    let mut engine = get_engine();
+    engine.handle_host_key_event(key_down("meta"), "Visual Studio Code", "Untitled");
+    engine.handle_host_key_event(key_down("meta + j"), "Visual Studio Code", "Untitled");
+    engine.get_synthetic_events();
+
+    // Still holding meta, nothing to do.
+    engine.sync_physical_state(&["meta"]);
+    assert_eq!(engine.pressed_keys.len(), 1);
+    assert!(engine.get_synthetic_events().is_empty());
+
+    // Meta was released while the host wasn't looking, the modifiers are released.
+    engine.sync_physical_state(&[]);
+    assert!(engine.pressed_keys.is_empty());
+    assert_eq!(engine.state(), pinkytwirl::ChordState::Idle);
+    let released = engine.get_synthetic_events();
+    assert!(released.iter().any(|event| event.key == "meta" && event.state == KeyState::Up));
+
+    // Meta + Tab leaves Meta down for the program switcher, a reset releases it.
+    engine.handle_host_key_event(key_down("meta"), "Visual Studio Code", "Untitled");
+    engine.handle_host_key_event(key_down("meta + tab"), "Visual Studio Code", "Untitled");
+    let events = engine.get_synthetic_events();
+    assert!(events.iter().any(|event| event.key == "meta" && event.state == KeyState::Down));
+    engine.reset_all();
+    assert!(engine.pressed_keys.is_empty());
+    let released = engine.get_synthetic_events();
+    assert!(released.iter().any(|event| event.key == "meta" && event.state == KeyState::Up));
+    assert!(released.iter().all(|event| event.state == KeyState::Up));
+    engine.reset_all();
+    assert!(engine.get_synthetic_events().is_empty());
+}
+
+#[test]
+fn test_panic_disables_engine() {
+    let mut engine = get_engine();
+    engine.handle_host_key_event(key_down("meta"), "Visual Studio Code", "Untitled");
+    engine.handle_host_key_event(key_down("meta + tab"), "Visual Studio Code", "Untitled");
+    engine.get_synthetic_events();
+    assert!(engine.failure().is_none());
+
+    let suppress = engine.catch_panic("handle_key_event", false, |_| -> bool { panic!("Key name 'j' already exists in the map") });
+    assert!(!suppress);
+    assert_eq!(engine.failure(), Some("Panic in handle_key_event: Key name 'j' already exists in the map"));
+    let released = engine.get_synthetic_events();
+    assert!(released.iter().any(|event| event.key == "meta" && event.state == KeyState::Up));
+
+    // Everything passes through from now on.
+    for event in [key_up("meta + tab"), key_down("meta + j"), key_up("meta")] {
+        assert!(!engine.handle_host_key_event(event, "Visual Studio Code", "Untitled"));
+        assert!(engine.get_synthetic_events().is_empty());
+    }
+}
+
+#[test]
+fn test_enable_and_kill_chord() {
+    let mut engine = get_engine();
+    assert!(engine.is_enabled());
+    engine.set_enabled(false);
+    let (suppress, output) = engine.handle_key_event(key_down("meta"), "Visual Studio Code", "Untitled");
+    assert!(!suppress && output.is_empty());
+    engine.handle_key_event(key_up("meta"), "Visual Studio Code", "Untitled");
+    engine.set_enabled(true);
+
+    // Both Shift keys and Escape disable the engine in the middle of a chord, and release Meta.
+    engine.handle_host_key_event(key_down("meta"), "Visual Studio Code", "Untitled");
+    engine.handle_host_key_event(key_down("meta + tab"), "Visual Studio Code", "Untitled");
+    engine.get_synthetic_events();
+    engine.handle_host_key_event(key_down("meta + shift"), "Visual Studio Code", "Untitled");
+    engine.handle_host_key_event(key_down("meta + shift + rightshift"), "Visual Studio Code", "Untitled");
+    assert!(engine.is_enabled());
+    assert!(!engine.handle_host_key_event(key_down("meta + shift + escape"), "Visual Studio Code", "Untitled"));
+    assert!(!engine.is_enabled());
+    assert!(engine.pressed_keys.is_empty());
+    let released = engine.get_synthetic_events();
+    assert!(released.iter().any(|event| event.key == "meta" && event.state == KeyState::Up));
+
+    // The kill chord can be changed or turned off.
+    engine.set_enabled(true);
+    engine.set_kill_chord(&[]);
+    engine.handle_key_event(key_down("shift"), "Untitled", "Untitled");
+    engine.handle_key_event(key_down("shift + rightshift"), "Untitled", "Untitled");
+    engine.handle_key_event(key_down("shift + escape"), "Untitled", "Untitled");
+    assert!(engine.is_enabled());
+}
+
+#[test]
+fn test_engine_commands() {
+    assert_eq!(pinkytwirl::EngineCommand::parse("Engine.SwitchProfile(Work)"), Some(pinkytwirl::EngineCommand::SwitchProfile("Work".to_string())));
+    assert_eq!(pinkytwirl::EngineCommand::parse("engine.reload"), Some(pinkytwirl::EngineCommand::Reload));
+    assert_eq!(pinkytwirl::EngineCommand::parse("Engine.Explode"), None);
+
+    let mut engine = get_fixture_engine("commands");
+    let query = engine.query_chord("Untitled", "Untitled", "Meta + W").unwrap();
+    assert_eq!(query.mapping.unwrap().action, "Engine.SwitchProfile(Work)");
+
+    // Escape alone no longer reloads, it just passes through.
+    let (suppress, _) = engine.handle_key_event(key_down("escape"), "Untitled", "Untitled");
+    assert!(!suppress);
+    engine.handle_key_event(key_up("escape"), "Untitled", "Untitled");
+
+    // Help is left for the host to pick up.
+    engine.handle_key_event(key_down("meta"), "Untitled", "Untitled");
+    let (suppress, output) = engine.handle_key_event(key_down("meta + h"), "Untitled", "Untitled");
+    assert!(suppress && output.is_empty());
+    let help = engine.take_help().unwrap();
+    assert!(help.contains("Meta + J (Default): NavLeft\n"));
+    assert!(help.contains("Meta + P (Default): Pause\n"));
+    assert!(engine.take_help().is_none());
+    engine.handle_key_event(key_up("meta + h"), "Untitled", "Untitled");
+
+    // Toggling through a named action disables the engine, and the same chord enables it again.
+    engine.handle_key_event(key_down("meta + p"), "Untitled", "Untitled");
+    assert!(!engine.is_enabled());
+    engine.handle_key_event(key_up("meta + p"), "Untitled", "Untitled");
+    let (suppress, _) = engine.handle_key_event(key_down("meta + j"), "Untitled", "Untitled");
+    assert!(!suppress);
+    engine.handle_key_event(key_up("meta + j"), "Untitled", "Untitled");
+    let (suppress, _) = engine.handle_key_event(key_down("meta + p"), "Untitled", "Untitled");
+    assert!(suppress);
+    assert!(engine.is_enabled());
+    // The host saw no down for `P`, so it sees no up either.
+    let (suppress, _) = engine.handle_key_event(key_up("meta + p"), "Untitled", "Untitled");
+    assert!(suppress);
+    engine.handle_key_event(key_up("meta"), "Untitled", "Untitled");
+
+    // Reset ends the chord, even with meta still held.
+    engine.handle_key_event(key_down("meta"), "Untitled", "Untitled");
+    engine.handle_key_event(key_down("meta + x"), "Untitled", "Untitled");
+    assert!(engine.pressed_keys.is_empty());
+    assert_eq!(engine.state(), pinkytwirl::ChordState::Idle);
+}
+
+#[test]
+fn test_toggling_the_engine_suppresses_the_ups_of_its_chord() {
+    let mut engine = get_fixture_engine("commands");
+    let releases = |events: &[KeyEvent]| {
+        events.iter().filter(|event| event.key == "meta" && event.state == KeyState::Up).count()
+    };
+
+    // Disabling: the host saw neither down, so both ups are suppressed and meta is released once.
+    assert!(engine.handle_host_key_event(key_down("meta"), "Untitled", "Untitled"));
+    assert!(engine.handle_host_key_event(key_down("meta + p"), "Untitled", "Untitled"));
+    assert!(!engine.is_enabled());
+    let mut events = engine.get_synthetic_events();
+    assert!(engine.handle_host_key_event(key_up("meta + p"), "Untitled", "Untitled"));
+    events.extend(engine.get_synthetic_events());
+    assert!(engine.handle_host_key_event(key_up("meta"), "Untitled", "Untitled"));
+    events.extend(engine.get_synthetic_events());
+    assert_eq!(releases(&events), 1);
+
+    // Enabling: the host saw the down of meta, but not the one of `P`.
+    assert!(!engine.handle_host_key_event(key_down("meta"), "Untitled", "Untitled"));
+    assert!(engine.handle_host_key_event(key_down("meta + p"), "Untitled", "Untitled"));
+    assert!(engine.is_enabled());
+    let mut events = engine.get_synthetic_events();
+    assert!(engine.handle_host_key_event(key_up("meta + p"), "Untitled", "Untitled"));
+    events.extend(engine.get_synthetic_events());
+    assert!(!engine.handle_host_key_event(key_up("meta"), "Untitled", "Untitled"));
+    events.extend(engine.get_synthetic_events());
+    assert_eq!(releases(&events), 1);
+    assert!(engine.pressed_keys.is_empty());
+}
+
+#[test]
+fn test_profiles() {
+    let config_dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/profiles");
+    let profile_file = std::env::temp_dir().join(format!("pinkytwirl-profile-{}.txt", std::process::id()));
+    let _ = std::fs::remove_file(&profile_file);
+    let build = || {
+        PinkyTwirlEngine::builder()
+            .platform(Platform::MacOS)
+            .config_dir(&config_dir)
+            .profile_file(&profile_file)
+            .build()
+    };
+
+    let mut engine = build();
+    assert!(engine.startup.is_ok());
+    assert_eq!(engine.profiles(), ["Focus", "Work"]);
+    assert_eq!(engine.active_profile(), None);
+    assert_eq!(engine.status_description(), "Enabled");
+    let query = engine.query_chord("Untitled", "Untitled", "Meta + J").unwrap();
+    assert_eq!(query.mapping.unwrap().action, "NavLeft");
+
+    // The work profile adds a layer, and is switched to through a chord. The running watcher
+    // follows the switch without holding up the key event.
+    engine.watch_config_with(std::time::Duration::from_secs(60), std::time::Duration::from_millis(30));
+    engine.handle_key_event(key_down("meta"), "Untitled", "Untitled");
+    let start = std::time::Instant::now();
+    let (suppress, _) = engine.handle_key_event(key_down("meta + w"), "Untitled", "Untitled");
+    assert!(suppress);
+    assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    assert!(engine.is_watching_config());
+    engine.handle_key_event(key_up("meta + w"), "Untitled", "Untitled");
+    engine.handle_key_event(key_up("meta"), "Untitled", "Untitled");
+    assert_eq!(engine.active_profile(), Some("Work"));
+    assert_eq!(engine.status_description(), "Enabled, profile Work");
+    let query = engine.query_chord("Untitled", "Untitled", "Meta + J").unwrap();
+    assert_eq!(query.mapping.unwrap().action, "NavRight");
+
+    // The profile is remembered for the next run.
+    assert_eq!(std::fs::read_to_string(&profile_file).unwrap().trim(), "Work");
+    assert_eq!(build().active_profile(), Some("Work"));
+
+    // Unknown profiles leave the active one in place.
+    assert!(engine.switch_profile("Nope").is_err());
+    assert_eq!(engine.active_profile(), Some("Work"));
+
+    // The focus profile only keeps the mappings of the terminal.
+    engine.switch_profile("Focus").unwrap();
+    assert!(engine.query_chord("Untitled", "Untitled", "Meta + J").unwrap().mapping.is_none());
+    let query = engine.query_chord("Terminal", "Untitled", "Meta + K").unwrap();
+    assert_eq!(query.mapping.unwrap().action, "NavRight");
+
+    // An empty name switches back to the base config, and forgets the profile.
+    engine.switch_profile("").unwrap();
+    assert_eq!(engine.active_profile(), None);
+    assert!(!profile_file.exists());
+
+    // A remembered profile that no longer exists falls back to the base config.
+    std::fs::write(&profile_file, "Removed\n").unwrap();
+    let engine = build();
+    assert!(engine.startup.is_ok());
+    assert_eq!(engine.active_profile(), None);
+    std::fs::remove_file(&profile_file).unwrap();
+
+    let mut simulator = Simulator::new(engine);
+    simulator.run_command("focus Finder Finder").unwrap();
+    let output = simulator.run_command("profile Work").unwrap();
+    assert!(output.contains("context: Default (profile Work)"));
+    assert!(simulator.run_command("profile Nope").is_err());
+    std::fs::remove_file(&profile_file).unwrap();
+}
+
+#[test]
+fn test_locked_and_one_shot_layers() {
+    let mut engine = get_fixture_engine("commands");
+    assert!(engine.toggle_layer("Nope").is_err());
+
+    // Meta + N locks the D4 layer, which then maps keys without D4 held.
+    engine.handle_key_event(key_down("meta"), "Untitled", "Untitled");
+    engine.handle_key_event(key_down("meta + n"), "Untitled", "Untitled");
+    engine.handle_key_event(key_up("meta + n"), "Untitled", "Untitled");
+    engine.handle_key_event(key_up("meta"), "Untitled", "Untitled");
+    assert_eq!(engine.active_layer().unwrap().to_string(), "d4 (locked)");
+    assert_eq!(engine.status_description(), "Enabled, layer d4 (locked)");
+    for _ in 0..2 {
+        let (suppress, output) = engine.handle_key_event(key_down("j"), "Untitled", "Untitled");
+        assert!(suppress);
+        assert!(output[0].key == "left" && output[0].shift);
+        engine.handle_key_event(key_up("j"), "Untitled", "Untitled");
+    }
+    // Keys the layer doesn't map pass through.
+    let (suppress, _) = engine.handle_key_event(key_down("k"), "Untitled", "Untitled");
+    assert!(!suppress);
+    engine.handle_key_event(key_up("k"), "Untitled", "Untitled");
+
+    // The layer's own toggle turns it off, and Escape does too.
+    engine.handle_key_event(key_down("n"), "Untitled", "Untitled");
+    engine.handle_key_event(key_up("n"), "Untitled", "Untitled");
+    assert!(engine.active_layer().is_none());
+    engine.toggle_layer("D4").unwrap();
+    let (suppress, output) = engine.handle_key_event(key_down("escape"), "Untitled", "Untitled");
+    assert!(suppress && output.is_empty());
+    engine.handle_key_event(key_up("escape"), "Untitled", "Untitled");
+    assert!(engine.active_layer().is_none());
+    assert_eq!(engine.state(), pinkytwirl::ChordState::Idle);
+
+    // A one-shot layer only maps the next chord.
+    engine.handle_key_event(key_down("meta"), "Untitled", "Untitled");
+    engine.handle_key_event(key_down("meta + o"), "Untitled", "Untitled");
+    engine.handle_key_event(key_up("meta + o"), "Untitled", "Untitled");
+    engine.handle_key_event(key_up("meta"), "Untitled", "Untitled");
+    assert_eq!(engine.active_layer().unwrap().mode, pinkytwirl::LayerMode::OneShot);
+    let (suppress, _) = engine.handle_key_event(key_down("j"), "Untitled", "Untitled");
+    assert!(suppress);
+    engine.handle_key_event(key_up("j"), "Untitled", "Untitled");
+    assert!(engine.active_layer().is_none());
+    let (suppress, _) = engine.handle_key_event(key_down("j"), "Untitled", "Untitled");
+    assert!(!suppress);
+    engine.handle_key_event(key_up("j"), "Untitled", "Untitled");
+
+    // Resetting clears the layer too.
+    engine.toggle_layer("D4").unwrap();
+    engine.reset_all();
+    assert!(engine.active_layer().is_none());
+}
+
+#[test]
+fn test_modal_input() {
+    use pinkytwirl::{ModalInput, ModalStep};
+    let mut input = ModalInput::default();
+    let mut type_keys = |keys: &[(&str, bool)]| {
+        let mut steps: Vec<ModalStep> = keys.iter().map(|(key, shift)| input.key(key, *shift)).collect();
+        steps.pop().unwrap()
+    };
+
+    assert_eq!(type_keys(&[("d5", false), ("j", false)]), ModalStep::Run("NavDown | NavDown | NavDown | NavDown | NavDown".to_string()));
+    assert_eq!(type_keys(&[("d0", false)]), ModalStep::Run("NavHome".to_string()));
+    assert_eq!(type_keys(&[("d", false), ("d4", true)]), ModalStep::Run("SelectEnd | Delete".to_string()));
+    assert_eq!(type_keys(&[("y", false), ("d2", false), ("w", false)]), ModalStep::Run("SelectNextWord | SelectNextWord | Copy".to_string()));
+    assert_eq!(type_keys(&[("d2", false), ("d", false), ("d", false)]), ModalStep::Run("NavHome | SelectDown | SelectDown | Delete".to_string()));
+    assert_eq!(type_keys(&[("c", false), ("rightbracket", true)]), ModalStep::Leave(Some("SelectBigDown | Cut".to_string())));
+    assert_eq!(type_keys(&[("g", false), ("g", false)]), ModalStep::Run("NavTop".to_string()));
+    assert_eq!(type_keys(&[("d", false), ("q", false)]), ModalStep::Cancelled);
+    assert_eq!(type_keys(&[("d", false), ("escape", false)]), ModalStep::Cancelled);
+    assert_eq!(type_keys(&[("escape", false)]), ModalStep::Leave(None));
+
+    let mut input = ModalInput::default();
+    input.key("d2", false);
+    input.key("d", false);
+    input.key("d3", false);
+    assert_eq!(input.to_string(), "2d3");
+    assert_eq!(input.key("d9", false), ModalStep::Pending);
+    assert_eq!(input.key("d9", false), ModalStep::Pending);
+    // Counts are clamped.
+    match input.key("j", false) {
+        ModalStep::Run(actions) => assert_eq!(actions.matches("SelectDown").count(), pinkytwirl::MAX_COUNT),
+        step => panic!("Unexpected step: {:?}", step),
+    }
+}
+
+#[test]
+fn test_modal_mode_and_chord_counts() {
+    let mut engine = get_engine();
+
+    // Meta + D5, K moves down five lines in an editor.
+    engine.handle_key_event(key_down("meta"), "Visual Studio Code", "Untitled");
+    let (suppress, output) = engine.handle_key_event(key_down("meta + d5"), "Visual Studio Code", "Untitled");
+    assert!(suppress && output.is_empty());
+    engine.handle_key_event(key_up("meta + d5"), "Visual Studio Code", "Untitled");
+    let (_, output) = engine.handle_key_event(key_down("meta + k"), "Visual Studio Code", "Untitled");
+    assert_eq!(output.len(), 5);
+    assert!(output.iter().all(|event| event.key == "down" && !event.meta));
+    engine.handle_key_event(key_up("meta + k"), "Visual Studio Code", "Untitled");
+    // The count is used up.
+    let (_, output) = engine.handle_key_event(key_down("meta + k"), "Visual Studio Code", "Untitled");
+    assert_eq!(output.len(), 1);
+    engine.handle_key_event(key_up("meta + k"), "Visual Studio Code", "Untitled");
+
+    // Meta + D0 pages down, but after a digit it continues the count.
+    for digit in ["meta + d6", "meta + d0"] {
+        let (suppress, output) = engine.handle_key_event(key_down(digit), "Visual Studio Code", "Untitled");
+        assert!(suppress && output.is_empty());
+        engine.handle_key_event(key_up(digit), "Visual Studio Code", "Untitled");
+    }
+    let (_, output) = engine.handle_key_event(key_down("meta + k"), "Visual Studio Code", "Untitled");
+    assert_eq!(output.len(), 60);
+    assert!(output.iter().all(|event| event.key == "down"));
+    engine.handle_key_event(key_up("meta + k"), "Visual Studio Code", "Untitled");
+    engine.handle_key_event(key_up("meta"), "Visual Studio Code", "Untitled");
+    assert_eq!(engine.state(), pinkytwirl::ChordState::Idle);
+
+    // Counts are off in a browser, which switches tabs with Meta + D1 itself.
+    let mut output = Vec::new();
+    for event in [key_down("meta"), key_down("meta + d1"), key_up("meta + d1"), key_up("meta")] {
+        output.extend(engine.handle_key_event(event, "Google Chrome", "Untitled").1);
+    }
+    assert!(output.iter().any(|event| event.key == "d1" && event.state == KeyState::Down && event.meta));
+    assert_eq!(engine.state(), pinkytwirl::ChordState::Idle);
+
+    engine.set_modal(true);
+    assert_eq!(engine.status_description(), "Enabled, modal");
+    let (suppress, output) = engine.handle_key_event(key_down("y"), "Untitled", "Untitled");
+    assert!(suppress && output.is_empty());
+    engine.handle_key_event(key_up("y"), "Untitled", "Untitled");
+    assert_eq!(engine.status_description(), "Enabled, modal y");
+    engine.handle_key_event(key_down("d2"), "Untitled", "Untitled");
+    engine.handle_key_event(key_up("d2"), "Untitled", "Untitled");
+    let (_, output) = engine.handle_key_event(key_down("w"), "Untitled", "Untitled");
+    let output: Vec<String> = output.iter().map(|event| event.to_string()).collect();
+    assert_eq!(output, [
+        "KeyEvent(Shift + Alt + right, DownUp)",
+        "KeyEvent(Shift + Alt + right, DownUp)",
+        "KeyEvent(Meta + c, DownUp)",
+    ]);
+    engine.handle_key_event(key_up("w"), "Untitled", "Untitled");
+
+    // Shift is part of keys such as `$`.
+    engine.handle_key_event(key_down("shift"), "Untitled", "Untitled");
+    let (suppress, output) = engine.handle_key_event(key_down("shift + d4"), "Untitled", "Untitled");
+    assert!(suppress);
+    assert_eq!(output[0].to_string(), "KeyEvent(Meta + right, DownUp)");
+    engine.handle_key_event(key_up("shift + d4"), "Untitled", "Untitled");
+    engine.handle_key_event(key_up("shift"), "Untitled", "Untitled");
+
+    // Chords with other modifiers still work.
+    engine.handle_key_event(key_down("meta"), "Untitled", "Untitled");
+    let (_, output) = engine.handle_key_event(key_down("meta + k"), "Untitled", "Untitled");
+    assert_eq!(output[0].key, "down");
+    engine.handle_key_event(key_up("meta + k"), "Untitled", "Untitled");
+    engine.handle_key_event(key_up("meta"), "Untitled", "Untitled");
+
+    // `i` leaves modal mode, and keys are typed again.
+    engine.handle_key_event(key_down("i"), "Untitled", "Untitled");
+    engine.handle_key_event(key_up("i"), "Untitled", "Untitled");
+    assert!(!engine.is_modal());
+    let (suppress, _) = engine.handle_key_event(key_down("j"), "Untitled", "Untitled");
+    assert!(!suppress);
+    engine.handle_key_event(key_up("j"), "Untitled", "Untitled");
+    assert_eq!(engine.state(), pinkytwirl::ChordState::Idle);
+}
+
+#[test]
+fn test_caps_lock_stem_and_lock_state() {
+    let mut engine = get_fixture_engine("capslock");
+    assert!(!engine.is_caps_lock_on());
+
+    // Used as a stem, Caps Lock maps chords and its toggle is undone.
+    let (suppress, _) = engine.handle_key_event(key_down("capslock"), "Untitled", "Untitled");
+    assert!(suppress);
+    assert!(engine.is_caps_lock_on());
+    let (_, output) = engine.handle_key_event(key_down("capslock + j"), "Untitled", "Untitled");
+    assert_eq!(output[0].key, "left");
+    engine.handle_key_event(key_up("capslock + j"), "Untitled", "Untitled");
+    engine.handle_key_event(key_up("capslock"), "Untitled", "Untitled");
+    assert_eq!(engine.take_caps_lock_restore(), Some(false));
+    assert_eq!(engine.take_caps_lock_restore(), None);
+    assert!(!engine.is_caps_lock_on());
+
+    // Tapped alone it keeps its toggle, and isn't pressed again.
+    engine.handle_key_event(key_down("capslock"), "Untitled", "Untitled");
+    let (_, output) = engine.handle_key_event(key_up("capslock"), "Untitled", "Untitled");
+    assert!(output.is_empty());
+    assert_eq!(engine.take_caps_lock_restore(), None);
+    assert!(engine.is_caps_lock_on());
+
+    // `CapsLocked` mappings only apply while the lock is on.
+    engine.handle_key_event(key_down("meta"), "Untitled", "Untitled");
+    let (_, output) = engine.handle_key_event(key_down("meta + j"), "Untitled", "Untitled");
+    assert_eq!(output[0].key, "pageup");
+    engine.handle_key_event(key_up("meta + j"), "Untitled", "Untitled");
+    engine.handle_key_event(key_up("meta"), "Untitled", "Untitled");
+    engine.set_caps_lock(false);
+    engine.handle_key_event(key_down("meta"), "Untitled", "Untitled");
+    let (_, output) = engine.handle_key_event(key_down("meta + j"), "Untitled", "Untitled");
+    assert_eq!(output[0].key, "right");
+    engine.handle_key_event(key_up("meta + j"), "Untitled", "Untitled");
+    engine.handle_key_event(key_up("meta"), "Untitled", "Untitled");
+    assert_eq!(engine.state(), pinkytwirl::ChordState::Idle);
+}
@@ -1,4 +1,6 @@
+import Carbon
 import Cocoa
+import IOKit.hidsystem
 
 class AppDelegate: NSObject, NSApplicationDelegate {
     private var statusItem: NSStatusItem?
@@ -10,7 +12,99 @@ class AppDelegate: NSObject, NSApplicationDelegate {
     func applicationDidFinishLaunching(_ notification: Notification) {
         setupStatusBarItem()
         checkAndRequestAccessibilityPermissions()
-        engine = PinkyTwirlEngine.new("../../../pinkytwirl-rs/src/user_config")
+        // The engine looks for PINKYTWIRL_CONFIG_DIR, then ~/Library/Application Support/PinkyTwirl,
+        // and otherwise runs on the defaults compiled into the library.
+        engine = PinkyTwirlEngine.discover()
+        // Config edits are picked up in the background, Engine.Reload reloads on demand.
+        engine!.watch_config()
+        print("PinkyTwirl config: \(engine!.config_location_description().toString())")
+        updateStatusItem()
+        if engine!.offers_default_config_install() && !UserDefaults.standard.bool(forKey: AppDelegate.declinedDefaultConfigKey) {
+            offerDefaultConfigInstall()
+        }
+        // Up events can go missing while the Mac sleeps or another app has focus.
+        let notifications = NSWorkspace.shared.notificationCenter
+        notifications.addObserver(self, selector: #selector(syncPhysicalState), name: NSWorkspace.didWakeNotification, object: nil)
+        notifications.addObserver(self, selector: #selector(syncPhysicalState), name: NSWorkspace.didActivateApplicationNotification, object: nil)
+    }
+
+    @objc private func syncPhysicalState() {
+        let heldKeyCodes = RustVec<Int64>()
+        for keyCode in 0..<128 {
+            if CGEventSource.keyState(.combinedSessionState, key: CGKeyCode(keyCode)) {
+                heldKeyCodes.push(value: Int64(keyCode))
+            }
+        }
+        engine?.macos_sync_physical_state(heldKeyCodes)
+        postSyntheticEvents()
+    }
+
+    // Shows whether the engine is enabled, or why it failed. A failed engine passes every event
+    // through instead of crashing.
+    private func updateStatusItem() {
+        if let failure = engine?.failure_description().toString(), !failure.isEmpty {
+            if statusItem?.button?.toolTip != failure {
+                print("PinkyTwirl stopped: \(failure)")
+            }
+            statusItem?.button?.title = "⚠️"
+            statusItem?.button?.toolTip = failure
+            return
+        }
+        let isEnabled = engine?.is_enabled() ?? false
+        statusItem?.button?.title = isEnabled ? "⌨️" : "🚫"
+        statusItem?.menu?.items.first?.title = isEnabled ? "Disable" : "Enable"
+        // Also reports the active profile, which Engine.SwitchProfile can change at any time.
+        if let status = engine?.status_description().toString(), statusItem?.button?.toolTip != status {
+            print("PinkyTwirl status: \(status)")
+            statusItem?.button?.toolTip = status
+        }
+    }
+
+    // Switches to a profile from profiles.txt, or back to the base config for an empty name.
+    // The engine remembers it for the next launch.
+    private func switchProfile(_ profile: String) {
+        if engine?.switch_profile(profile) == true {
+            updateStatusItem()
+        }
+    }
+
+    // Undoes the toggle of a Caps Lock press that was used as a stem.
+    private func setCapsLock(_ on: Bool) {
+        let service = IOServiceGetMatchingService(kIOMainPortDefault, IOServiceMatching(kIOHIDSystemClass))
+        defer { IOObjectRelease(service) }
+        var connect: io_connect_t = 0
+        guard IOServiceOpen(service, mach_task_self_, UInt32(kIOHIDParamConnectType), &connect) == KERN_SUCCESS else {
+            print("Failed to set Caps Lock")
+            return
+        }
+        IOHIDSetModifierLockState(connect, Int32(kIOHIDCapsLockState), on)
+        IOServiceClose(connect)
+    }
+
+    // Shown for Engine.ShowHelp, with the chords of the focused application.
+    private func showHelp(_ help: String) {
+        let alert = NSAlert()
+        alert.messageText = "PinkyTwirl chords"
+        alert.informativeText = help
+        alert.runModal()
+    }
+
+    // Remembers that the user turned down the default config, so they aren't asked on every launch.
+    private static let declinedDefaultConfigKey = "DeclinedDefaultConfigInstall"
+
+    private func offerDefaultConfigInstall() {
+        let alert = NSAlert()
+        alert.messageText = "Create an editable PinkyTwirl config?"
+        alert.informativeText = "PinkyTwirl is running on its built-in defaults. A copy can be written to ~/Library/Application Support/PinkyTwirl for you to edit."
+        alert.addButton(withTitle: "Create Config")
+        alert.addButton(withTitle: "Don't Ask Again")
+        if alert.runModal() == .alertFirstButtonReturn {
+            if engine?.macos_install_default_config() == true {
+                print("PinkyTwirl config: \(engine!.config_location_description().toString())")
+            }
+        } else {
+            UserDefaults.standard.set(true, forKey: AppDelegate.declinedDefaultConfigKey)
+        }
     }
 
     private func setupStatusBarItem() {
@@ -22,14 +116,15 @@ class AppDelegate: NSObject, NSApplicationDelegate {
         menu.addItem(NSMenuItem(title: "Quit", action: #selector(NSApplication.terminate(_:)), keyEquivalent: "q"))
     }
 
+    // The event tap stays enabled, the engine passes events through while it's disabled.
     @objc private func toggleEnabled() {
-        if let eventTap = eventTap {
-            let isEnabled = CGEvent.tapIsEnabled(tap: eventTap)
-            CGEvent.tapEnable(tap: eventTap, enable: !isEnabled)
-            print("PinkyTwirl is \(isEnabled ? "disabled" : "enabled")")
-            statusItem?.button?.title = isEnabled ? "⌨️" : "🚫"
-            statusItem?.menu?.items.first?.title = isEnabled ? "Disable" : "Enable"
+        guard let engine = engine else {
+            return
         }
+        engine.set_enabled(!engine.is_enabled())
+        print("PinkyTwirl is \(engine.is_enabled() ? "enabled" : "disabled")")
+        postSyntheticEvents()
+        updateStatusItem()
     }
 
     @objc func checkAndRequestAccessibilityPermissions() {
@@ -85,6 +180,34 @@ class AppDelegate: NSObject, NSApplicationDelegate {
         print("Event tap enabled")
     }
 
+    private func postSyntheticEvents() {
+        let synthetic_keys = engine?.get_synthetic_events()
+        for i in 0..<synthetic_keys!.len() {
+            let key = synthetic_keys![i]
+            if let synth = CGEvent(keyboardEventSource: nil, virtualKey: UInt16(key.get_code()), keyDown: key.is_down()) {
+                if key.get_shift() {
+                    synth.flags.insert([.maskShift])
+                }
+                if key.get_ctrl() {
+                    synth.flags.insert([.maskControl])
+                }
+                if key.get_alt() {
+                    synth.flags.insert([.maskAlternate])
+                }
+                if key.get_meta() {
+                    synth.flags.insert([.maskCommand])
+                }
+                if key.get_fn() {
+                    synth.flags.insert([.maskSecondaryFn])
+                }
+                // Mark this event as synthetic so we can easily filter it out elsewhere.
+                synth.setIntegerValueField(.eventSourceUserData, value: 0x1234)
+                // synth.post(tap: .cgSessionEventTap)
+                synth.post(tap: .cghidEventTap)
+            }
+        }
+    }
+
     private func getActiveWindowInfo() -> (appName: String, windowTitle: String, bundleId: String) {
         guard let app = NSWorkspace.shared.frontmostApplication else {
             return ("unknown", "unknown", "unknown")
@@ -110,6 +233,16 @@ class AppDelegate: NSObject, NSApplicationDelegate {
     }
 
     private func handleEvent(proxy: CGEventTapProxy, type: CGEventType, event: CGEvent) -> Unmanaged<CGEvent>? {
+        // The tap misses every event while it's disabled, so the engine lets go of all keys.
+        if type == .tapDisabledByTimeout || type == .tapDisabledByUserInput {
+            if type == .tapDisabledByTimeout, let eventTap = eventTap {
+                CGEvent.tapEnable(tap: eventTap, enable: true)
+            }
+            engine?.reset_all()
+            postSyntheticEvents()
+            return Unmanaged.passUnretained(event)
+        }
+
         // Ignore our own synthetic events.
         if event.getIntegerValueField(.eventSourceUserData) == 0x1234 {
             return Unmanaged.passRetained(event)
@@ -119,10 +252,21 @@ class AppDelegate: NSObject, NSApplicationDelegate {
         let flags = event.flags
 
         // Get active window info.
-        let (appName, windowTitle, bundleId) = getActiveWindowInfo()
+        let (appName, windowTitle, _) = getActiveWindowInfo()
+
+        // Password fields turn on secure input, the engine then passes everything through.
+        let secureInput = IsSecureEventInputEnabled()
+        engine?.set_secure_input(secureInput)
+
+        // Caps Lock toggles its lock as it goes down, which the engine tracks itself. Other
+        // events carry the lock state in their flags.
+        let isCapsLock = keyCode == 57
+        if !isCapsLock {
+            engine?.set_caps_lock(flags.contains(.maskAlphaShift))
+        }
 
-        // Debug print the event.
-        print("Event: \(type) \(keyCode) \(flags) \(appName) \(windowTitle) \(bundleId)")
+        // Key events aren't printed here: the engine logs them itself, and only names typed keys
+        // with the `typed-keys` opt-in of PINKYTWIRL_LOG.
 
         var isKeyDown = false
         if type == .flagsChanged {
@@ -139,7 +283,9 @@ class AppDelegate: NSObject, NSApplicationDelegate {
                 flags.contains(.maskCommand) && keyCode == 54 ||
                 flags.contains(.maskControl) && keyCode == 62 ||
                 flags.contains(.maskShift) && keyCode == 60 ||
-                flags.contains(.maskAlternate) && keyCode == 61)
+                flags.contains(.maskAlternate) && keyCode == 61 ||
+                // Caps Lock reports its lock in the flags, so ask whether the key is held.
+                isCapsLock && CGEventSource.keyState(.hidSystemState, key: CGKeyCode(keyCode)))
         } else {
             isKeyDown = type == .keyDown
         }
@@ -155,33 +301,14 @@ class AppDelegate: NSObject, NSApplicationDelegate {
             appName,
             windowTitle)
         
-        print("shouldSuppress: \(shouldSuppress!)")        
-        let synthetic_keys = engine?.get_synthetic_events()
-        for i in 0..<synthetic_keys!.len() {
-            let key = synthetic_keys![i]
-            print("Synthetic key to generate \(i): \(key.is_down()) \(key.get_code())")
-
-            if let synth = CGEvent(keyboardEventSource: nil, virtualKey: UInt16(key.get_code()), keyDown: key.is_down()) {
-                if key.get_shift() {
-                    synth.flags.insert([.maskShift])
-                }
-                if key.get_ctrl() {
-                    synth.flags.insert([.maskControl])
-                }
-                if key.get_alt() {
-                    synth.flags.insert([.maskAlternate])
-                }
-                if key.get_meta() {
-                    synth.flags.insert([.maskCommand])
-                }
-                if key.get_fn() {
-                    synth.flags.insert([.maskSecondaryFn])
-                }
-                // Mark this event as synthetic so we can easily filter it out elsewhere.
-                synth.setIntegerValueField(.eventSourceUserData, value: 0x1234)
-                // synth.post(tap: .cgSessionEventTap)
-                synth.post(tap: .cghidEventTap)
-            }
+        postSyntheticEvents()
+        if let capsLock = engine?.take_caps_lock_restore() {
+            setCapsLock(capsLock)
+        }
+        updateStatusItem()
+        if let help = engine?.take_help_text().toString(), !help.isEmpty {
+            // The alert waits for the event tap to return first.
+            DispatchQueue.main.async { self.showHelp(help) }
         }
 
         if shouldSuppress! {